@@ -0,0 +1,123 @@
+use anyhow::Result;
+use rdev::Key;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::audio::output::{CpalBackend, OutputBackend};
+use crate::audio::SoundEngine;
+use crate::config::Config;
+use crate::events::Subscription;
+use crate::input::KeyboardHandler;
+use crate::settings::Settings;
+
+/// Keyboard sounds for one app: the settings and the engine playing them. Changes to
+/// the settings apply to the next key.
+pub struct ClickClack {
+    settings: Settings,
+    keyboard: KeyboardHandler,
+}
+
+impl ClickClack {
+    /// Plays on the sound card with `config`. Changes are kept in memory; save them with
+    /// `Config::save` to share them with the ClickClack app.
+    pub fn new(config: Config) -> Result<Self> {
+        Self::with_settings(Settings::new(config))
+    }
+
+    /// Plays on the sound card, sharing `settings` with whatever else holds them
+    pub fn with_settings(settings: Settings) -> Result<Self> {
+        let buffer_frames = settings.current().buffer_frames;
+        Self::with_backend(settings, Box::new(CpalBackend { buffer_frames }), crate::packs::switchtypes_dir())
+    }
+
+    /// Plays through `backend`, loading packs from `switchtypes_dir`
+    pub fn with_backend(settings: Settings, backend: Box<dyn OutputBackend>, switchtypes_dir: PathBuf) -> Result<Self> {
+        let sound_engine = Arc::new(SoundEngine::with_backend(settings.clone(), backend, switchtypes_dir)?);
+        Ok(Self { settings, keyboard: KeyboardHandler::new(sound_engine)? })
+    }
+
+    /// Plays the sound for a key event the app got itself. Auto-repeated presses of a
+    /// held key play nothing, and neither does anything while sound is off.
+    pub fn key(&self, key: Key, is_press: bool) {
+        self.keyboard.handle(key, is_press);
+    }
+
+    /// Listens for keys system wide instead, like the ClickClack app does. Needs the
+    /// accessibility permission on macOS.
+    pub fn listen(&self) -> Result<()> {
+        self.keyboard.start()
+    }
+
+    pub fn config(&self) -> Arc<Config> {
+        self.settings.current()
+    }
+
+    /// Changes the settings, e.g. `clickclack.update(|config| config.volume = 0.5)`
+    pub fn update(&self, apply: impl FnOnce(&mut Config)) -> Arc<Config> {
+        self.settings.update(apply)
+    }
+
+    /// Key presses and settings changes from now on. A subscriber that doesn't keep up
+    /// misses events rather than slowing down the keys.
+    pub fn subscribe(&self) -> Subscription {
+        self.settings.events().subscribe()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn sound_engine(&self) -> &Arc<SoundEngine> {
+        self.keyboard.sound_engine()
+    }
+
+    pub fn keyboard(&self) -> &KeyboardHandler {
+        &self.keyboard
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::buffer::BufferBackend;
+    use crate::audio::fixtures::test_pack;
+    use crate::events::Event;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_plays_keys_from_the_app() {
+        let backend = BufferBackend::new();
+        let config = Config { switch_type: "test".into(), ..Default::default() };
+        let clickclack = ClickClack::with_backend(Settings::new(config), Box::new(backend.clone()), test_pack("app")).unwrap();
+        let events = clickclack.subscribe();
+
+        clickclack.key(Key::KeyQ, true);
+        // Auto-repeat while held
+        clickclack.key(Key::KeyQ, true);
+        clickclack.key(Key::KeyQ, false);
+        clickclack.update(|config| config.enabled = false);
+        clickclack.key(Key::KeyA, true);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while backend.triggers().len() < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        let samples: Vec<String> = backend.triggers().into_iter().map(|trigger| trigger.sample).collect();
+        assert_eq!(samples, vec!["test/press/GENERIC_R1.wav", "test/release/GENERIC.wav"]);
+        assert!(!clickclack.config().enabled);
+
+        let events: Vec<Event> = std::iter::from_fn(|| events.try_recv()).collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Key { key: Key::KeyQ, is_press: true },
+                Event::Key { key: Key::KeyQ, is_press: false },
+                Event::EnabledChanged(false),
+                Event::ConfigChanged(clickclack.config()),
+                // Keys still go out while sound is off
+                Event::Key { key: Key::KeyA, is_press: true },
+            ]
+        );
+    }
+}
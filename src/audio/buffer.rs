@@ -0,0 +1,363 @@
+use anyhow::Result;
+use parking_lot::Mutex;
+use rodio::source::UniformSourceIterator;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::flac;
+use super::latency::Counters;
+use super::output::{AudioOutput, OutputBackend, Sound};
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const CHANNELS: u16 = 2;
+
+/// A sample that was played, relative to the start of the recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trigger {
+    pub at: Duration,
+    /// Path of the sample below the switchtypes directory, e.g. `mxblue/press/GENERIC_R2.mp3`
+    pub sample: String,
+}
+
+/// Every sound mixed into one interleaved stereo buffer, plus a log of what was
+/// triggered when
+#[derive(Debug)]
+pub struct Recording {
+    pub triggers: Vec<Trigger>,
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+impl Default for Recording {
+    fn default() -> Self {
+        Self::new(SAMPLE_RATE)
+    }
+}
+
+impl Recording {
+    pub fn new(sample_rate: u32) -> Self {
+        Self { triggers: Vec::new(), samples: Vec::new(), sample_rate }
+    }
+
+    /// Mixes `sound` in starting at `at`
+    pub fn add(&mut self, at: Duration, sample: &str, sound: Sound) {
+        let start = (at.as_secs_f64() * self.sample_rate as f64).round() as usize * CHANNELS as usize;
+        let sound = UniformSourceIterator::<_, f32>::new(sound, CHANNELS, self.sample_rate);
+        for (i, value) in sound.enumerate() {
+            let index = start + i;
+            if index >= self.samples.len() {
+                self.samples.resize(index + 1, 0.0);
+            }
+            self.samples[index] += value;
+        }
+        self.triggers.push(Trigger { at, sample: sample.to_string() });
+    }
+
+    /// The mix as clipped 16-bit samples
+    pub fn pcm16(&self) -> Vec<i16> {
+        self.samples.iter().map(|sample| to_i16(*sample)).collect()
+    }
+
+    pub fn write_wav(&self, path: &Path) -> Result<()> {
+        let mut writer = hound::WavWriter::create(path, wav_spec(self.sample_rate))?;
+        for sample in self.pcm16() {
+            writer.write_sample(sample)?;
+        }
+        writer.finalize()?;
+        Ok(())
+    }
+
+    pub fn write_flac(&self, path: &Path) -> Result<()> {
+        flac::write(path, CHANNELS, self.sample_rate, &self.pcm16())
+    }
+}
+
+fn wav_spec(sample_rate: u32) -> hound::WavSpec {
+    hound::WavSpec {
+        channels: CHANNELS,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    }
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+/// Discards everything. For running without audio hardware.
+pub struct NullBackend;
+
+struct NullOutput;
+
+impl OutputBackend for NullBackend {
+    fn list_devices(&self) -> Result<Vec<String>> {
+        Ok(vec!["null".to_string()])
+    }
+
+    fn default_device(&self) -> Option<String> {
+        Some("null".to_string())
+    }
+
+    fn open(&self, _device: Option<&str>, _counters: &Arc<Counters>) -> Result<Box<dyn AudioOutput>> {
+        Ok(Box::new(NullOutput))
+    }
+}
+
+impl AudioOutput for NullOutput {
+    fn requested(&self) -> Option<&str> {
+        None
+    }
+
+    fn name(&self) -> &str {
+        "null"
+    }
+
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    fn format(&self) -> (u16, u32) {
+        (CHANNELS, SAMPLE_RATE)
+    }
+
+    fn play(&self, _sample: &str, _sound: Sound) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Mixes everything into a shared `Recording`, timed from when the backend was created.
+/// Clones share the recording, so tests and the latency benchmark keep one to look at
+/// what was played.
+#[derive(Clone)]
+pub struct BufferBackend {
+    recording: Arc<Mutex<Recording>>,
+    started: Instant,
+}
+
+impl BufferBackend {
+    pub fn new() -> Self {
+        Self {
+            recording: Arc::new(Mutex::new(Recording::default())),
+            started: Instant::now(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn triggers(&self) -> Vec<Trigger> {
+        self.recording.lock().triggers.clone()
+    }
+
+    #[cfg(test)]
+    pub fn samples(&self) -> Vec<f32> {
+        self.recording.lock().samples.clone()
+    }
+}
+
+impl Default for BufferBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct BufferOutput(BufferBackend);
+
+impl OutputBackend for BufferBackend {
+    fn list_devices(&self) -> Result<Vec<String>> {
+        Ok(vec!["buffer".to_string()])
+    }
+
+    fn default_device(&self) -> Option<String> {
+        Some("buffer".to_string())
+    }
+
+    fn open(&self, _device: Option<&str>, _counters: &Arc<Counters>) -> Result<Box<dyn AudioOutput>> {
+        Ok(Box::new(BufferOutput(self.clone())))
+    }
+}
+
+impl AudioOutput for BufferOutput {
+    fn requested(&self) -> Option<&str> {
+        None
+    }
+
+    fn name(&self) -> &str {
+        "buffer"
+    }
+
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    fn format(&self) -> (u16, u32) {
+        (CHANNELS, SAMPLE_RATE)
+    }
+
+    fn play(&self, sample: &str, sound: Sound) -> Result<()> {
+        let at = self.0.started.elapsed();
+        self.0.recording.lock().add(at, sample, sound);
+        Ok(())
+    }
+}
+
+/// Streams a live session into a WAV file. Audio is written up to the start of each new
+/// sound, since nothing later can change it, so the tail of the last sound is only written
+/// once another one plays.
+#[derive(Clone)]
+pub struct WavFileBackend(Arc<Mutex<WavFile>>);
+
+struct WavFile {
+    writer: hound::WavWriter<BufWriter<File>>,
+    /// Audio not written yet, starting at `written`
+    pending: Recording,
+    written: Duration,
+    started: Instant,
+}
+
+impl WavFileBackend {
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Self(Arc::new(Mutex::new(WavFile {
+            writer: hound::WavWriter::create(path, wav_spec(SAMPLE_RATE))?,
+            pending: Recording::default(),
+            written: Duration::ZERO,
+            started: Instant::now(),
+        }))))
+    }
+}
+
+struct WavFileOutput(WavFileBackend);
+
+impl OutputBackend for WavFileBackend {
+    fn list_devices(&self) -> Result<Vec<String>> {
+        Ok(vec!["wav".to_string()])
+    }
+
+    fn default_device(&self) -> Option<String> {
+        Some("wav".to_string())
+    }
+
+    fn open(&self, _device: Option<&str>, _counters: &Arc<Counters>) -> Result<Box<dyn AudioOutput>> {
+        Ok(Box::new(WavFileOutput(self.clone())))
+    }
+}
+
+impl AudioOutput for WavFileOutput {
+    fn requested(&self) -> Option<&str> {
+        None
+    }
+
+    fn name(&self) -> &str {
+        "wav"
+    }
+
+    fn is_healthy(&self) -> bool {
+        true
+    }
+
+    fn format(&self) -> (u16, u32) {
+        (CHANNELS, SAMPLE_RATE)
+    }
+
+    fn play(&self, sample: &str, sound: Sound) -> Result<()> {
+        let mut file = self.0 .0.lock();
+        let offset = file.started.elapsed().saturating_sub(file.written);
+        file.pending.add(offset, sample, sound);
+        file.pending.triggers.clear();
+
+        let frames = (offset.as_secs_f64() * SAMPLE_RATE as f64).round() as usize;
+        let ready_len = frames * CHANNELS as usize;
+        // Nothing pending reaches this far when every sound so far was shorter than the
+        // gap, or empty. The gap is silence.
+        if file.pending.samples.len() < ready_len {
+            file.pending.samples.resize(ready_len, 0.0);
+        }
+        let ready: Vec<f32> = file.pending.samples.drain(..ready_len).collect();
+        for value in ready {
+            file.writer.write_sample(to_i16(value))?;
+        }
+        file.written += Duration::from_secs_f64(frames as f64 / SAMPLE_RATE as f64);
+        // Keeps the header valid in case the process is killed
+        file.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn tone(value: f32, frames: usize) -> Sound {
+        Box::new(SamplesBuffer::new(2, SAMPLE_RATE, vec![value; frames * 2]))
+    }
+
+    #[test]
+    fn test_recording_mixes_at_offsets() {
+        let mut recording = Recording::default();
+        recording.add(Duration::ZERO, "a", tone(0.25, 4));
+        recording.add(Duration::from_secs_f64(2.0 / SAMPLE_RATE as f64), "b", tone(0.5, 4));
+
+        assert_eq!(recording.samples, vec![0.25, 0.25, 0.25, 0.25, 0.75, 0.75, 0.75, 0.75, 0.5, 0.5, 0.5, 0.5]);
+        let samples: Vec<&str> = recording.triggers.iter().map(|t| t.sample.as_str()).collect();
+        assert_eq!(samples, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_mono_is_upmixed() {
+        let mut recording = Recording::default();
+        recording.add(Duration::ZERO, "mono", Box::new(SamplesBuffer::new(1, SAMPLE_RATE, vec![0.5, 0.5])));
+        assert_eq!(recording.samples, vec![0.5, 0.5, 0.5, 0.5]);
+    }
+
+    #[test]
+    fn test_wav_file_streams_completed_audio() {
+        let path = std::env::temp_dir().join(format!("clickclack-stream-{}.wav", std::process::id()));
+        let backend = WavFileBackend::create(&path).unwrap();
+        let output = backend.open(None, &Arc::default()).unwrap();
+        output.play("a", tone(0.5, 4)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        output.play("b", tone(0.5, 4)).unwrap();
+
+        // The first tone and the silence after it are on disk, the second is pending
+        let reader = hound::WavReader::open(&path).unwrap();
+        let frames = reader.duration() as usize;
+        assert!(frames >= SAMPLE_RATE as usize / 50, "only {} frames written", frames);
+        assert_eq!(backend.0.lock().pending.samples.len(), 8);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_wav_file_writes_silence_for_empty_sounds() {
+        let path = std::env::temp_dir().join(format!("clickclack-empty-{}.wav", std::process::id()));
+        let backend = WavFileBackend::create(&path).unwrap();
+        let output = backend.open(None, &Arc::default()).unwrap();
+        output.play("empty", tone(0.5, 0)).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        output.play("empty", tone(0.5, 0)).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert!(reader.duration() as usize >= SAMPLE_RATE as usize / 50, "only {} frames written", reader.duration());
+        assert!(reader.samples::<i16>().all(|s| s.unwrap() == 0));
+        assert!(backend.0.lock().pending.samples.is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_wav_file_clips() {
+        let path = std::env::temp_dir().join(format!("clickclack-clip-{}.wav", std::process::id()));
+        let backend = WavFileBackend::create(&path).unwrap();
+        let output = backend.open(None, &Arc::default()).unwrap();
+        output.play("loud", tone(2.0, 3)).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        output.play("next", tone(0.0, 1)).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, CHANNELS);
+        let written: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+        assert_eq!(written.iter().filter(|s| **s == i16::MAX).count(), 6);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use anyhow::{Context, Result};
+use log::debug;
+use rodio::source::UniformSourceIterator;
+use rodio::{Decoder, Source};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::synth::{self, SynthParams};
+use super::trim::TrimStart;
+use crate::logging::Redact;
+use crate::packs;
+
+/// The samples of one pack, decoded and converted to an output's channels and rate, so a
+/// key only has to copy one. Built again when the pack or the output changes.
+#[derive(Default)]
+pub struct SampleCache {
+    switch_type: String,
+    format: (u16, u32),
+    /// By sample path without extension, e.g. `mxblue/press/GENERIC_R2`, with the file
+    /// below the switchtypes directory it was decoded from
+    samples: HashMap<String, (String, Arc<[f32]>)>,
+    /// The pack's synth.toml, for a synth pack
+    synth: Option<SynthParams>,
+}
+
+impl SampleCache {
+    /// Decodes every sample of `switch_type`. Samples that fail to decode are left out,
+    /// so playing one reports why. Synth packs have nothing to decode, so only their
+    /// parameters are kept.
+    pub fn build(switchtypes_dir: &Path, switch_type: &str, format: (u16, u32)) -> Self {
+        let mut samples = HashMap::new();
+        let pack_dir = switchtypes_dir.join(switch_type);
+        let synth = match synth::load(&pack_dir) {
+            Ok(params) => params,
+            Err(e) => {
+                debug!("Not caching {}: {:#}", switch_type, e);
+                return Self { switch_type: switch_type.to_string(), format, ..Self::default() };
+            }
+        };
+        if let (None, Ok(files)) = (&synth, packs::list_samples(&pack_dir)) {
+            for (sample, path) in files {
+                match decode(&path, format) {
+                    Ok(decoded) => {
+                        let file = path.strip_prefix(switchtypes_dir).unwrap_or(&path).display().to_string();
+                        samples.insert(format!("{}/{}", switch_type, sample), (file, decoded));
+                    }
+                    Err(e) => debug!("Not caching {}/{}: {:#}", switch_type, Redact(&sample), e),
+                }
+            }
+        }
+        Self { switch_type: switch_type.to_string(), format, samples, synth }
+    }
+
+    pub fn is_for(&self, switch_type: &str, format: (u16, u32)) -> bool {
+        self.switch_type == switch_type && self.format == format
+    }
+
+    /// The synth parameters of `switch_type`, if the cache is for that synth pack
+    pub fn synth(&self, switch_type: &str) -> Option<&SynthParams> {
+        self.synth.as_ref().filter(|_| self.switch_type == switch_type)
+    }
+
+    /// The sample at `sample`, as `SoundEvent::sample` names it, and the file it came from
+    pub fn get(&self, sample: &str) -> Option<(String, Cached)> {
+        let (file, samples) = self.samples.get(sample)?;
+        let (channels, sample_rate) = self.format;
+        Some((file.clone(), Cached { samples: samples.clone(), position: 0, channels, sample_rate }))
+    }
+}
+
+fn decode(path: &Path, (channels, sample_rate): (u16, u32)) -> Result<Arc<[f32]>> {
+    let file = File::open(path).context("Failed to open sound file")?;
+    let source = Decoder::new(BufReader::new(file)).context("Failed to decode sound file")?;
+    Ok(UniformSourceIterator::new(TrimStart::new(source.convert_samples()), channels, sample_rate).collect())
+}
+
+/// Plays a cached sample without copying it
+pub struct Cached {
+    samples: Arc<[f32]>,
+    position: usize,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl Iterator for Cached {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.samples.get(self.position)?;
+        self.position += 1;
+        Some(*sample)
+    }
+}
+
+impl Source for Cached {
+    fn current_frame_len(&self) -> Option<usize> {
+        Some(self.samples.len() - self.position)
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        let frames = self.samples.len() / self.channels.max(1) as usize;
+        Some(Duration::from_secs_f64(frames as f64 / self.sample_rate as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::sound::fixtures::test_pack;
+
+    #[test]
+    fn test_samples_are_converted_to_the_output_format() {
+        let switchtypes_dir = test_pack("cache");
+        let cache = SampleCache::build(&switchtypes_dir, "test", (2, 22050));
+        assert!(cache.is_for("test", (2, 22050)));
+        assert!(!cache.is_for("test", (2, 48000)));
+
+        let (file, sound) = cache.get("test/press/GENERIC_R3").unwrap();
+        assert_eq!(file, "test/press/GENERIC_R3.wav");
+        assert_eq!((sound.channels(), sound.sample_rate()), (2, 22050));
+        // 441 frames at 44.1 kHz, half as many at 22.05 kHz, on both channels
+        let frames = sound.count() / 2;
+        assert!((219..=221).contains(&frames), "{} frames", frames);
+        assert!(cache.get("other/press/GENERIC_R3").is_none());
+    }
+
+    #[test]
+    fn test_synth_packs_keep_their_parameters() {
+        let switchtypes_dir = test_pack("cache-synth");
+        let params = synth::preset("mxblue").unwrap();
+        synth::save(&params, &switchtypes_dir.join("synthblue")).unwrap();
+        let cache = SampleCache::build(&switchtypes_dir, "synthblue", (2, 44100));
+        assert!(cache.samples.is_empty());
+        assert_eq!(cache.synth("synthblue"), Some(&params));
+        assert!(cache.synth("test").is_none());
+
+        let missing = SampleCache::build(&switchtypes_dir, "not-installed", (2, 44100));
+        assert!(missing.samples.is_empty() && missing.synth("not-installed").is_none());
+        assert!(SampleCache::build(&switchtypes_dir, "test", (2, 44100)).synth("test").is_none());
+    }
+}
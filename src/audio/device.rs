@@ -0,0 +1,42 @@
+use anyhow::{Context, Result};
+use rodio::cpal::traits::{DeviceTrait, HostTrait};
+
+/// Names of the output devices cpal can see right now
+pub fn list_devices() -> Result<Vec<String>> {
+    let host = rodio::cpal::default_host();
+    let devices = host.output_devices().context("Failed to enumerate output devices")?;
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
+
+pub fn default_device_name() -> Option<String> {
+    rodio::cpal::default_host().default_output_device()?.name().ok()
+}
+
+/// The rate the device mixes at, so samples can be prepared to need no resampling
+pub fn sample_rate(name: Option<&str>) -> Option<u32> {
+    let host = rodio::cpal::default_host();
+    let named = name.and_then(|name| host.output_devices().ok()?.find(|device| device.name().ok().as_deref() == Some(name)));
+    let device = named.or_else(|| host.default_output_device())?;
+    Some(device.default_output_config().ok()?.sample_rate().0)
+}
+
+/// The device to play on: the configured one while it is plugged in, otherwise the default
+pub fn target(wanted: Option<&str>, available: &[String]) -> Option<String> {
+    wanted.filter(|name| available.iter().any(|device| device == name)).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn devices() -> Vec<String> {
+        vec!["Speakers".to_string(), "Headphones".to_string()]
+    }
+
+    #[test]
+    fn test_target() {
+        assert_eq!(target(Some("Headphones"), &devices()), Some("Headphones".to_string()));
+        assert_eq!(target(Some("USB DAC"), &devices()), None);
+        assert_eq!(target(None, &devices()), None);
+    }
+}
@@ -0,0 +1,280 @@
+//! A small FLAC encoder for rendered sessions. It uses fixed blocks, independent channels,
+//! and the fixed predictors with one Rice partition per subframe. That compresses clicks
+//! and silence well without pulling in a full encoder.
+
+use anyhow::Result;
+use std::path::Path;
+
+const BLOCK_SIZE: usize = 4096;
+const BITS_PER_SAMPLE: u32 = 16;
+/// Highest parameter of the 4-bit Rice coding method, 15 is the escape code
+const MAX_RICE_PARAMETER: u32 = 14;
+
+/// Writes interleaved 16-bit samples to a FLAC file
+pub fn write(path: &Path, channels: u16, sample_rate: u32, samples: &[i16]) -> Result<()> {
+    std::fs::write(path, encode(channels, sample_rate, samples))?;
+    Ok(())
+}
+
+fn encode(channels: u16, sample_rate: u32, samples: &[i16]) -> Vec<u8> {
+    let channel_count = channels as usize;
+    let frames = samples.len() / channel_count;
+
+    let mut stream = BitWriter::default();
+    stream.write_bytes(b"fLaC");
+    // STREAMINFO, the only metadata block
+    stream.write(1, 1);
+    stream.write(0, 7);
+    stream.write(34, 24);
+    stream.write(BLOCK_SIZE as u64, 16);
+    stream.write(BLOCK_SIZE as u64, 16);
+    // Minimum and maximum frame size, unknown
+    stream.write(0, 24);
+    stream.write(0, 24);
+    stream.write(sample_rate as u64, 20);
+    stream.write(channels as u64 - 1, 3);
+    stream.write(BITS_PER_SAMPLE as u64 - 1, 5);
+    stream.write(frames as u64 >> 32, 4);
+    stream.write(frames as u64, 32);
+    // No MD5 signature
+    stream.write_bytes(&[0; 16]);
+
+    for (number, block) in samples[..frames * channel_count].chunks(BLOCK_SIZE * channel_count).enumerate() {
+        let deinterleaved: Vec<Vec<i32>> = (0..channel_count)
+            .map(|channel| block.iter().skip(channel).step_by(channel_count).map(|s| *s as i32).collect())
+            .collect();
+        stream.write_bytes(&encode_frame(number as u64, &deinterleaved));
+    }
+    stream.bytes
+}
+
+fn encode_frame(number: u64, channels: &[Vec<i32>]) -> Vec<u8> {
+    let block_size = channels[0].len();
+    let mut frame = BitWriter::default();
+    frame.write(0b11111111111110, 14);
+    frame.write(0, 1);
+    // Fixed block size stream
+    frame.write(0, 1);
+    // Block size follows the frame number as 16 bits
+    frame.write(0b0111, 4);
+    // Sample rate from STREAMINFO
+    frame.write(0b0000, 4);
+    // Independent channels
+    frame.write(channels.len() as u64 - 1, 4);
+    // 16 bits per sample
+    frame.write(0b100, 3);
+    frame.write(0, 1);
+    write_utf8(&mut frame, number);
+    frame.write(block_size as u64 - 1, 16);
+    let crc = crc8(&frame.bytes);
+    frame.write(crc as u64, 8);
+
+    for samples in channels {
+        write_subframe(&mut frame, samples);
+    }
+    frame.align();
+    let crc = crc16(&frame.bytes);
+    frame.write(crc as u64, 16);
+    frame.bytes
+}
+
+fn write_subframe(writer: &mut BitWriter, samples: &[i32]) {
+    // Zero padding bit, then the type, then no wasted bits
+    writer.write(0, 1);
+    if samples.iter().all(|sample| *sample == samples[0]) {
+        writer.write(0b000000, 6);
+        writer.write(0, 1);
+        writer.write(samples[0] as u64, BITS_PER_SAMPLE);
+        return;
+    }
+
+    let verbatim_bits = samples.len() as u64 * BITS_PER_SAMPLE as u64;
+    let best = (0..=4)
+        .filter(|order| *order < samples.len())
+        .map(|order| {
+            let residual = residual(samples, order);
+            let (parameter, bits) = rice_parameter(&residual);
+            let total = (order as u64 * BITS_PER_SAMPLE as u64) + 10 + bits;
+            (order, residual, parameter, total)
+        })
+        .min_by_key(|(_, _, _, total)| *total);
+
+    match best {
+        Some((order, residual, parameter, total)) if total < verbatim_bits => {
+            writer.write(0b001000 | order as u64, 6);
+            writer.write(0, 1);
+            for sample in &samples[..order] {
+                writer.write(*sample as u64, BITS_PER_SAMPLE);
+            }
+            // 4-bit Rice parameters, one partition
+            writer.write(0b00, 2);
+            writer.write(0, 4);
+            writer.write(parameter as u64, 4);
+            for value in residual {
+                let value = zigzag(value);
+                writer.write_unary(value >> parameter);
+                writer.write(value, parameter);
+            }
+        }
+        _ => {
+            writer.write(0b000001, 6);
+            writer.write(0, 1);
+            for sample in samples {
+                writer.write(*sample as u64, BITS_PER_SAMPLE);
+            }
+        }
+    }
+}
+
+/// What is left after the fixed polynomial predictor of `order`
+fn residual(samples: &[i32], order: usize) -> Vec<i64> {
+    samples
+        .windows(order + 1)
+        .map(|window| {
+            let s = |back: usize| window[order - back] as i64;
+            match order {
+                0 => s(0),
+                1 => s(0) - s(1),
+                2 => s(0) - 2 * s(1) + s(2),
+                3 => s(0) - 3 * s(1) + 3 * s(2) - s(3),
+                _ => s(0) - 4 * s(1) + 6 * s(2) - 4 * s(3) + s(4),
+            }
+        })
+        .collect()
+}
+
+/// The Rice parameter that codes `residual` in the fewest bits, and that size
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    (0..=MAX_RICE_PARAMETER)
+        .map(|parameter| {
+            let bits = residual.iter().map(|value| (zigzag(*value) >> parameter) + 1 + parameter as u64).sum();
+            (parameter, bits)
+        })
+        .min_by_key(|(_, bits)| *bits)
+        .unwrap()
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+/// The frame number, coded like UTF-8 but up to 36 bits
+fn write_utf8(writer: &mut BitWriter, value: u64) {
+    if value < 0x80 {
+        writer.write(value, 8);
+        return;
+    }
+    let length = (2..=7).find(|length| value < 1 << (5 * length + 1)).unwrap();
+    let prefix = (0xff00u64 >> length) & 0xff;
+    writer.write(prefix | (value >> (6 * (length - 1))), 8);
+    for index in (0..length - 1).rev() {
+        writer.write(0x80 | ((value >> (6 * index)) & 0x3f), 8);
+    }
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 })
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ ((*byte as u16) << 8), |crc, _| if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 })
+    })
+}
+
+/// Big-endian bit writer
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    pending: u64,
+    pending_bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the low `bits` of `value`, at most 32
+    fn write(&mut self, value: u64, bits: u32) {
+        let mask = (1u64 << bits) - 1;
+        self.pending = (self.pending << bits) | (value & mask);
+        self.pending_bits += bits;
+        while self.pending_bits >= 8 {
+            self.pending_bits -= 8;
+            self.bytes.push((self.pending >> self.pending_bits) as u8);
+        }
+        self.pending &= (1 << self.pending_bits) - 1;
+    }
+
+    /// `value` zeros followed by a one
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u64, 8);
+        }
+    }
+
+    fn align(&mut self) {
+        if self.pending_bits > 0 {
+            self.write(0, 8 - self.pending_bits);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn decode(bytes: Vec<u8>) -> (claxon::metadata::StreamInfo, Vec<i16>) {
+        let mut reader = claxon::FlacReader::new(Cursor::new(bytes)).unwrap();
+        let samples = reader.samples().map(|sample| sample.unwrap() as i16).collect();
+        (reader.streaminfo(), samples)
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // Silence, a click and a full scale square wave, over several blocks
+        let mut samples = vec![0i16; 3000];
+        samples.extend((0..2000).map(|i| ((i as f32 * 0.05).sin() * 12000.0 * (-(i as f32) / 500.0).exp()) as i16));
+        samples.extend((0..6000).map(|i| if (i / 7) % 2 == 0 { i16::MAX } else { i16::MIN }));
+        samples.extend((0..1000).map(|i| (i * 7919 % 65536 - 32768) as i16));
+
+        let (info, decoded) = decode(encode(2, 48000, &samples));
+        assert_eq!(info.sample_rate, 48000);
+        assert_eq!(info.channels, 2);
+        assert_eq!(info.samples, Some(samples.len() as u64 / 2));
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn test_short_stream() {
+        let samples = vec![5, -5, 1000, -32768];
+        let (info, decoded) = decode(encode(1, 22050, &samples));
+        assert_eq!(info.samples, Some(4));
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn test_silence_compresses() {
+        let samples = vec![0i16; 44100 * 2];
+        let bytes = encode(2, 44100, &samples);
+        assert!(bytes.len() < 1000, "{} bytes", bytes.len());
+        assert_eq!(decode(bytes).1, samples);
+    }
+
+    #[test]
+    fn test_utf8_frame_numbers() {
+        let mut writer = BitWriter::default();
+        write_utf8(&mut writer, 0x7f);
+        write_utf8(&mut writer, 0x80);
+        write_utf8(&mut writer, 0x10000);
+        assert_eq!(writer.bytes, vec![0x7f, 0xc2, 0x80, 0xf0, 0x90, 0x80, 0x80]);
+    }
+}
@@ -0,0 +1,153 @@
+use rodio::Source;
+use std::time::Duration;
+
+/// Highest volume the slider and the control interfaces accept. Anything above 1.0 boosts.
+pub const MAX_VOLUME: f32 = 1.5;
+
+/// Attenuation at the bottom of the slider, just above mute
+const MIN_DB: f32 = -40.0;
+/// Boost at `MAX_VOLUME`
+const MAX_BOOST_DB: f32 = 12.0;
+
+/// Limiter ceiling, a little under full scale
+const LIMITER_CEILING: f32 = 0.98;
+const LIMITER_RELEASE: Duration = Duration::from_millis(50);
+
+/// Maps a volume position to decibels. 1.0 is unity, the range below it is linear in dB
+/// so the slider feels even, and 0.0 mutes.
+pub fn volume_to_db(volume: f32) -> f32 {
+    if volume <= 1.0 {
+        MIN_DB * (1.0 - volume)
+    } else {
+        MAX_BOOST_DB * (volume.min(MAX_VOLUME) - 1.0) / (MAX_VOLUME - 1.0)
+    }
+}
+
+pub fn db_to_amplitude(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+/// Linear gain for a volume position plus the master gain from config
+pub fn gain(volume: f32, master_gain_db: f32) -> f32 {
+    if volume <= 0.0 {
+        return 0.0;
+    }
+    db_to_amplitude(volume_to_db(volume) + master_gain_db)
+}
+
+/// Keeps samples under `LIMITER_CEILING`. Peaks are caught instantly and the gain
+/// reduction recovers over `LIMITER_RELEASE`. Interleaved channels share one reduction.
+pub struct PeakLimiter {
+    reduction: f32,
+    release: f32,
+}
+
+impl PeakLimiter {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let samples_per_release = LIMITER_RELEASE.as_secs_f32() * sample_rate as f32 * channels.max(1) as f32;
+        Self { reduction: 1.0, release: 1.0 - (-1.0 / samples_per_release).exp() }
+    }
+
+    pub fn limit(&mut self, sample: f32) -> f32 {
+        let peak = sample.abs();
+        self.reduction += (1.0 - self.reduction) * self.release;
+        if peak * self.reduction > LIMITER_CEILING {
+            self.reduction = LIMITER_CEILING / peak;
+        }
+        sample * self.reduction
+    }
+}
+
+/// Applies a gain and keeps the result under `LIMITER_CEILING`
+pub struct Limiter<S> {
+    source: S,
+    gain: f32,
+    limiter: PeakLimiter,
+}
+
+impl<S: Source<Item = f32>> Limiter<S> {
+    pub fn new(source: S, gain: f32) -> Self {
+        let limiter = PeakLimiter::new(source.channels(), source.sample_rate());
+        Self { source, gain, limiter }
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for Limiter<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.source.next()? * self.gain;
+        Some(self.limiter.limit(sample))
+    }
+}
+
+impl<S: Source<Item = f32>> Source for Limiter<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn test_curve_endpoints() {
+        assert_eq!(gain(0.0, 0.0), 0.0);
+        assert_eq!(gain(1.0, 0.0), 1.0);
+        assert_eq!(volume_to_db(0.5), -20.0);
+        assert_eq!(volume_to_db(MAX_VOLUME), MAX_BOOST_DB);
+        assert_eq!(volume_to_db(3.0), MAX_BOOST_DB);
+    }
+
+    #[test]
+    fn test_curve_is_monotonic() {
+        let gains: Vec<f32> = (0..=150).map(|step| gain(step as f32 / 100.0, 0.0)).collect();
+        assert!(gains.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn test_master_gain_shifts_curve() {
+        assert!((gain(1.0, -6.0) - 0.501).abs() < 0.001);
+        assert!((gain(0.5, 20.0) - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_limiter_passes_quiet_signal() {
+        let samples = vec![0.1, -0.2, 0.3, -0.4];
+        let limited: Vec<f32> = Limiter::new(SamplesBuffer::new(1, 44100, samples.clone()), 1.0).collect();
+        assert_eq!(limited, samples);
+    }
+
+    #[test]
+    fn test_limiter_catches_boosted_peaks() {
+        let sine: Vec<f32> = (0..4410).map(|i| (i as f32 * 0.1).sin()).collect();
+        let limited: Vec<f32> = Limiter::new(SamplesBuffer::new(1, 44100, sine), db_to_amplitude(12.0)).collect();
+        assert!(limited.iter().all(|sample| sample.abs() <= LIMITER_CEILING + f32::EPSILON));
+        // Still louder than the unboosted signal
+        assert!(limited.iter().any(|sample| sample.abs() > 0.9));
+    }
+
+    #[test]
+    fn test_peak_limiter_catches_summed_voices() {
+        let mut limiter = PeakLimiter::new(2, 44100);
+        let limited: Vec<f32> = [0.5, 0.75, 1.5, -2.0, 0.5].iter().map(|sample| limiter.limit(*sample)).collect();
+        assert_eq!(limited[..2], [0.5, 0.75]);
+        assert!(limited.iter().all(|sample| sample.abs() <= LIMITER_CEILING + f32::EPSILON));
+        // Recovers slowly instead of letting the next sample jump back up
+        assert!(limited[4] < 0.5 * 0.6);
+    }
+}
@@ -0,0 +1,275 @@
+use rdev::Key;
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::input::session::SessionEvent;
+
+/// Buckets grow by a quarter octave from `FIRST_BUCKET_US`, up to about a second
+const BUCKETS: usize = 68;
+const BUCKETS_PER_OCTAVE: f64 = 4.0;
+const FIRST_BUCKET_US: f64 = 10.0;
+
+/// Steps of a key sound, each timed from the end of the one before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    /// From the key event reaching the keyboard callback to the sound being queued
+    Input,
+    /// Waiting in the channel to the audio thread
+    Channel,
+    /// Finding and opening the sample, or synthesizing the hit
+    Decode,
+    /// Until the output pulls the first sample, which includes waiting for its next buffer
+    Mix,
+    /// Key event to first sample
+    Total,
+}
+
+pub const STAGES: [Stage; 5] = [Stage::Input, Stage::Channel, Stage::Decode, Stage::Mix, Stage::Total];
+
+/// A histogram per stage. Recording is a couple of atomic adds, so it is safe to do from
+/// the audio callback.
+pub struct Latency {
+    stages: [Histogram; STAGES.len()],
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self { stages: std::array::from_fn(|_| Histogram::default()) }
+    }
+}
+
+impl Latency {
+    pub fn record(&self, stage: Stage, elapsed: Duration) {
+        self.stages[stage as usize].record(elapsed);
+    }
+
+    /// How many sounds made it through `stage`
+    pub fn count(&self, stage: Stage) -> u64 {
+        self.stages[stage as usize].count()
+    }
+
+    pub fn summary(&self) -> Vec<StageSummary> {
+        STAGES.iter().map(|stage| self.stages[*stage as usize].summary(*stage)).collect()
+    }
+}
+
+/// Times the `Mix` and `Total` stages of a sound when the output starts playing it
+pub struct StartTimer {
+    pub arrived: Instant,
+    pub loaded: Instant,
+    pub latency: Arc<Latency>,
+}
+
+impl StartTimer {
+    /// Safe to call from the audio callback
+    pub fn started(self) {
+        let now = Instant::now();
+        self.latency.record(Stage::Mix, now - self.loaded);
+        self.latency.record(Stage::Total, now - self.arrived);
+    }
+}
+
+/// Running counts of one engine, shared with its output's callback. Like `Latency`,
+/// counting is an atomic add.
+#[derive(Default)]
+pub struct Counters {
+    /// Sound events sent to the audio thread
+    events: AtomicU64,
+    /// Times the device ran out of audio before the callback refilled it
+    underruns: AtomicU64,
+    /// Sounds queued for or playing in a callback. The mixer takes a sound off when it
+    /// ends, before it is freed.
+    voices: AtomicUsize,
+}
+
+impl Counters {
+    pub fn events(&self) -> u64 {
+        self.events.load(Ordering::Relaxed)
+    }
+
+    pub fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
+    }
+
+    pub fn voices(&self) -> usize {
+        self.voices.load(Ordering::Relaxed)
+    }
+
+    pub fn count_event(&self) {
+        self.events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn count_underrun(&self) {
+        self.underruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn start_voice(&self) {
+        self.voices.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn end_voice(&self) {
+        self.voices.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageSummary {
+    pub stage: Stage,
+    pub count: u64,
+    pub p50_us: Option<u64>,
+    pub p99_us: Option<u64>,
+    /// The buckets that aren't empty
+    pub buckets: Vec<Bucket>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    /// Upper bound, inclusive
+    pub le_us: u64,
+    pub count: u64,
+}
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self { buckets: std::array::from_fn(|_| AtomicU64::new(0)) }
+    }
+}
+
+impl Histogram {
+    fn record(&self, elapsed: Duration) {
+        self.buckets[bucket(elapsed.as_micros() as u64)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> Vec<u64> {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect()
+    }
+
+    fn count(&self) -> u64 {
+        self.counts().iter().sum()
+    }
+
+    fn summary(&self, stage: Stage) -> StageSummary {
+        let counts = self.counts();
+        let count = counts.iter().sum();
+        StageSummary {
+            stage,
+            count,
+            p50_us: percentile(&counts, 0.5),
+            p99_us: percentile(&counts, 0.99),
+            buckets: counts
+                .iter()
+                .enumerate()
+                .filter(|(_, count)| **count > 0)
+                .map(|(index, count)| Bucket { le_us: upper_bound_us(index), count: *count })
+                .collect(),
+        }
+    }
+}
+
+fn bucket(us: u64) -> usize {
+    if us as f64 <= FIRST_BUCKET_US {
+        return 0;
+    }
+    let index = ((us as f64 / FIRST_BUCKET_US).log2() * BUCKETS_PER_OCTAVE).ceil() as usize;
+    index.min(BUCKETS - 1)
+}
+
+fn upper_bound_us(index: usize) -> u64 {
+    (FIRST_BUCKET_US * 2f64.powf(index as f64 / BUCKETS_PER_OCTAVE)).round() as u64
+}
+
+/// The upper bound of the bucket holding the `quantile`, so it is never understated
+fn percentile(counts: &[u64], quantile: f64) -> Option<u64> {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let rank = ((total as f64 * quantile).ceil() as u64).max(1);
+    let mut seen = 0;
+    for (index, count) in counts.iter().enumerate() {
+        seen += count;
+        if seen >= rank {
+            return Some(upper_bound_us(index));
+        }
+    }
+    None
+}
+
+/// Steady typing for the benchmark: `count` keys across the rows, each held 30 ms
+pub fn benchmark_events(count: usize, interval: Duration) -> Vec<SessionEvent> {
+    const KEYS: [Key; 5] = [Key::Num1, Key::KeyQ, Key::KeyA, Key::KeyZ, Key::Space];
+    const HOLD_US: u64 = 30_000;
+    let interval_us = interval.as_micros() as u64;
+    let mut events: Vec<SessionEvent> = (0..count)
+        .flat_map(|index| {
+            let (key, t_us) = (KEYS[index % KEYS.len()], index as u64 * interval_us);
+            [SessionEvent { t_us, key, press: true }, SessionEvent { t_us: t_us + HOLD_US, key, press: false }]
+        })
+        .collect();
+    events.sort_by_key(|event| event.t_us);
+    events
+}
+
+pub fn to_text(summary: &[StageSummary]) -> String {
+    let ms = |us: Option<u64>| us.map_or("-".to_string(), |us| format!("{:.2} ms", us as f64 / 1000.0));
+    let mut out = format!("{:<8} {:>7} {:>10} {:>10}\n", "Stage", "Count", "p50", "p99");
+    for stage in summary {
+        let name = format!("{:?}", stage.stage).to_lowercase();
+        let _ = writeln!(out, "{:<8} {:>7} {:>10} {:>10}", name, stage.count, ms(stage.p50_us), ms(stage.p99_us));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        assert_eq!(bucket(0), 0);
+        assert_eq!(bucket(10), 0);
+        assert_eq!(bucket(11), 1);
+        assert_eq!(bucket(20), 4);
+        assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+        // Every value lands in the bucket whose bound is the first one above it
+        for us in [15, 100, 999, 12_345, 500_000] {
+            assert!(upper_bound_us(bucket(us)) >= us);
+            assert!(upper_bound_us(bucket(us) - 1) < us);
+        }
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let latency = Latency::default();
+        assert_eq!(latency.summary()[0].p50_us, None);
+        for _ in 0..98 {
+            latency.record(Stage::Input, Duration::from_micros(100));
+        }
+        latency.record(Stage::Input, Duration::from_millis(5));
+        latency.record(Stage::Input, Duration::from_millis(20));
+
+        let input = &latency.summary()[0];
+        assert_eq!(input.count, 100);
+        assert_eq!(input.p50_us, Some(upper_bound_us(bucket(100))));
+        assert_eq!(input.p99_us, Some(upper_bound_us(bucket(5000))));
+        assert_eq!(input.buckets.len(), 3);
+        assert_eq!(latency.count(Stage::Total), 0);
+    }
+
+    #[test]
+    fn test_benchmark_events() {
+        let events = benchmark_events(10, Duration::from_millis(50));
+        assert_eq!(events.len(), 20);
+        assert_eq!(events.iter().filter(|event| event.press).count(), 10);
+        assert!(events.windows(2).all(|pair| pair[0].t_us <= pair[1].t_us));
+        assert_eq!(events.last().unwrap().t_us, 9 * 50_000 + 30_000);
+    }
+}
@@ -0,0 +1,145 @@
+use std::f64::consts::PI;
+
+/// Offset in the BS.1770 loudness formula, so a full scale 1 kHz sine reads -3 LUFS
+const LUFS_OFFSET: f64 = -0.691;
+
+/// Sums the K-weighted energy of clips of interleaved samples. Clicks are far shorter
+/// than the 400 ms blocks of BS.1770 gating, so the loudness is the ungated mean over
+/// everything added.
+#[derive(Debug, Default)]
+pub struct Meter {
+    energy: f64,
+    frames: u64,
+}
+
+impl Meter {
+    pub fn add(&mut self, samples: &[f32], channels: u16, sample_rate: u32) {
+        let channels = channels.max(1) as usize;
+        for channel in 0..channels {
+            let mut filter = KWeighting::new(sample_rate);
+            for sample in samples.iter().skip(channel).step_by(channels) {
+                let weighted = filter.process(*sample as f64);
+                self.energy += weighted * weighted;
+            }
+        }
+        self.frames += (samples.len() / channels) as u64;
+    }
+
+    /// Loudness in LUFS, or `None` before anything audible was added
+    pub fn lufs(&self) -> Option<f64> {
+        let mean = self.energy / self.frames.max(1) as f64;
+        (mean > 0.0).then(|| LUFS_OFFSET + 10.0 * mean.log10())
+    }
+}
+
+/// The BS.1770 pre-filter: a shelf for the head's acoustic boost above 1.5 kHz,
+/// then a high pass that ignores rumble
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    /// Designed for any rate from the filters' analog parameters, which reproduces the
+    /// 48 kHz coefficients of the standard exactly
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let (gain_db, q, freq) = (3.999_843_853_973_347, 0.707_175_236_955_419_3, 1_681.974_450_955_533);
+        let k = (PI * freq / rate).tan();
+        let high = 10f64.powf(gain_db / 20.0);
+        let band = high.powf(0.499_666_774_154_541_6);
+        let shelf = Biquad::new(
+            [high + band * k / q + k * k, 2.0 * (k * k - high), high - band * k / q + k * k],
+            [1.0 + k / q + k * k, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+        );
+
+        let (q, freq) = (0.500_327_037_325_395_3, 38.135_470_876_139_82);
+        let k = (PI * freq / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        // The standard leaves the numerator unscaled
+        let high_pass = Biquad::new(
+            [a0, -2.0 * a0, a0],
+            [a0, 2.0 * (k * k - 1.0), 1.0 - k / q + k * k],
+        );
+
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+/// Direct form I, normalized so `a[0]` is 1
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    inputs: [f64; 2],
+    outputs: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 3]) -> Self {
+        Self {
+            b: b.map(|coefficient| coefficient / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+            inputs: [0.0; 2],
+            outputs: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b[0] * input + self.b[1] * self.inputs[0] + self.b[2] * self.inputs[1]
+            - self.a[0] * self.outputs[0]
+            - self.a[1] * self.outputs[1];
+        self.inputs = [input, self.inputs[0]];
+        self.outputs = [output, self.outputs[0]];
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, amplitude: f32, sample_rate: u32) -> Vec<f32> {
+        (0..sample_rate)
+            .map(|i| amplitude * (2.0 * PI * freq * i as f64 / sample_rate as f64).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn test_full_scale_sine_reads_minus_three() {
+        for sample_rate in [44100, 48000] {
+            let mut meter = Meter::default();
+            meter.add(&sine(997.0, 1.0, sample_rate), 1, sample_rate);
+            let lufs = meter.lufs().unwrap();
+            assert!((lufs + 3.01).abs() < 0.05, "{} Hz: {} LUFS", sample_rate, lufs);
+        }
+    }
+
+    #[test]
+    fn test_weighting() {
+        let lufs = |freq| {
+            let mut meter = Meter::default();
+            meter.add(&sine(freq, 0.5, 48000), 1, 48000);
+            meter.lufs().unwrap()
+        };
+        // The shelf lifts the treble to 4 dB, and 1 kHz by less than 1 dB. The high pass
+        // cuts the rumble.
+        assert!((lufs(8000.0) - lufs(997.0) - 3.34).abs() < 0.05);
+        assert!(lufs(20.0) < lufs(997.0) - 6.0);
+    }
+
+    #[test]
+    fn test_stereo_sums_channels() {
+        let mono = sine(997.0, 0.5, 48000);
+        let stereo: Vec<f32> = mono.iter().flat_map(|sample| [*sample, *sample]).collect();
+        let (mut left, mut both) = (Meter::default(), Meter::default());
+        left.add(&mono, 1, 48000);
+        both.add(&stereo, 2, 48000);
+        assert!((both.lufs().unwrap() - left.lufs().unwrap() - 3.01).abs() < 0.01);
+        assert_eq!(Meter::default().lufs(), None);
+    }
+}
@@ -1,2 +1,17 @@
+pub mod buffer;
+mod cache;
+pub mod device;
+pub mod flac;
+pub mod gain;
+pub mod latency;
+pub mod loudness;
+pub mod output;
+pub mod preview;
+pub mod render;
+pub mod ring;
 mod sound;
-pub use sound::SoundEngine; 
\ No newline at end of file
+pub mod synth;
+pub mod trim;
+pub use sound::{SoundEngine, SoundEvent};
+#[cfg(test)]
+pub use sound::fixtures;
\ No newline at end of file
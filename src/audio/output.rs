@@ -0,0 +1,721 @@
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info, warn};
+use parking_lot::Mutex;
+use rodio::cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use rodio::cpal::{self, BufferSize, FromSample, SampleFormat, SizedSample, StreamInstant, SupportedBufferSize};
+use rodio::source::UniformSourceIterator;
+use rodio::Source;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::device;
+use super::gain::PeakLimiter;
+use super::latency::{Counters, StartTimer};
+use super::ring::{self, Consumer, Producer};
+
+/// Sounds the callback mixes at once, and sounds queued for it. Past this, new sounds
+/// are dropped rather than growing anything from inside the callback.
+const MAX_VOICES: usize = 64;
+
+/// A decoded sound ready to be mixed
+pub type Sound = Box<dyn Source<Item = f32> + Send>;
+
+/// An open output stream
+pub trait AudioOutput {
+    /// The device that was asked for, `None` when following the system default
+    fn requested(&self) -> Option<&str>;
+    /// The device actually playing
+    fn name(&self) -> &str;
+    /// False once the stream has reported an error. A dead stream never recovers.
+    fn is_healthy(&self) -> bool;
+    /// Channels and sample rate that sounds are mixed at
+    fn format(&self) -> (u16, u32);
+    /// Mixes in `sound`. `sample` names it for backends that record what was played.
+    fn play(&self, sample: &str, sound: Sound) -> Result<()>;
+
+    /// Like `play`, starting `timer` when the sound's first sample is pulled
+    fn play_timed(&self, sample: &str, sound: Sound, timer: StartTimer) -> Result<()> {
+        self.play(sample, Box::new(Timed { sound, timer: Some(timer) }))
+    }
+}
+
+/// Opens output streams. The real one talks to cpal; `buffer` has a null and a
+/// recording backend for running without a sound card.
+pub trait OutputBackend: Send {
+    fn list_devices(&self) -> Result<Vec<String>>;
+    fn default_device(&self) -> Option<String>;
+    /// Opens `device`, counting voices and underruns in `counters` where the output
+    /// mixes in a callback
+    fn open(&self, device: Option<&str>, counters: &Arc<Counters>) -> Result<Box<dyn AudioOutput>>;
+}
+
+pub struct CpalBackend {
+    /// Frames per buffer to ask the device for, `None` for its default
+    pub buffer_frames: Option<u32>,
+}
+
+impl OutputBackend for CpalBackend {
+    fn list_devices(&self) -> Result<Vec<String>> {
+        device::list_devices()
+    }
+
+    fn default_device(&self) -> Option<String> {
+        device::default_device_name()
+    }
+
+    fn open(&self, device: Option<&str>, counters: &Arc<Counters>) -> Result<Box<dyn AudioOutput>> {
+        Ok(Box::new(CpalOutput::open(device, self.buffer_frames, counters.clone())?))
+    }
+}
+
+/// A cpal stream with its own mixer. Sounds are rendered to the stream's format before
+/// they are queued, so the callback only sums samples: it never decodes, locks or
+/// allocates. Unlike `rodio::OutputStream` it notices when the stream errors, e.g.
+/// because the device was unplugged.
+pub struct CpalOutput {
+    _stream: cpal::Stream,
+    /// Sounds on their way to the callback, and finished ones on their way back to be
+    /// freed here. Only `play` takes these locks.
+    queue: Mutex<Producer<Rendered>>,
+    finished: Mutex<Consumer<Rendered>>,
+    channels: u16,
+    sample_rate: u32,
+    requested: Option<String>,
+    name: String,
+    healthy: Arc<AtomicBool>,
+    counters: Arc<Counters>,
+}
+
+impl CpalOutput {
+    pub fn open(requested: Option<&str>, buffer_frames: Option<u32>, counters: Arc<Counters>) -> Result<Self> {
+        let host = cpal::default_host();
+        let device = match requested {
+            Some(name) => host
+                .output_devices()?
+                .find(|device| device.name().map(|n| n == name).unwrap_or(false))
+                .with_context(|| format!("Output device not found: {}", name))?,
+            None => host.default_output_device().context("No default output device")?,
+        };
+        let name = device.name().unwrap_or_default();
+
+        let supported = device.default_output_config()?;
+        let mut config = supported.config();
+        config.buffer_size = buffer_size(supported.buffer_size(), buffer_frames);
+        let (stream, queue, finished, healthy) = match start(&device, supported.sample_format(), &config, &counters) {
+            Err(e) if config.buffer_size != BufferSize::Default => {
+                warn!("{:#}, using the default buffer size", e);
+                config.buffer_size = BufferSize::Default;
+                start(&device, supported.sample_format(), &config, &counters)
+            }
+            started => started,
+        }
+        .with_context(|| format!("Failed to open output device {}", name))?;
+
+        match config.buffer_size {
+            BufferSize::Fixed(frames) => info!("Playing on output device {} with {} frame buffers", name, frames),
+            BufferSize::Default => info!("Playing on output device {}", name),
+        }
+        Ok(Self {
+            _stream: stream,
+            queue: Mutex::new(queue),
+            finished: Mutex::new(finished),
+            channels: config.channels,
+            sample_rate: config.sample_rate.0,
+            requested: requested.map(str::to_string),
+            name,
+            healthy,
+            counters,
+        })
+    }
+
+    fn queue(&self, sound: Sound, timer: Option<StartTimer>) -> Result<()> {
+        if !self.is_healthy() {
+            return Err(anyhow!("Output stream on {} has failed", self.name));
+        }
+        // Free what the callback is done with before handing it more
+        while self.finished.lock().pop().is_some() {}
+
+        let mut sound = sound;
+        let samples = UniformSourceIterator::new(Draining(&mut sound), self.channels, self.sample_rate).collect();
+        if self.queue.lock().push(Rendered::new(samples, timer, sound, self.counters.clone())).is_err() {
+            bail!("{} sounds are already waiting to play, dropping this one", MAX_VOICES);
+        }
+        Ok(())
+    }
+}
+
+/// The device's buffer size closest to `frames`
+fn buffer_size(supported: &SupportedBufferSize, frames: Option<u32>) -> BufferSize {
+    match (frames, supported) {
+        (None, _) => BufferSize::Default,
+        (Some(frames), SupportedBufferSize::Range { min, max }) => BufferSize::Fixed(frames.max(*min).min(*max)),
+        (Some(frames), SupportedBufferSize::Unknown) => BufferSize::Fixed(frames),
+    }
+}
+
+type Started = (cpal::Stream, Producer<Rendered>, Consumer<Rendered>, Arc<AtomicBool>);
+
+fn start(device: &cpal::Device, format: SampleFormat, config: &cpal::StreamConfig, counters: &Arc<Counters>) -> Result<Started> {
+    let (queue, incoming) = ring::ring(MAX_VOICES);
+    // Room for every sound that can be queued or playing, so the callback never has to
+    // hold on to a finished one
+    let (done, finished) = ring::ring(4 * MAX_VOICES);
+    let mixer = Mixer::new(incoming, done, config, counters.clone());
+    let healthy = Arc::new(AtomicBool::new(true));
+
+    let stream = match format {
+        SampleFormat::F32 => build_stream::<f32>(device, config, mixer, healthy.clone()),
+        SampleFormat::I16 => build_stream::<i16>(device, config, mixer, healthy.clone()),
+        SampleFormat::U16 => build_stream::<u16>(device, config, mixer, healthy.clone()),
+        format => bail!("Unsupported sample format {}", format),
+    }?;
+    stream.play()?;
+    Ok((stream, queue, finished, healthy))
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut mixer: Mixer,
+    healthy: Arc<AtomicBool>,
+) -> Result<cpal::Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], info| {
+            let timestamp = info.timestamp();
+            mixer.count_underrun(timestamp.callback, timestamp.playback, data.len());
+            mixer.mix(data);
+        },
+        move |e| {
+            error!("Output stream error: {}", e);
+            healthy.store(false, Ordering::SeqCst);
+        },
+        None,
+    )?;
+    Ok(stream)
+}
+
+/// A sound at the stream's channels and rate
+struct Rendered {
+    samples: Vec<f32>,
+    position: usize,
+    timer: Option<StartTimer>,
+    /// Taken off the voice count, and only waiting to be handed back
+    retired: bool,
+    counters: Arc<Counters>,
+    /// The drained sound, kept so it is freed off the callback along with the samples
+    _sound: Sound,
+}
+
+impl Rendered {
+    fn new(samples: Vec<f32>, timer: Option<StartTimer>, sound: Sound, counters: Arc<Counters>) -> Self {
+        counters.start_voice();
+        Self { samples, position: 0, timer, retired: false, counters, _sound: sound }
+    }
+
+    fn retire(&mut self) {
+        if !std::mem::replace(&mut self.retired, true) {
+            self.counters.end_voice();
+        }
+    }
+}
+
+impl Drop for Rendered {
+    /// Dropped without finishing, e.g. because the stream closed
+    fn drop(&mut self) {
+        self.retire();
+    }
+}
+
+/// Lets `UniformSourceIterator` drain a sound without taking it
+struct Draining<'a>(&'a mut Sound);
+
+impl Iterator for Draining<'_> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.0.next()
+    }
+}
+
+impl Source for Draining<'_> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.0.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.0.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.0.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.0.total_duration()
+    }
+}
+
+/// Owned by the callback
+struct Mixer {
+    incoming: Consumer<Rendered>,
+    done: Producer<Rendered>,
+    /// Allocated up front for `MAX_VOICES`, and never grown
+    playing: Vec<Rendered>,
+    /// On the sum, so voices that play together don't clip
+    limiter: PeakLimiter,
+    channels: usize,
+    sample_rate: u32,
+    /// When the device plays the end of what the last callback wrote
+    runs_dry: Option<StreamInstant>,
+    counters: Arc<Counters>,
+}
+
+impl Mixer {
+    fn new(incoming: Consumer<Rendered>, done: Producer<Rendered>, config: &cpal::StreamConfig, counters: Arc<Counters>) -> Self {
+        Self {
+            incoming,
+            done,
+            playing: Vec::with_capacity(MAX_VOICES),
+            limiter: PeakLimiter::new(config.channels, config.sample_rate.0),
+            channels: config.channels.max(1) as usize,
+            sample_rate: config.sample_rate.0,
+            runs_dry: None,
+            counters,
+        }
+    }
+
+    /// Counts an underrun when the callback comes after the device already played out
+    /// the last buffer
+    fn count_underrun(&mut self, callback: StreamInstant, playback: StreamInstant, len: usize) {
+        if self.runs_dry.is_some_and(|runs_dry| callback > runs_dry) {
+            self.counters.count_underrun();
+        }
+        let frames = (len / self.channels) as u64;
+        self.runs_dry = playback.add(Duration::from_nanos(frames * 1_000_000_000 / self.sample_rate as u64));
+    }
+
+    fn mix<T: SizedSample + FromSample<f32>>(&mut self, data: &mut [T]) {
+        while self.playing.len() < MAX_VOICES {
+            let Some(mut voice) = self.incoming.pop() else { break };
+            if let Some(timer) = voice.timer.take() {
+                timer.started();
+            }
+            self.playing.push(voice);
+        }
+
+        for sample in data.iter_mut() {
+            let mut mixed = 0.0;
+            for voice in self.playing.iter_mut() {
+                if let Some(value) = voice.samples.get(voice.position) {
+                    mixed += value;
+                    voice.position += 1;
+                }
+            }
+            *sample = T::from_sample(self.limiter.limit(mixed));
+        }
+
+        let mut index = 0;
+        while index < self.playing.len() {
+            let voice = &mut self.playing[index];
+            if voice.position < voice.samples.len() {
+                index += 1;
+                continue;
+            }
+            voice.retire();
+            // Can't be full while the audio thread keeps up. If it is, the voice stays
+            // here until there is room, so nothing is freed in the callback.
+            if let Err(voice) = self.done.push(self.playing.swap_remove(index)) {
+                self.playing.push(voice);
+                break;
+            }
+        }
+    }
+}
+
+
+impl AudioOutput for CpalOutput {
+    fn requested(&self) -> Option<&str> {
+        self.requested.as_deref()
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::SeqCst)
+    }
+
+    fn format(&self) -> (u16, u32) {
+        (self.channels, self.sample_rate)
+    }
+
+    fn play(&self, _sample: &str, sound: Sound) -> Result<()> {
+        self.queue(sound, None)
+    }
+
+    fn play_timed(&self, _sample: &str, sound: Sound, timer: StartTimer) -> Result<()> {
+        self.queue(sound, Some(timer))
+    }
+}
+
+/// Starts a timer when the sound's first sample is pulled
+struct Timed {
+    sound: Sound,
+    timer: Option<StartTimer>,
+}
+
+impl Iterator for Timed {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if let Some(timer) = self.timer.take() {
+            timer.started();
+        }
+        self.sound.next()
+    }
+}
+
+impl Source for Timed {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.sound.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.sound.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sound.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.sound.total_duration()
+    }
+}
+
+/// Keeps an output open on the right device, re-creating it when the stream dies, the
+/// configured device comes or goes, or the system default moves
+pub struct OutputSupervisor {
+    backend: Box<dyn OutputBackend>,
+    output: Option<Box<dyn AudioOutput>>,
+    /// Handed to every output it opens, so the counts carry over when it re-creates one
+    counters: Arc<Counters>,
+}
+
+impl OutputSupervisor {
+    pub fn new(backend: Box<dyn OutputBackend>, counters: Arc<Counters>) -> Self {
+        Self { backend, output: None, counters }
+    }
+
+    /// Re-creates the output if it is dead or on the wrong device
+    pub fn check(&mut self, wanted: Option<&str>) -> Result<()> {
+        let available = self.backend.list_devices().unwrap_or_else(|e| {
+            warn!("{:#}", e);
+            Vec::new()
+        });
+        let target = device::target(wanted, &available);
+
+        let reopen = match &self.output {
+            None => true,
+            Some(output) if !output.is_healthy() => {
+                warn!("Output stream on {} failed, re-creating it", output.name());
+                true
+            }
+            Some(output) if output.requested() != target.as_deref() => true,
+            // Following the system default, which moved
+            Some(output) => output.requested().is_none()
+                && self.backend.default_device().is_some_and(|default| default != output.name()),
+        };
+
+        if reopen {
+            self.open(target.as_deref())?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self, target: Option<&str>) -> Result<()> {
+        // Close the old stream first, some backends can't open a device twice
+        self.output = None;
+        let output = match self.backend.open(target, &self.counters) {
+            Ok(output) => output,
+            Err(e) if target.is_some() => {
+                warn!("{:#}, falling back to the default", e);
+                self.backend.open(None, &self.counters)?
+            }
+            Err(e) => return Err(e),
+        };
+        self.output = Some(output);
+        Ok(())
+    }
+
+    /// Plays a sound, re-creating a dead output first. `timer` is started when the sound
+    /// starts playing.
+    pub fn play(&mut self, wanted: Option<&str>, sample: &str, sound: Sound, timer: Option<StartTimer>) {
+        if let Some(output) = self.healthy_output(wanted) {
+            let played = match timer {
+                Some(timer) => output.play_timed(sample, sound, timer),
+                None => output.play(sample, sound),
+            };
+            if let Err(e) = played {
+                error!("{:#}", e);
+            }
+        }
+    }
+
+    fn healthy_output(&mut self, wanted: Option<&str>) -> Option<&dyn AudioOutput> {
+        if !self.output.as_ref().is_some_and(|output| output.is_healthy()) {
+            if let Err(e) = self.check(wanted) {
+                error!("Failed to re-create output stream: {:#}", e);
+                return None;
+            }
+        }
+        self.output.as_deref()
+    }
+
+    /// The format of the open output, `None` while there is none
+    pub fn format(&self) -> Option<(u16, u32)> {
+        self.output.as_ref().map(|output| output.format())
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+
+    #[derive(Default)]
+    pub struct FakeState {
+        pub devices: Vec<String>,
+        pub default: String,
+        pub opened: Vec<String>,
+        /// Sounds played, as (device, samples)
+        pub played: Vec<(String, Vec<f32>)>,
+        /// Streams that have "errored"
+        pub failed: Vec<usize>,
+    }
+
+    /// Pretend devices. `fail_current` kills the newest stream the way an unplug would.
+    #[derive(Clone, Default)]
+    pub struct FakeBackend(pub Arc<Mutex<FakeState>>);
+
+    impl FakeBackend {
+        pub fn new(devices: &[&str], default: &str) -> Self {
+            let backend = Self::default();
+            {
+                let mut state = backend.0.lock();
+                state.devices = devices.iter().map(|d| d.to_string()).collect();
+                state.default = default.to_string();
+            }
+            backend
+        }
+
+        pub fn fail_current(&self) {
+            let mut state = self.0.lock();
+            let current = state.opened.len() - 1;
+            state.failed.push(current);
+        }
+    }
+
+    struct FakeOutput {
+        backend: FakeBackend,
+        index: usize,
+        requested: Option<String>,
+        name: String,
+    }
+
+    impl OutputBackend for FakeBackend {
+        fn list_devices(&self) -> Result<Vec<String>> {
+            Ok(self.0.lock().devices.clone())
+        }
+
+        fn default_device(&self) -> Option<String> {
+            Some(self.0.lock().default.clone())
+        }
+
+        fn open(&self, device: Option<&str>, _counters: &Arc<Counters>) -> Result<Box<dyn AudioOutput>> {
+            let mut state = self.0.lock();
+            let name = device.map(str::to_string).unwrap_or_else(|| state.default.clone());
+            if !state.devices.contains(&name) {
+                bail!("Output device not found: {}", name);
+            }
+            state.opened.push(name.clone());
+            Ok(Box::new(FakeOutput {
+                backend: self.clone(),
+                index: state.opened.len() - 1,
+                requested: device.map(str::to_string),
+                name,
+            }))
+        }
+    }
+
+    impl AudioOutput for FakeOutput {
+        fn requested(&self) -> Option<&str> {
+            self.requested.as_deref()
+        }
+
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn is_healthy(&self) -> bool {
+            !self.backend.0.lock().failed.contains(&self.index)
+        }
+
+        fn format(&self) -> (u16, u32) {
+            (1, 44100)
+        }
+
+        fn play(&self, _sample: &str, sound: Sound) -> Result<()> {
+            if !self.is_healthy() {
+                bail!("Output stream on {} has failed", self.name);
+            }
+            self.backend.0.lock().played.push((self.name.clone(), sound.collect()));
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fake::FakeBackend;
+    use super::*;
+    use crate::audio::latency::{Latency, Stage};
+    use rodio::buffer::SamplesBuffer;
+    use std::time::Instant;
+
+    fn current(output: &OutputSupervisor) -> Option<&str> {
+        output.output.as_ref().map(|output| output.name())
+    }
+
+    fn click() -> Sound {
+        Box::new(SamplesBuffer::new(1, 44100, vec![0.5, -0.5]))
+    }
+
+    #[test]
+    fn test_recreates_stream_after_error() {
+        let backend = FakeBackend::new(&["Speakers"], "Speakers");
+        let mut output = OutputSupervisor::new(Box::new(backend.clone()), Arc::default());
+        output.check(None).unwrap();
+
+        backend.fail_current();
+        output.check(None).unwrap();
+        output.play(None, "click", click(), None);
+
+        let state = backend.0.lock();
+        assert_eq!(state.opened, vec!["Speakers", "Speakers"]);
+        assert_eq!(state.played.len(), 1);
+    }
+
+    #[test]
+    fn test_play_recovers_without_waiting_for_check() {
+        let backend = FakeBackend::new(&["Speakers"], "Speakers");
+        let mut output = OutputSupervisor::new(Box::new(backend.clone()), Arc::default());
+        output.play(None, "click", click(), None);
+        backend.fail_current();
+        output.play(None, "click", click(), None);
+
+        let state = backend.0.lock();
+        assert_eq!(state.opened.len(), 2);
+        assert_eq!(state.played.len(), 2);
+    }
+
+    #[test]
+    fn test_follows_default_device() {
+        let backend = FakeBackend::new(&["Speakers", "Bluetooth"], "Speakers");
+        let mut output = OutputSupervisor::new(Box::new(backend.clone()), Arc::default());
+        output.check(None).unwrap();
+        output.check(None).unwrap();
+        assert_eq!(backend.0.lock().opened.len(), 1);
+
+        backend.0.lock().default = "Bluetooth".into();
+        output.check(None).unwrap();
+        assert_eq!(current(&output), Some("Bluetooth"));
+    }
+
+    #[test]
+    fn test_falls_back_and_switches_back() {
+        let backend = FakeBackend::new(&["Speakers", "Headphones"], "Speakers");
+        let mut output = OutputSupervisor::new(Box::new(backend.clone()), Arc::default());
+        output.check(Some("Headphones")).unwrap();
+        assert_eq!(current(&output), Some("Headphones"));
+
+        // Unplugged: the stream errors and the device is gone
+        backend.fail_current();
+        backend.0.lock().devices.retain(|d| d != "Headphones");
+        output.play(Some("Headphones"), "click", click(), None);
+        assert_eq!(current(&output), Some("Speakers"));
+
+        // Still gone, stay put
+        output.check(Some("Headphones")).unwrap();
+        assert_eq!(backend.0.lock().opened.len(), 2);
+
+        backend.0.lock().devices.push("Headphones".into());
+        output.check(Some("Headphones")).unwrap();
+        assert_eq!(current(&output), Some("Headphones"));
+        assert_eq!(backend.0.lock().played, vec![("Speakers".to_string(), vec![0.5, -0.5])]);
+    }
+
+    #[test]
+    fn test_setting_change_switches() {
+        let backend = FakeBackend::new(&["Speakers", "Headphones"], "Speakers");
+        let mut output = OutputSupervisor::new(Box::new(backend.clone()), Arc::default());
+        output.check(Some("Headphones")).unwrap();
+        output.check(None).unwrap();
+        assert_eq!(current(&output), Some("Speakers"));
+    }
+
+    #[test]
+    fn test_buffer_size_is_clamped_to_the_device() {
+        let range = SupportedBufferSize::Range { min: 64, max: 4096 };
+        assert_eq!(buffer_size(&range, None), BufferSize::Default);
+        assert_eq!(buffer_size(&range, Some(128)), BufferSize::Fixed(128));
+        assert_eq!(buffer_size(&range, Some(16)), BufferSize::Fixed(64));
+        assert_eq!(buffer_size(&SupportedBufferSize::Unknown, Some(16)), BufferSize::Fixed(16));
+    }
+
+    #[test]
+    fn test_mixer_limits_the_sum_and_hands_back_finished_voices() {
+        let (mut queue, incoming) = ring::ring(MAX_VOICES);
+        // Room for one finished voice at a time
+        let (done, mut finished) = ring::ring(1);
+        let config = cpal::StreamConfig { channels: 1, sample_rate: cpal::SampleRate(1000), buffer_size: BufferSize::Default };
+        let counters = Arc::new(Counters::default());
+        let mut mixer = Mixer::new(incoming, done, &config, counters.clone());
+        let latency = Arc::new(Latency::default());
+        let timer = StartTimer { arrived: Instant::now(), loaded: Instant::now(), latency: latency.clone() };
+        let voice = |samples: Vec<f32>, timer| Rendered::new(samples, timer, click(), counters.clone());
+        let voices = || counters.voices();
+        assert!(queue.push(voice(vec![0.5, 0.5, 0.5], Some(timer))).is_ok());
+        assert!(queue.push(voice(vec![0.25, 0.75], None)).is_ok());
+        assert!(queue.push(voice(vec![0.25], None)).is_ok());
+        assert_eq!(voices(), 3);
+
+        // Both samples sum past full scale
+        let mut data = [0.0f32; 2];
+        mixer.mix(&mut data);
+        assert!(data.iter().all(|sample| (0.9..0.99).contains(sample)), "{:?}", data);
+        assert_eq!(latency.count(Stage::Total), 1);
+        // Two voices ended but only one fits in `done`, the other waits in `playing`
+        assert_eq!(voices(), 1);
+        assert!(finished.pop().is_some());
+        assert!(finished.pop().is_none());
+        assert_eq!(mixer.playing.len(), 2);
+
+        let mut data = [0i16; 2];
+        mixer.mix(&mut data);
+        // Still held down after the peak
+        assert!(data[0] > 0 && data[0] < i16::MAX / 2, "{:?}", data);
+        assert_eq!(data[1], 0);
+        assert_eq!(voices(), 0);
+        assert!(finished.pop().is_some());
+        mixer.mix(&mut data);
+        assert!(finished.pop().is_some());
+        assert!(mixer.playing.is_empty());
+        assert_eq!(voices(), 0);
+    }
+}
@@ -0,0 +1,98 @@
+use rdev::Key;
+use std::sync::Arc;
+use std::time::Instant;
+
+use super::sound::{SoundEngine, SoundEvent};
+use crate::config::Config;
+use crate::input::session::SessionEvent;
+
+/// Typed at a relaxed pace: the number row, every letter row, space, enter and backspace
+const PHRASE: [Key; 16] = [
+    Key::KeyQ, Key::KeyU, Key::KeyI, Key::KeyZ, Key::Space,
+    Key::Num4, Key::Num2, Key::Space,
+    Key::KeyJ, Key::KeyU, Key::KeyM, Key::KeyP, Key::KeyS,
+    Key::Return, Key::Backspace, Key::Backspace,
+];
+const PHRASE_GAP_US: u64 = 150_000;
+const PHRASE_HOLD_US: u64 = 70_000;
+
+/// Typed as fast as a good typist's burst, to hear how the pack holds up
+const BURST: [Key; 10] = [
+    Key::KeyA, Key::KeyS, Key::KeyD, Key::KeyF, Key::KeyG,
+    Key::KeyH, Key::KeyJ, Key::KeyK, Key::KeyL, Key::SemiColon,
+];
+const BURST_GAP_US: u64 = 45_000;
+const BURST_HOLD_US: u64 = 30_000;
+const PAUSE_US: u64 = 400_000;
+
+/// The scripted demo, in time order
+pub fn demo() -> Vec<SessionEvent> {
+    let mut events = Vec::new();
+    let burst_start = type_keys(&PHRASE, 0, PHRASE_GAP_US, PHRASE_HOLD_US, &mut events) + PAUSE_US;
+    type_keys(&BURST, burst_start, BURST_GAP_US, BURST_HOLD_US, &mut events);
+    events.sort_by_key(|event| event.t_us);
+    events
+}
+
+/// Adds a press and release per key and returns when the next key would start
+fn type_keys(keys: &[Key], start_us: u64, gap_us: u64, hold_us: u64, events: &mut Vec<SessionEvent>) -> u64 {
+    let mut t_us = start_us;
+    for &key in keys {
+        events.push(SessionEvent { t_us, key, press: true });
+        events.push(SessionEvent { t_us: t_us + hold_us, key, press: false });
+        t_us += gap_us;
+    }
+    t_us
+}
+
+/// Plays the demo with the pack and volume of `config`, blocking until the last sound
+/// was sent. Plays even while sound is disabled, since it was asked for.
+pub fn play(sound_engine: &SoundEngine, config: &Arc<Config>) {
+    let start = Instant::now();
+    for event in demo() {
+        if let Some(wait) = event.at().checked_sub(start.elapsed()) {
+            std::thread::sleep(wait);
+        }
+        sound_engine.play(SoundEvent::new(Some(event.key), event.press, config));
+    }
+}
+
+/// Previews `pack` at the current settings on a thread of its own, for the tray
+pub fn spawn(sound_engine: Arc<SoundEngine>, pack: String) {
+    let config = Arc::new(Config { switch_type: pack, ..Config::clone(&sound_engine.settings().current()) });
+    std::thread::spawn(move || play(&sound_engine, &config));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demo_covers_every_row() {
+        let config = Arc::new(Config { switch_type: "test".into(), ..Default::default() });
+        let events = demo();
+        assert!(events.windows(2).all(|pair| pair[0].t_us <= pair[1].t_us));
+
+        let mut samples: Vec<String> = events.iter()
+            .map(|event| SoundEvent::new(Some(event.key), event.press, &config).sample())
+            .collect();
+        samples.sort();
+        samples.dedup();
+        assert_eq!(samples, vec![
+            "test/press/GENERIC_R0", "test/press/GENERIC_R1", "test/press/GENERIC_R2",
+            "test/press/GENERIC_R3", "test/press/GENERIC_R4", "test/release/GENERIC",
+        ]);
+        assert!(events.iter().any(|event| event.key == Key::Return));
+        assert!(events.iter().any(|event| event.key == Key::Backspace));
+    }
+
+    #[test]
+    fn test_burst_follows_phrase() {
+        let presses: Vec<u64> = demo().iter().filter(|event| event.press).map(|event| event.t_us).collect();
+        assert_eq!(presses.len(), PHRASE.len() + BURST.len());
+        // The pause before the burst, then the burst itself is faster than the phrase
+        assert_eq!(presses[PHRASE.len()] - presses[PHRASE.len() - 1], PHRASE_GAP_US + PAUSE_US);
+        assert!(presses[PHRASE.len()..].windows(2).all(|pair| pair[1] - pair[0] == BURST_GAP_US));
+        assert_eq!(demo().last().unwrap().t_us, presses[presses.len() - 1] + BURST_HOLD_US);
+    }
+}
@@ -0,0 +1,119 @@
+use anyhow::{bail, Result};
+use log::warn;
+use std::path::Path;
+use std::sync::Arc;
+
+use super::buffer::Recording;
+use super::sound::SoundEvent;
+use crate::config::Config;
+use crate::input::session::SessionEvent;
+use crate::input::PressedKeys;
+use crate::logging::Redact;
+
+/// Mixes a typing session at `sample_rate`, filtering held keys and picking and loading
+/// samples the same way live playback does for `config`
+pub fn render(events: &[SessionEvent], config: &Config, switchtypes_dir: &Path, sample_rate: u32) -> Recording {
+    let mut recording = Recording::new(sample_rate);
+    let settings = Arc::new(config.clone());
+    let mut pressed = PressedKeys::default();
+    for event in events.iter().filter(|event| pressed.update(event.key, event.press)) {
+        let sound_event = SoundEvent::new(Some(event.key), event.press, &settings);
+        match sound_event.load(switchtypes_dir) {
+            Ok((sample, sound)) => recording.add(event.at(), &sample, sound),
+            // The sample gives away the key row
+            Err(e) => warn!("Skipping {} at {:?}: {:#}", Redact(sound_event.sample()), event.at(), e),
+        }
+    }
+    recording
+}
+
+/// Writes the mix as WAV or FLAC, depending on the extension of `path`
+pub fn save(recording: &Recording, path: &Path) -> Result<()> {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("wav") => recording.write_wav(path),
+        Some("flac") => recording.write_flac(path),
+        _ => bail!("Can't tell the format of {:?}, use a .wav or .flac file", path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::buffer::CHANNELS;
+    use crate::audio::sound::fixtures::test_pack;
+    use rdev::Key;
+    use std::time::Duration;
+
+    fn session() -> Vec<SessionEvent> {
+        vec![
+            SessionEvent { t_us: 0, key: Key::KeyQ, press: true },
+            SessionEvent { t_us: 50_000, key: Key::Space, press: true },
+            SessionEvent { t_us: 100_000, key: Key::KeyQ, press: false },
+        ]
+    }
+
+    fn config() -> Config {
+        Config { switch_type: "test".into(), ..Default::default() }
+    }
+
+    #[test]
+    fn test_render_at_sample_rate() {
+        let recording = render(&session(), &config(), &test_pack("render"), 22050);
+        assert_eq!(recording.sample_rate, 22050);
+        let triggers: Vec<(Duration, &str)> = recording.triggers.iter().map(|t| (t.at, t.sample.as_str())).collect();
+        assert_eq!(triggers, vec![
+            (Duration::ZERO, "test/press/GENERIC_R1.wav"),
+            (Duration::from_millis(50), "test/press/GENERIC_R4.wav"),
+            (Duration::from_millis(100), "test/release/GENERIC.wav"),
+        ]);
+
+        // The last sample starts 2205 frames in and lasts 10 ms
+        let frames = recording.samples.len() / CHANNELS as usize;
+        assert!((2205 + 219..=2205 + 221).contains(&frames), "{} frames", frames);
+    }
+
+    #[test]
+    fn test_held_keys_play_once() {
+        let events = vec![
+            SessionEvent { t_us: 0, key: Key::KeyQ, press: true },
+            SessionEvent { t_us: 30_000, key: Key::KeyQ, press: true },
+            SessionEvent { t_us: 60_000, key: Key::KeyQ, press: false },
+            // Went down before the recording started
+            SessionEvent { t_us: 90_000, key: Key::Space, press: false },
+        ];
+        let recording = render(&events, &config(), &test_pack("render-held"), 44100);
+        let triggers: Vec<Duration> = recording.triggers.iter().map(|t| t.at).collect();
+        assert_eq!(triggers, vec![Duration::ZERO, Duration::from_millis(60)]);
+    }
+
+    #[test]
+    fn test_missing_pack_renders_silence() {
+        let config = Config { switch_type: "not-installed".into(), ..config() };
+        let recording = render(&session(), &config, &test_pack("render-missing"), 44100);
+        assert!(recording.triggers.is_empty());
+        assert!(recording.samples.is_empty());
+    }
+
+    #[test]
+    fn test_save_picks_format() {
+        let recording = render(&session(), &config(), &test_pack("render-save"), 48000);
+        let dir = std::env::temp_dir();
+        let wav = dir.join(format!("clickclack-render-{}.wav", std::process::id()));
+        let flac = dir.join(format!("clickclack-render-{}.FLAC", std::process::id()));
+
+        save(&recording, &wav).unwrap();
+        let reader = hound::WavReader::open(&wav).unwrap();
+        assert_eq!(reader.spec().sample_rate, 48000);
+        assert_eq!(reader.len() as usize, recording.samples.len());
+
+        save(&recording, &flac).unwrap();
+        let mut reader = claxon::FlacReader::open(&flac).unwrap();
+        let samples: Vec<i16> = reader.samples().map(|sample| sample.unwrap() as i16).collect();
+        assert_eq!(samples, recording.pcm16());
+
+        assert!(save(&recording, &dir.join("typing.mp3")).is_err());
+        std::fs::remove_file(wav).unwrap();
+        std::fs::remove_file(flac).unwrap();
+    }
+}
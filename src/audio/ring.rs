@@ -0,0 +1,118 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// A fixed-size queue between one producer and one consumer thread. Neither side locks or
+/// allocates, so either side can be the audio callback.
+pub fn ring<T: Send>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let shared = Arc::new(Shared {
+        slots: (0..capacity.max(1)).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect(),
+        read: AtomicUsize::new(0),
+        write: AtomicUsize::new(0),
+    });
+    (Producer(shared.clone()), Consumer(shared))
+}
+
+struct Shared<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Positions only ever grow; the slot is the position modulo the capacity
+    read: AtomicUsize,
+    write: AtomicUsize,
+}
+
+// Each slot is only touched by the producer before `write` passes it and by the consumer
+// before `read` passes it
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Drop for Shared<T> {
+    fn drop(&mut self) {
+        let (read, write) = (*self.read.get_mut(), *self.write.get_mut());
+        for position in read..write {
+            unsafe { self.slots[position % self.slots.len()].get_mut().assume_init_drop() };
+        }
+    }
+}
+
+pub struct Producer<T>(Arc<Shared<T>>);
+
+impl<T> Producer<T> {
+    /// Queues `value`, or hands it back when the queue is full
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let shared = &*self.0;
+        let write = shared.write.load(Ordering::Relaxed);
+        if write - shared.read.load(Ordering::Acquire) == shared.slots.len() {
+            return Err(value);
+        }
+        unsafe { (*shared.slots[write % shared.slots.len()].get()).write(value) };
+        shared.write.store(write + 1, Ordering::Release);
+        Ok(())
+    }
+}
+
+pub struct Consumer<T>(Arc<Shared<T>>);
+
+impl<T> Consumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        let shared = &*self.0;
+        let read = shared.read.load(Ordering::Relaxed);
+        if read == shared.write.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*shared.slots[read % shared.slots.len()].get()).assume_init_read() };
+        shared.read.store(read + 1, Ordering::Release);
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fifo_until_full() {
+        let (mut producer, mut consumer) = ring(2);
+        assert_eq!(consumer.pop(), None);
+        assert_eq!(producer.push(1), Ok(()));
+        assert_eq!(producer.push(2), Ok(()));
+        assert_eq!(producer.push(3), Err(3));
+        assert_eq!(consumer.pop(), Some(1));
+        assert_eq!(producer.push(3), Ok(()));
+        assert_eq!(consumer.pop(), Some(2));
+        assert_eq!(consumer.pop(), Some(3));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn test_across_threads() {
+        let (mut producer, mut consumer) = ring(8);
+        let sender = std::thread::spawn(move || {
+            for value in 0..10_000u32 {
+                let mut value = value;
+                while let Err(rejected) = producer.push(value) {
+                    value = rejected;
+                    std::thread::yield_now();
+                }
+            }
+        });
+        let mut received = Vec::new();
+        while received.len() < 10_000 {
+            match consumer.pop() {
+                Some(value) => received.push(value),
+                None => std::thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+        assert!(received.iter().copied().eq(0..10_000));
+    }
+
+    #[test]
+    fn test_drops_what_is_left() {
+        let value = Arc::new(());
+        let (mut producer, consumer) = ring(4);
+        producer.push(value.clone()).unwrap();
+        producer.push(value.clone()).unwrap();
+        drop((producer, consumer));
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+}
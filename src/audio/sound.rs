@@ -1,13 +1,41 @@
-use anyhow::Result;
-use log::{error, info, debug};
-use rodio::{Decoder, OutputStream, Sink};
+use anyhow::{Context, Result};
+use log::{error, debug};
+use rodio::buffer::SamplesBuffer;
+use rodio::{Decoder, Source};
 use rdev::Key;
+use std::collections::hash_map::RandomState;
 use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use dirs;
 
+use super::cache::SampleCache;
+use super::gain::{self, Limiter};
+use super::latency::{Counters, Latency, Stage, StartTimer};
+use super::synth::{self, SynthParams};
+use super::trim::TrimStart;
+use super::output::{CpalBackend, OutputBackend, OutputSupervisor, Sound};
+use crate::config::{Config, Keymap};
+use crate::events::Event;
+use crate::logging::Redact;
+use crate::packs;
+use crate::settings::Settings;
+
+/// How often the audio thread checks the output stream's health and device
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+/// How often the settings watcher checks whether its engine is gone
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest random delay added to masked sounds
+const MAX_JITTER: Duration = Duration::from_millis(40);
+/// How far a hit's loudness and start can stray at `humanize = 1.0`
+const MAX_HUMAN_GAIN_DB: f32 = 3.0;
+const MAX_HUMAN_DELAY: Duration = Duration::from_millis(15);
+
 fn get_assets_dir() -> PathBuf {
     dirs::config_dir()
         .unwrap_or_else(|| PathBuf::from("~/.config"))
@@ -15,181 +43,616 @@ fn get_assets_dir() -> PathBuf {
 }
 
 pub struct SoundEngine {
-    _stream: OutputStream,
-    stream_handle: rodio::OutputStreamHandle,
-    sender: mpsc::Sender<SoundEvent>,
+    sender: mpsc::Sender<Request>,
+    latency: Arc<Latency>,
+    counters: Arc<Counters>,
+    settings: Settings,
+    /// Ends the settings watcher, which may not hear from the settings for a long time
+    stopped: Arc<AtomicBool>,
+}
+
+/// What the audio thread is asked to do
+enum Request {
+    Play(SoundEvent),
+    /// The pack or output device changed, so the output is checked and the pack's
+    /// samples decoded now rather than on the next key
+    SettingsChanged,
+    Stop,
 }
 
 pub struct SoundEvent {
     key: Option<Key>,
     is_press: bool,
-    volume: f32,
-    switch_type: String,
+    gain: f32,
+    /// The settings when the key was hit. Sharing the snapshot saves copying the pack
+    /// name on every key.
+    settings: Arc<Config>,
+    delay: Duration,
+    /// When the key event came in, and when the event was queued for the audio thread
+    arrived: Instant,
+    sent: Instant,
+}
+
+impl SoundEvent {
+    pub fn new(key: Option<Key>, is_press: bool, settings: &Arc<Config>) -> Self {
+        let (variation_db, delay) = humanization(settings.humanize);
+        Self {
+            key,
+            is_press,
+            gain: gain::gain(settings.volume, settings.master_gain_db + variation_db),
+            settings: settings.clone(),
+            delay,
+            arrived: Instant::now(),
+            sent: Instant::now(),
+        }
+    }
+
+    /// Times the event from when its key event reached the keyboard callback
+    pub fn arrived_at(self, arrived: Instant) -> Self {
+        Self { arrived, ..self }
+    }
+
+    /// The same sample for every key, started a random moment late, so neither the
+    /// sound nor its timing gives away which key it was
+    pub fn masked(is_press: bool, settings: &Arc<Config>) -> Self {
+        Self { delay: jitter(), ..Self::new(None, is_press, settings) }
+    }
+
+    /// Path of the generic sample to play below the switchtypes directory, without its
+    /// extension since a pack can have it in any of `packs::SAMPLE_EXTENSIONS`
+    pub fn sample(&self) -> String {
+        let sound_file = match (self.key, self.is_press) {
+            (Some(_), true) => {
+                format!("press/GENERIC_R{}", self.row())
+            }
+            (Some(_), false) => {
+                "release/GENERIC".to_string()
+            }
+            (None, _) => "press/GENERIC_R2".to_string(),
+        };
+        format!("{}/{}", self.settings.switch_type, sound_file)
+    }
+
+    /// Space and enter's own sample, e.g. `mxblue/press/SPACE`, which is played instead of
+    /// `sample` when the pack has it
+    fn key_sample(&self) -> Option<String> {
+        let name = match (self.key?, self.settings.keymap) {
+            (_, Keymap::Flat) => return None,
+            (Key::Space, _) => "SPACE",
+            (Key::Return | Key::KpReturn, _) => "ENTER",
+            _ => return None,
+        };
+        let direction = if self.is_press { "press" } else { "release" };
+        Some(format!("{}/{}/{}", self.settings.switch_type, direction, name))
+    }
+
+    /// The key's row as the keymap has it, the home row for masked events
+    fn row(&self) -> usize {
+        match (self.key, self.settings.keymap) {
+            (Some(key), Keymap::Rows) => SoundEngine::key_row(&key),
+            _ => 2,
+        }
+    }
+
+    /// Decodes the sample, or synthesizes the hit for a synth pack, with the event's gain
+    /// applied. Live playback and offline rendering both go through here.
+    pub fn load(&self, switchtypes_dir: &Path) -> Result<(String, Sound)> {
+        if let Some(params) = synth::load(&switchtypes_dir.join(&self.settings.switch_type))? {
+            return Ok(self.synthesize(&params));
+        }
+
+        let sample = self.sample();
+        let path = self.key_sample()
+            .and_then(|key_sample| packs::find_sample(switchtypes_dir, &key_sample))
+            .or_else(|| packs::find_sample(switchtypes_dir, &sample))
+            .with_context(|| format!("No {} sample for {}", packs::SAMPLE_EXTENSIONS.join("/"), Redact(&sample)))?;
+        let file = File::open(&path).context("Failed to open sound file")?;
+        let sample = path.strip_prefix(switchtypes_dir).unwrap_or(&path).display().to_string();
+        let source = Decoder::new(BufReader::new(file)).context("Failed to decode sound file")?;
+        Ok((sample, self.finish(TrimStart::new(source.convert_samples()))))
+    }
+
+    /// Synthesizes the hit for a synth pack with `params`
+    fn synthesize(&self, params: &SynthParams) -> (String, Sound) {
+        let row = self.row();
+        let samples = synth::hit(params, row, self.is_press, synth::SAMPLE_RATE, random());
+        let source = SamplesBuffer::new(1, synth::SAMPLE_RATE, samples);
+        let direction = if self.is_press { "press" } else { "release" };
+        (format!("{}/synth/{}/R{}", self.settings.switch_type, direction, row), self.finish(source))
+    }
+
+    /// Like `load`, taking the sample or synth parameters from `cache` when it has them
+    fn load_cached(&self, switchtypes_dir: &Path, cache: &SampleCache) -> Result<(String, Sound)> {
+        if let Some(params) = cache.synth(&self.settings.switch_type) {
+            return Ok(self.synthesize(params));
+        }
+        match self.key_sample().and_then(|sample| cache.get(&sample)).or_else(|| cache.get(&self.sample())) {
+            Some((sample, source)) => Ok((sample, self.finish(source))),
+            None => self.load(switchtypes_dir),
+        }
+    }
+
+    /// Applies the event's gain and delay
+    fn finish(&self, source: impl Source<Item = f32> + Send + 'static) -> Sound {
+        Box::new(Limiter::new(source, self.gain).delay(self.delay))
+    }
 }
 
-// Implement Send and Sync explicitly since we control the thread safety
-unsafe impl Send for SoundEngine {}
-unsafe impl Sync for SoundEngine {}
+fn random() -> u64 {
+    // Every RandomState is keyed differently, which is random enough for timing and noise
+    RandomState::new().build_hasher().finish()
+}
+
+fn jitter() -> Duration {
+    MAX_JITTER.mul_f64((random() % 1000) as f64 / 1000.0)
+}
+
+/// A random gain offset in dB and start delay for one hit, both scaled by `humanize`
+fn humanization(humanize: f32) -> (f32, Duration) {
+    if humanize.is_nan() || humanize <= 0.0 {
+        return (0.0, Duration::ZERO);
+    }
+    let amount = humanize.min(1.0);
+    let spread = (random() % 2001) as f32 / 1000.0 - 1.0;
+    let delay = MAX_HUMAN_DELAY.mul_f32(amount * (random() % 1000) as f32 / 1000.0);
+    (amount * MAX_HUMAN_GAIN_DB * spread, delay)
+}
 
 impl SoundEngine {
-    pub fn new() -> Result<Self> {
-        let (stream, stream_handle) = OutputStream::try_default()?;
+    /// Plays on the sound card, following `settings` for the output device
+    pub fn new(settings: Settings) -> Result<Self> {
+        let buffer_frames = settings.current().buffer_frames;
+        Self::with_backend(settings, Box::new(CpalBackend { buffer_frames }), packs::switchtypes_dir())
+    }
+
+    /// Plays through `backend`, loading samples from the packs in `switchtypes_dir`
+    pub fn with_backend(settings: Settings, backend: Box<dyn OutputBackend>, switchtypes_dir: PathBuf) -> Result<Self> {
         let (sender, receiver) = mpsc::channel();
+        let (ready_sender, ready_receiver) = mpsc::channel();
+        let latency = Arc::new(Latency::default());
+        let thread_latency = latency.clone();
+        let counters = Arc::new(Counters::default());
+        let thread_counters = counters.clone();
+        let thread_settings = settings.clone();
 
-        // Spawn a thread to handle sound events
-        let stream_handle_clone = stream_handle.clone();
+        let mut changes = settings.events().subscribe();
+        let wake = sender.clone();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let watching = stopped.clone();
         std::thread::spawn(move || {
-            while let Ok(event) = receiver.recv() {
-                Self::handle_sound_event(event, &stream_handle_clone);
+            // Dropping `changes` on the way out unsubscribes
+            while !watching.load(Ordering::Relaxed) {
+                let event = match changes.recv_timeout(WATCH_INTERVAL) {
+                    Ok(event) => event,
+                    Err(mpsc::RecvTimeoutError::Timeout) => continue,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+                let changed = matches!(event, Event::PackChanged(_) | Event::OutputDeviceChanged(_));
+                if (changed || changes.missed()) && wake.send(Request::SettingsChanged).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // cpal streams can't leave the thread that opened them, so the audio thread owns it
+        std::thread::spawn(move || {
+            let mut output = OutputSupervisor::new(backend, thread_counters);
+            let wanted = thread_settings.current().output_device.clone();
+            match output.check(wanted.as_deref()) {
+                Ok(()) => {
+                    let _ = ready_sender.send(Ok(()));
+                }
+                Err(e) => {
+                    let _ = ready_sender.send(Err(e));
+                    return;
+                }
+            }
+
+            let mut cache = SampleCache::default();
+            loop {
+                // A new pack, or a new output that mixes at another rate
+                let switch_type = thread_settings.current().switch_type.clone();
+                if let Some(format) = output.format().filter(|format| !cache.is_for(&switch_type, *format)) {
+                    cache = SampleCache::build(&switchtypes_dir, &switch_type, format);
+                }
+
+                match receiver.recv_timeout(DEVICE_CHECK_INTERVAL) {
+                    Ok(Request::Play(event)) => {
+                        Self::handle_sound_event(event, &switchtypes_dir, &cache, &mut output, &thread_latency)
+                    }
+                    Ok(Request::SettingsChanged) | Err(mpsc::RecvTimeoutError::Timeout) => {
+                        let wanted = thread_settings.current().output_device.clone();
+                        if let Err(e) = output.check(wanted.as_deref()) {
+                            debug!("Output stream still unavailable: {:#}", e);
+                        }
+                    }
+                    Ok(Request::Stop) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
             }
         });
 
-        Ok(Self {
-            _stream: stream,
-            stream_handle,
-            sender,
-        })
+        // Built first, so dropping it stops the watcher when the output fails to open
+        let engine = Self { sender, latency, counters, settings, stopped };
+        ready_receiver.recv()??;
+        Ok(engine)
+    }
+
+    /// The settings sounds are played with
+    pub fn settings(&self) -> &Settings {
+        &self.settings
     }
 
     pub fn play_sound(&self, key: Option<Key>, is_press: bool) {
-        let app_state = crate::APP_STATE.lock();
-        if !app_state.enabled {
+        let settings = self.settings.current();
+        if !settings.enabled {
             return;
         }
 
-        // Create event with current state
-        let event = SoundEvent {
-            key,
-            is_press,
-            volume: app_state.volume,
-            switch_type: app_state.switch_type.clone(),
-        };
+        // Send event with current state to audio thread
+        self.play(SoundEvent::new(key, is_press, &settings));
+    }
 
-        // Send event to audio thread
-        let _ = self.sender.send(event);
+    pub fn play(&self, event: SoundEvent) {
+        self.counters.count_event();
+        let _ = self.sender.send(Request::Play(SoundEvent { sent: Instant::now(), ..event }));
     }
 
-    fn get_sound_number_for_key(key: &Key) -> String {
-        // Map specific keys to row numbers for GENERIC_R0-R4 sounds
+    /// How long sounds took through each stage since start
+    pub fn latency(&self) -> &Latency {
+        &self.latency
+    }
+
+    /// Sounds asked for and playing, and underruns of the output, since start
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    fn key_row(key: &Key) -> usize {
+        // Map specific keys to row numbers, for the GENERIC_R0-R4 sounds
         match key {
             // Row 0 - Number keys
             Key::Num1 | Key::Num2 | Key::Num3 | Key::Num4 | Key::Num5 |
             Key::Num6 | Key::Num7 | Key::Num8 | Key::Num9 | Key::Num0 |
-            Key::Minus | Key::Equal => "GENERIC_R0",
+            Key::Minus | Key::Equal => 0,
             
             // Row 1 - Top letter row
             Key::KeyQ | Key::KeyW | Key::KeyE | Key::KeyR | Key::KeyT |
             Key::KeyY | Key::KeyU | Key::KeyI | Key::KeyO | Key::KeyP |
-            Key::LeftBracket | Key::RightBracket => "GENERIC_R1",
+            Key::LeftBracket | Key::RightBracket => 1,
             
             // Row 2 - Home row
             Key::KeyA | Key::KeyS | Key::KeyD | Key::KeyF | Key::KeyG |
             Key::KeyH | Key::KeyJ | Key::KeyK | Key::KeyL |
-            Key::SemiColon | Key::Quote | Key::BackSlash => "GENERIC_R2",
+            Key::SemiColon | Key::Quote | Key::BackSlash => 2,
             
             // Row 3 - Bottom letter row
             Key::KeyZ | Key::KeyX | Key::KeyC | Key::KeyV | Key::KeyB |
-            Key::KeyN | Key::KeyM | Key::Comma | Key::Dot | Key::Slash => "GENERIC_R3",
+            Key::KeyN | Key::KeyM | Key::Comma | Key::Dot | Key::Slash => 3,
             
             // Row 4 - Space row and modifiers
             Key::Space | Key::Alt | Key::MetaLeft | Key::MetaRight |
             Key::ControlLeft | Key::ControlRight | Key::ShiftLeft |
-            Key::ShiftRight => "GENERIC_R4",
+            Key::ShiftRight => 4,
             
             // Default to R2 (home row) for any other keys
-            _ => "GENERIC_R2"
-        }.to_string()
+            _ => 2,
+        }
     }
 
-    fn handle_sound_event(event: SoundEvent, stream_handle: &rodio::OutputStreamHandle) {
-        // Determine which sound file to play based on the key and event type
-        let sound_file = match (event.key, event.is_press) {
-            (Some(key), true) => {
-                format!("press/{}.mp3", Self::get_sound_number_for_key(&key))
-            }
-            (Some(_), false) => {
-                "release/GENERIC.mp3".to_string()
-            }
-            (None, _) => "press/GENERIC_R2.mp3".to_string(),
-        };
+    fn handle_sound_event(
+        event: SoundEvent,
+        switchtypes_dir: &Path,
+        cache: &SampleCache,
+        output: &mut OutputSupervisor,
+        latency: &Arc<Latency>,
+    ) {
+        let received = Instant::now();
+        latency.record(Stage::Input, event.sent.saturating_duration_since(event.arrived));
+        latency.record(Stage::Channel, received.saturating_duration_since(event.sent));
 
-        info!("Key sound: {}", sound_file);
-
-        let path = dirs::config_dir()
-            .unwrap_or_else(|| PathBuf::from("~/.config"))
-            .join("clickclack")
-            .join("switchtypes")
-            .join(&event.switch_type)
-            .join(&sound_file);
-
-        // Create a new sink for this sound
-        if let Ok(sink) = Sink::try_new(stream_handle) {
-            match File::open(&path) {
-                Ok(file) => {
-                    let reader = BufReader::new(file);
-                    match Decoder::new(reader) {
-                        Ok(source) => {
-                            sink.set_volume(event.volume);
-                            sink.append(source);
-                            sink.detach(); // Let the sink clean itself up when done
-                        }
-                        Err(e) => error!("Failed to decode audio: {:?}", e),
-                    }
-                }
-                Err(e) => error!("Failed to open sound file {:?}: {:?}", path, e),
+        match event.load_cached(switchtypes_dir, cache) {
+            Ok((sample, sound)) => {
+                let loaded = Instant::now();
+                latency.record(Stage::Decode, loaded - received);
+                debug!("Key sound: {}", Redact(&sample));
+                let timer = StartTimer { arrived: event.arrived, loaded, latency: latency.clone() };
+                output.play(event.settings.output_device.as_deref(), &sample, sound, Some(timer));
             }
+            // The sample path gives away the key row
+            Err(e) => error!("Can't play {}: {:#}", Redact(event.sample()), e),
         }
     }
 
     #[cfg(test)]
-    pub fn play_test_sound(&self) -> bool {
-        let app_state = crate::APP_STATE.lock();
-        let event = SoundEvent {
-            key: None,
-            is_press: true,
-            volume: app_state.volume,
-            switch_type: app_state.switch_type.clone(),
+    pub fn play_test_sound(&self, key: Option<Key>, is_press: bool, config: &Arc<Config>) -> bool {
+        self.sender.send(Request::Play(SoundEvent::new(key, is_press, config))).is_ok()
+    }
+}
+
+impl Drop for SoundEngine {
+    fn drop(&mut self) {
+        // The thread watching the settings keeps a sender, so the audio thread has to be told
+        self.stopped.store(true, Ordering::Relaxed);
+        let _ = self.sender.send(Request::Stop);
+    }
+}
+
+#[cfg(test)]
+pub mod fixtures {
+    use std::path::PathBuf;
+
+    /// A pack called `test` whose samples are all a short constant tone
+    pub fn test_pack(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clickclack-sound-{}-{}", std::process::id(), name));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 44100,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
         };
-        self.sender.send(event).is_ok()
+        let files = ["GENERIC_R0", "GENERIC_R1", "GENERIC_R2", "GENERIC_R3", "GENERIC_R4"]
+            .iter()
+            .map(|row| format!("press/{}.wav", row))
+            .chain(["release/GENERIC.wav".to_string()]);
+        for file in files {
+            let path = dir.join("test").join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            let mut writer = hound::WavWriter::create(path, spec).unwrap();
+            for _ in 0..441 {
+                writer.write_sample(i16::MAX / 2).unwrap();
+            }
+            writer.finalize().unwrap();
+        }
+        dir
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::fixtures::test_pack;
+    use crate::audio::buffer::{BufferBackend, NullBackend, Trigger};
+
+    fn config(volume: f32) -> Arc<Config> {
+        Arc::new(Config { switch_type: "test".into(), volume, ..Default::default() })
+    }
+
+    fn pack(switch_type: &str) -> Arc<Config> {
+        Arc::new(Config { switch_type: switch_type.into(), ..Default::default() })
+    }
+
+    fn wait_for(backend: &BufferBackend, count: usize) -> Vec<Trigger> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while backend.triggers().len() < count && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        backend.triggers()
+    }
+
+    fn samples(triggers: &[Trigger]) -> Vec<&str> {
+        triggers.iter().map(|trigger| trigger.sample.as_str()).collect()
+    }
 
     #[test]
     fn test_sound_engine_creation() {
-        assert!(SoundEngine::new().is_ok());
+        assert!(SoundEngine::with_backend(Settings::default(), Box::new(NullBackend), PathBuf::new()).is_ok());
     }
 
     #[test]
     fn test_sound_file_selection() {
-        let engine = SoundEngine::new().unwrap();
-        assert!(engine.play_test_sound());
-        // Give some time for the sound to be processed
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        let backend = BufferBackend::new();
+        let engine = SoundEngine::with_backend(Settings::default(), Box::new(backend.clone()), test_pack("selection")).unwrap();
+        let config = config(1.0);
+        assert!(engine.play_test_sound(Some(Key::KeyQ), true, &config));
+        assert!(engine.play_test_sound(Some(Key::KeyQ), false, &config));
+        assert!(engine.play_test_sound(Some(Key::Space), true, &config));
+        assert!(engine.play_test_sound(None, true, &config));
+
+        let triggers = wait_for(&backend, 4);
+        assert_eq!(samples(&triggers), vec![
+            "test/press/GENERIC_R1.wav",
+            "test/release/GENERIC.wav",
+            "test/press/GENERIC_R4.wav",
+            "test/press/GENERIC_R2.wav",
+        ]);
+        assert!(triggers.windows(2).all(|pair| pair[0].at <= pair[1].at));
+    }
+
+    #[test]
+    fn test_space_and_enter_play_their_own_samples() {
+        let switchtypes_dir = test_pack("space");
+        for sample in ["press/SPACE", "release/SPACE"] {
+            std::fs::copy(switchtypes_dir.join("test/press/GENERIC_R4.wav"), switchtypes_dir.join(format!("test/{}.wav", sample))).unwrap();
+        }
+        let backend = BufferBackend::new();
+        let engine = SoundEngine::with_backend(Settings::default(), Box::new(backend.clone()), switchtypes_dir.clone()).unwrap();
+        let config = config(1.0);
+        assert!(engine.play_test_sound(Some(Key::Space), true, &config));
+        assert!(engine.play_test_sound(Some(Key::Space), false, &config));
+        // The test pack has no ENTER samples
+        assert!(engine.play_test_sound(Some(Key::Return), true, &config));
+        assert!(engine.play_test_sound(Some(Key::Return), false, &config));
+        assert_eq!(samples(&wait_for(&backend, 4)), vec![
+            "test/press/SPACE.wav",
+            "test/release/SPACE.wav",
+            "test/press/GENERIC_R2.wav",
+            "test/release/GENERIC.wav",
+        ]);
+
+        // Without the cache, and not at all with a flat keymap
+        let (sample, _) = SoundEvent::new(Some(Key::Space), true, &config).load(&switchtypes_dir).unwrap();
+        assert_eq!(sample, "test/press/SPACE.wav");
+        let flat = Arc::new(Config { keymap: Keymap::Flat, ..(*config).clone() });
+        let (sample, _) = SoundEvent::new(Some(Key::Space), true, &flat).load(&switchtypes_dir).unwrap();
+        assert_eq!(sample, "test/press/GENERIC_R2.wav");
+    }
+
+    #[test]
+    fn test_engines_count_their_own_events() {
+        let engine = SoundEngine::with_backend(Settings::default(), Box::new(NullBackend), PathBuf::new()).unwrap();
+        let other = SoundEngine::with_backend(Settings::default(), Box::new(NullBackend), PathBuf::new()).unwrap();
+        engine.play_sound(Some(Key::KeyA), true);
+        engine.play_sound(Some(Key::KeyA), false);
+        assert_eq!(engine.counters().events(), 2);
+        assert_eq!(other.counters().events(), 0);
+    }
+
+    #[test]
+    fn test_settings_watcher_ends_with_the_engine() {
+        let settings = Settings::default();
+        let engine = SoundEngine::with_backend(settings.clone(), Box::new(NullBackend), PathBuf::new()).unwrap();
+        assert_eq!(settings.events().subscribers(), 1);
+        drop(engine);
+
+        // Settings stay quiet, apart from what it takes to notice a dropped subscription
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while settings.events().subscribers() > 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(50));
+            settings.events().publish(Event::Key { key: Key::KeyA, is_press: true });
+        }
+        assert_eq!(settings.events().subscribers(), 0);
     }
 
     #[test]
     fn test_concurrent_sounds() {
-        let engine = SoundEngine::new().unwrap();
-        
+        let backend = BufferBackend::new();
+        let engine = SoundEngine::with_backend(Settings::default(), Box::new(backend.clone()), test_pack("concurrent")).unwrap();
+
         // Play multiple test sounds
         for _ in 0..3 {
-            assert!(engine.play_test_sound());
+            assert!(engine.play_test_sound(None, true, &config(1.0)));
         }
-        // Give some time for the sounds to be processed
-        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(wait_for(&backend, 3).len(), 3);
     }
 
     #[test]
     fn test_volume_control() {
-        let engine = SoundEngine::new().unwrap();
-        {
-            let mut app_state = crate::APP_STATE.lock();
-            app_state.volume = 0.5;
+        let backend = BufferBackend::new();
+        let engine = SoundEngine::with_backend(Settings::default(), Box::new(backend.clone()), test_pack("volume")).unwrap();
+        assert!(engine.play_test_sound(None, true, &config(0.5)));
+        wait_for(&backend, 1);
+
+        // Half scale samples at -20 dB
+        let peak = backend.samples().iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - 0.5 * gain::gain(0.5, 0.0)).abs() < 0.001, "peak {}", peak);
+    }
+
+    #[test]
+    fn test_masked_sounds_are_uniform_and_jittered() {
+        let config = config(1.0);
+        let events: Vec<SoundEvent> = (0..20).map(|i| SoundEvent::masked(i % 2 == 0, &config)).collect();
+        assert!(events.iter().all(|event| event.sample() == "test/press/GENERIC_R2"));
+        assert!(events.iter().all(|event| event.delay < MAX_JITTER));
+        assert!(events.iter().any(|event| event.delay != events[0].delay));
+
+        // The delay is silence in front of the sample
+        let (_, sound) = SoundEvent { delay: Duration::from_millis(10), ..SoundEvent::masked(true, &config) }
+            .load(&test_pack("masked"))
+            .unwrap();
+        let samples: Vec<f32> = sound.collect();
+        assert!(samples[..441].iter().all(|sample| *sample == 0.0));
+        assert!(samples[441..].iter().all(|sample| *sample != 0.0));
+    }
+
+    #[test]
+    fn test_flat_keymap_plays_the_home_row() {
+        let config = Arc::new(Config { keymap: Keymap::Flat, ..(*config(1.0)).clone() });
+        for key in [Key::Num1, Key::KeyQ, Key::Space] {
+            assert_eq!(SoundEvent::new(Some(key), true, &config).sample(), "test/press/GENERIC_R2");
         }
-        assert!(engine.play_test_sound());
+        assert_eq!(SoundEvent::new(Some(Key::KeyQ), false, &config).sample(), "test/release/GENERIC");
     }
-} 
\ No newline at end of file
+
+    #[test]
+    fn test_humanize_varies_gain_and_timing() {
+        let steady = SoundEvent::new(Some(Key::KeyA), true, &config(1.0));
+        assert_eq!((steady.gain, steady.delay), (1.0, Duration::ZERO));
+
+        let config = Arc::new(Config { humanize: 1.0, ..(*config(1.0)).clone() });
+        let events: Vec<SoundEvent> = (0..20).map(|_| SoundEvent::new(Some(Key::KeyA), true, &config)).collect();
+        let loudest = gain::db_to_amplitude(MAX_HUMAN_GAIN_DB);
+        assert!(events.iter().all(|event| (1.0 / loudest..=loudest).contains(&event.gain)));
+        assert!(events.iter().all(|event| event.delay < MAX_HUMAN_DELAY));
+        assert!(events.iter().any(|event| event.gain != events[0].gain));
+        assert!(events.iter().any(|event| event.delay != events[0].delay));
+    }
+
+    #[test]
+    fn test_synth_pack_needs_no_samples() {
+        let switchtypes_dir = test_pack("synth");
+        synth::save(&synth::preset("mxblue").unwrap(), &switchtypes_dir.join("synthblue")).unwrap();
+        let config = pack("synthblue");
+
+        let (sample, sound) = SoundEvent::new(Some(Key::Space), true, &config).load(&switchtypes_dir).unwrap();
+        assert_eq!(sample, "synthblue/synth/press/R4");
+        assert_eq!(sound.sample_rate(), synth::SAMPLE_RATE);
+        assert!(sound.into_iter().any(|sample| sample != 0.0));
+
+        let (sample, _) = SoundEvent::masked(false, &config).load(&switchtypes_dir).unwrap();
+        assert_eq!(sample, "synthblue/synth/release/R2");
+    }
+
+    #[test]
+    fn test_cached_synth_pack_is_not_read_again() {
+        let switchtypes_dir = test_pack("synth-cached");
+        let pack_dir = switchtypes_dir.join("synthblue");
+        synth::save(&synth::preset("mxblue").unwrap(), &pack_dir).unwrap();
+        let cache = SampleCache::build(&switchtypes_dir, "synthblue", (1, synth::SAMPLE_RATE));
+        std::fs::remove_dir_all(&pack_dir).unwrap();
+
+        let event = SoundEvent::new(Some(Key::KeyQ), true, &pack("synthblue"));
+        let (sample, _) = event.load_cached(&switchtypes_dir, &cache).unwrap();
+        assert_eq!(sample, "synthblue/synth/press/R1");
+        assert!(event.load(&switchtypes_dir).is_err());
+    }
+
+    #[test]
+    fn test_samples_in_other_formats() {
+        let switchtypes_dir = test_pack("formats");
+        let path = switchtypes_dir.join("test/press/GENERIC_R2.wav");
+        std::fs::remove_file(&path).unwrap();
+        super::super::flac::write(&path.with_extension("flac"), 1, 44100, &[i16::MAX / 2; 441]).unwrap();
+
+        let (sample, sound) = SoundEvent::masked(true, &config(1.0)).load(&switchtypes_dir).unwrap();
+        assert_eq!(sample, "test/press/GENERIC_R2.flac");
+        assert_eq!(sound.skip_while(|sample| *sample == 0.0).count(), 441);
+    }
+
+    #[test]
+    fn test_missing_sample_error_hides_the_row() {
+        let Err(error) = SoundEvent::new(Some(Key::KeyQ), true, &pack("not-installed")).load(&test_pack("redact")) else {
+            panic!("not-installed has no samples");
+        };
+        assert!(!format!("{:#}", error).contains("GENERIC_R1"), "{:#}", error);
+    }
+
+    #[test]
+    fn test_latency_is_timed_per_stage() {
+        let backend = BufferBackend::new();
+        let engine = SoundEngine::with_backend(Settings::default(), Box::new(backend.clone()), test_pack("latency")).unwrap();
+        let missing = pack("not-installed");
+        engine.play(SoundEvent::new(Some(Key::KeyA), true, &config(1.0)).arrived_at(Instant::now() - Duration::from_millis(5)));
+        engine.play(SoundEvent::new(Some(Key::KeyA), true, &missing));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while engine.latency().count(Stage::Input) < 2 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        wait_for(&backend, 1);
+        let counts: Vec<u64> = engine.latency().summary().iter().map(|stage| stage.count).collect();
+        // The missing sample never got past decoding
+        assert_eq!(counts, vec![2, 2, 1, 1, 1]);
+        let total = &engine.latency().summary()[4];
+        assert!(total.p50_us.unwrap() >= 5000);
+    }
+
+    #[test]
+    fn test_missing_sample_plays_nothing() {
+        let backend = BufferBackend::new();
+        let engine = SoundEngine::with_backend(Settings::default(), Box::new(backend.clone()), test_pack("missing")).unwrap();
+        let missing = pack("not-installed");
+        assert!(engine.play_test_sound(None, true, &missing));
+        assert!(engine.play_test_sound(None, true, &config(1.0)));
+        assert_eq!(samples(&wait_for(&backend, 1)), vec!["test/press/GENERIC_R2.wav"]);
+    }
+}
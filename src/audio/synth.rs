@@ -0,0 +1,447 @@
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::f32::consts::PI;
+use std::fs;
+use std::path::Path;
+
+/// Packs with this file in their folder are synthesized instead of played from MP3s
+pub const PARAMS_FILE: &str = "synth.toml";
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Built-in starting points, named after the sampled packs they approximate
+pub const PRESETS: [&str; 13] = [
+    "alpaca", "blackink", "bluealps", "boxnavy", "buckling", "cream", "holypanda",
+    "mxblack", "mxblue", "mxbrown", "redink", "topre", "turquoise",
+];
+
+/// Pitch and ring length per key row. The space bar is bigger, so it sounds lower and
+/// rings longer.
+const ROW_SHAPE: [(f32, f32); 5] = [(1.06, 0.9), (1.03, 0.95), (1.0, 1.0), (0.97, 1.05), (0.8, 1.6)];
+/// Top-out knocks the stem into the housing, which sounds higher than bottom-out
+const RELEASE_PITCH: f32 = 1.12;
+/// Random spread between hits of the same key
+const PITCH_SPREAD: f32 = 0.03;
+const LEVEL_SPREAD: f32 = 0.1;
+/// Share of the excitation heard directly rather than through the resonators
+const DIRECT: f32 = 0.5;
+/// Hits last this many decay times of their longest mode, by then it's 60 dB down
+const DECAY_LENGTHS: f32 = 7.0;
+const MAX_LENGTH_MS: f32 = 1000.0;
+const FADE_OUT_MS: f32 = 5.0;
+/// A bar clamped at one end rings at this multiple of its base frequency too
+const BAR_PARTIAL: f32 = 6.27;
+const BAR_PARTIAL_GAIN: f32 = 0.3;
+/// The jacket snapping back up is quieter than it snapping down
+const JACKET_RELEASE_GAIN: f32 = 0.5;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SynthParams {
+    /// Peak of a press, up to 1.0 for full scale
+    pub level: f32,
+    /// Peak of a release relative to the press
+    pub release_level: f32,
+    pub transient: Transient,
+    /// Resonances of the stem and housing
+    pub body: Vec<Mode>,
+    /// The spring's metallic ping
+    pub spring: Mode,
+    /// The keyboard case, the same for every key
+    pub case: Mode,
+    /// Clicky switches only
+    pub click: Option<Click>,
+}
+
+/// The excitation: an impulse for the hard contact plus a burst of noise for the scrape
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transient {
+    pub impulse: f32,
+    pub noise: f32,
+    pub decay_ms: f32,
+    /// 0.0 to 1.0, how much of the noise is high-passed
+    pub brightness: f32,
+}
+
+/// One resonance, rung by the transient
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mode {
+    pub freq: f32,
+    pub decay_ms: f32,
+    pub gain: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Click {
+    pub kind: ClickKind,
+    pub gain: f32,
+    pub freq: f32,
+    pub decay_ms: f32,
+    /// How long before bottom-out the click happens
+    pub lead_ms: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClickKind {
+    /// A plastic jacket snapping down and back up: a noisy tick on press and release
+    Jacket,
+    /// A metal bar or leaf flicking: a ringing ping on press only
+    Bar,
+}
+
+impl SynthParams {
+    fn check(&self) -> Result<()> {
+        let modes = self.body.iter().chain([&self.spring, &self.case]);
+        for mode in modes {
+            ensure!(mode.freq > 0.0 && mode.decay_ms > 0.0, "Modes need a positive freq and decay_ms");
+        }
+        ensure!((0.0..=1.0).contains(&self.level), "level must be between 0.0 and 1.0");
+        ensure!((0.0..=1.0).contains(&self.release_level), "release_level must be between 0.0 and 1.0");
+        ensure!(self.transient.decay_ms > 0.0, "The transient needs a positive decay_ms");
+        ensure!((0.0..=1.0).contains(&self.transient.brightness), "brightness must be between 0.0 and 1.0");
+        if let Some(click) = &self.click {
+            ensure!(click.freq > 0.0 && click.decay_ms > 0.0, "The click needs a positive freq and decay_ms");
+            ensure!(click.lead_ms >= 0.0, "The click's lead_ms can't be negative");
+        }
+        Ok(())
+    }
+}
+
+/// Reads the synth parameters of the pack in `pack_dir`, or `None` for a sampled pack
+pub fn load(pack_dir: &Path) -> Result<Option<SynthParams>> {
+    let path = pack_dir.join(PARAMS_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents = fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    let params: SynthParams = toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))?;
+    params.check().with_context(|| format!("Invalid {:?}", path))?;
+    Ok(Some(params))
+}
+
+pub fn save(params: &SynthParams, pack_dir: &Path) -> Result<()> {
+    fs::create_dir_all(pack_dir)?;
+    fs::write(pack_dir.join(PARAMS_FILE), toml::to_string(params)?)?;
+    Ok(())
+}
+
+fn mode(freq: f32, decay_ms: f32, gain: f32) -> Mode {
+    Mode { freq, decay_ms, gain }
+}
+
+fn transient(impulse: f32, noise: f32, decay_ms: f32, brightness: f32) -> Transient {
+    Transient { impulse, noise, decay_ms, brightness }
+}
+
+fn click(kind: ClickKind, gain: f32, freq: f32, decay_ms: f32, lead_ms: f32) -> Option<Click> {
+    Some(Click { kind, gain, freq, decay_ms, lead_ms })
+}
+
+pub fn preset(name: &str) -> Result<SynthParams> {
+    let linear = |transient, body, spring, case| SynthParams {
+        level: 0.8,
+        release_level: 0.5,
+        transient,
+        body,
+        spring,
+        case,
+        click: None,
+    };
+    let params = match name {
+        "alpaca" => linear(
+            transient(0.6, 0.5, 2.5, 0.4),
+            vec![mode(1400.0, 12.0, 0.5), mode(2900.0, 8.0, 0.25), mode(5200.0, 5.0, 0.12)],
+            mode(4200.0, 60.0, 0.01),
+            mode(320.0, 25.0, 0.3),
+        ),
+        "blackink" => linear(
+            transient(0.6, 0.45, 2.5, 0.3),
+            vec![mode(1150.0, 14.0, 0.55), mode(2500.0, 9.0, 0.25), mode(4600.0, 5.0, 0.1)],
+            mode(4100.0, 60.0, 0.01),
+            mode(280.0, 30.0, 0.35),
+        ),
+        "redink" => SynthParams { level: 0.75, ..linear(
+            transient(0.6, 0.5, 2.0, 0.5),
+            vec![mode(1600.0, 11.0, 0.5), mode(3300.0, 7.0, 0.25), mode(5800.0, 4.0, 0.12)],
+            mode(4400.0, 50.0, 0.01),
+            mode(340.0, 22.0, 0.28),
+        ) },
+        "mxblack" => linear(
+            transient(0.6, 0.55, 2.5, 0.55),
+            vec![mode(1500.0, 10.0, 0.45), mode(3100.0, 7.0, 0.22), mode(5400.0, 4.0, 0.12)],
+            mode(4000.0, 90.0, 0.03),
+            mode(300.0, 22.0, 0.3),
+        ),
+        "cream" => linear(
+            transient(0.5, 0.6, 3.0, 0.6),
+            vec![mode(1250.0, 13.0, 0.5), mode(2700.0, 9.0, 0.28), mode(5000.0, 6.0, 0.15)],
+            mode(4300.0, 60.0, 0.005),
+            mode(300.0, 28.0, 0.32),
+        ),
+        "turquoise" => linear(
+            transient(0.6, 0.45, 2.5, 0.35),
+            vec![mode(1350.0, 12.0, 0.5), mode(2800.0, 8.0, 0.22), mode(5100.0, 5.0, 0.1)],
+            mode(4300.0, 50.0, 0.005),
+            mode(310.0, 26.0, 0.3),
+        ),
+        "holypanda" => SynthParams { level: 0.9, ..linear(
+            transient(0.8, 0.7, 3.0, 0.45),
+            vec![mode(1100.0, 15.0, 0.6), mode(2400.0, 10.0, 0.3), mode(4400.0, 6.0, 0.12)],
+            mode(3800.0, 70.0, 0.01),
+            mode(260.0, 35.0, 0.4),
+        ) },
+        "mxbrown" => linear(
+            transient(0.6, 0.6, 3.0, 0.5),
+            vec![mode(1450.0, 10.0, 0.45), mode(3000.0, 7.0, 0.22), mode(5300.0, 4.0, 0.12)],
+            mode(4000.0, 80.0, 0.025),
+            mode(300.0, 22.0, 0.3),
+        ),
+        "mxblue" => SynthParams {
+            click: click(ClickKind::Jacket, 0.7, 4500.0, 6.0, 8.0),
+            ..preset("mxbrown")?
+        },
+        "boxnavy" => SynthParams {
+            level: 0.9,
+            click: click(ClickKind::Bar, 0.9, 3200.0, 25.0, 6.0),
+            ..linear(
+                transient(0.7, 0.5, 2.5, 0.45),
+                vec![mode(1200.0, 13.0, 0.55), mode(2600.0, 9.0, 0.28), mode(4800.0, 5.0, 0.12)],
+                mode(3900.0, 70.0, 0.02),
+                mode(290.0, 28.0, 0.35),
+            )
+        },
+        "bluealps" => SynthParams {
+            click: click(ClickKind::Bar, 0.8, 3800.0, 15.0, 5.0),
+            ..linear(
+                transient(0.6, 0.6, 2.0, 0.6),
+                vec![mode(1700.0, 10.0, 0.45), mode(3500.0, 7.0, 0.25), mode(6000.0, 4.0, 0.1)],
+                mode(4500.0, 40.0, 0.01),
+                mode(330.0, 20.0, 0.28),
+            )
+        },
+        "buckling" => SynthParams {
+            level: 0.9,
+            release_level: 0.6,
+            click: click(ClickKind::Bar, 1.0, 2400.0, 20.0, 4.0),
+            ..linear(
+                transient(0.8, 0.6, 3.5, 0.4),
+                vec![mode(900.0, 20.0, 0.5), mode(2100.0, 12.0, 0.3)],
+                // The spring rings for a long time in the barrel
+                mode(1900.0, 180.0, 0.35),
+                mode(180.0, 60.0, 0.5),
+            )
+        },
+        "topre" => SynthParams {
+            level: 0.85,
+            release_level: 0.3,
+            // Rubber domes have no spring to ping
+            ..linear(
+                transient(0.3, 0.3, 4.0, 0.15),
+                vec![mode(800.0, 18.0, 0.55), mode(1900.0, 10.0, 0.2), mode(3600.0, 5.0, 0.05)],
+                mode(4000.0, 10.0, 0.0),
+                mode(240.0, 35.0, 0.35),
+            )
+        },
+        _ => bail!("Unknown preset {:?}, pick one of {}", name, PRESETS.join(", ")),
+    };
+    Ok(params)
+}
+
+/// Generates one mono hit at `sample_rate`. `row` is 0 (number row) to 4 (space bar),
+/// `seed` varies the hit a little the way no two real key presses sound the same.
+pub fn hit(params: &SynthParams, row: usize, is_press: bool, sample_rate: u32, seed: u64) -> Vec<f32> {
+    let mut random = XorShift::new(seed);
+    let rate = sample_rate as f32;
+    let (pitch, ring) = ROW_SHAPE[row.min(ROW_SHAPE.len() - 1)];
+    let pitch = pitch * (1.0 + random.bipolar() * PITCH_SPREAD) * if is_press { 1.0 } else { RELEASE_PITCH };
+    let level = (params.level
+        * if is_press { 1.0 } else { params.release_level }
+        * (1.0 + random.bipolar() * LEVEL_SPREAD)).min(1.0);
+
+    let click = params.click.as_ref().filter(|click| is_press || click.kind == ClickKind::Jacket);
+    let lead = click.map_or(0, |click| ms_to_samples(click.lead_ms, rate));
+    let longest = params.body.iter()
+        .chain([&params.spring, &params.case])
+        .map(|resonance| resonance.decay_ms * ring)
+        .chain(click.map(|click| click.decay_ms))
+        .fold(params.transient.decay_ms, f32::max);
+    let len = lead + ms_to_samples((longest * DECAY_LENGTHS).min(MAX_LENGTH_MS), rate);
+
+    let mut out = vec![0.0; len];
+    let excitation = excite(&params.transient, len - lead, rate, &mut random);
+    for (i, x) in excitation.iter().enumerate() {
+        out[lead + i] += x * DIRECT;
+    }
+    for body_mode in &params.body {
+        resonate(&excitation, body_mode, pitch, ring, rate, &mut out[lead..]);
+    }
+    // The spring and case are the same size under every key
+    resonate(&excitation, &params.spring, 1.0, ring, rate, &mut out[lead..]);
+    resonate(&excitation, &params.case, 1.0, 1.0, rate, &mut out[lead..]);
+
+    if let Some(click) = click {
+        let gain = if is_press { click.gain } else { click.gain * JACKET_RELEASE_GAIN };
+        let ping = mode(click.freq, click.decay_ms, gain);
+        match click.kind {
+            ClickKind::Jacket => {
+                let tick = excite(&transient(0.0, 1.0, click.decay_ms / 3.0, 1.0), len, rate, &mut random);
+                resonate(&tick, &ping, 1.0, 1.0, rate, &mut out);
+            }
+            ClickKind::Bar => {
+                let mut flick = vec![0.0; len];
+                flick[0] = 1.0;
+                resonate(&flick, &ping, 1.0, 1.0, rate, &mut out);
+                let partial = mode(click.freq * BAR_PARTIAL, click.decay_ms / 2.0, gain * BAR_PARTIAL_GAIN);
+                resonate(&flick, &partial, 1.0, 1.0, rate, &mut out);
+            }
+        }
+    }
+
+    let fade = ms_to_samples(FADE_OUT_MS, rate).min(len);
+    for (i, sample) in out[len - fade..].iter_mut().enumerate() {
+        *sample *= 1.0 - i as f32 / fade as f32;
+    }
+    // However the resonances add up, every hit peaks at its level
+    let peak = out.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs()));
+    let scale = if peak > 0.0 { level / peak } else { 0.0 };
+    out.iter_mut().for_each(|sample| *sample *= scale);
+    out
+}
+
+fn ms_to_samples(ms: f32, rate: f32) -> usize {
+    (ms / 1000.0 * rate).round() as usize
+}
+
+fn excite(transient: &Transient, len: usize, rate: f32, random: &mut XorShift) -> Vec<f32> {
+    let decay = ms_to_samples(transient.decay_ms, rate).max(1) as f32;
+    let mut previous = 0.0;
+    let mut out: Vec<f32> = (0..len)
+        .map(|i| {
+            let noise = random.bipolar();
+            // A first difference is the cheapest high-pass there is
+            let shaped = transient.brightness * (noise - previous) + (1.0 - transient.brightness) * noise;
+            previous = noise;
+            shaped * transient.noise * (-(i as f32) / decay).exp()
+        })
+        .collect();
+    if let Some(first) = out.first_mut() {
+        *first += transient.impulse;
+    }
+    out
+}
+
+/// Runs `input` through a two-pole resonator tuned to `mode` and adds the result to
+/// `out`. An impulse in gives a sine of amplitude `mode.gain` that decays by 1/e every
+/// `decay_ms`.
+fn resonate(input: &[f32], mode: &Mode, pitch: f32, ring: f32, rate: f32, out: &mut [f32]) {
+    let freq = (mode.freq * pitch).min(rate * 0.45);
+    let w = 2.0 * PI * freq / rate;
+    let r = (-1.0 / (mode.decay_ms * ring / 1000.0 * rate)).exp();
+    let (a1, a2) = (2.0 * r * w.cos(), -r * r);
+    let b0 = mode.gain * w.sin();
+    let (mut y1, mut y2) = (0.0, 0.0);
+    for (i, sample) in out.iter_mut().enumerate() {
+        let y = b0 * input.get(i).copied().unwrap_or(0.0) + a1 * y1 + a2 * y2;
+        y2 = y1;
+        y1 = y;
+        *sample += y;
+    }
+}
+
+/// Small fast generator for the noise, seeded per hit
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // Zero would get stuck at zero
+        Self(seed | 1)
+    }
+
+    /// Uniform in -1.0..1.0
+    fn bipolar(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn test_every_preset_plays_without_clipping() {
+        for name in PRESETS {
+            let params = preset(name).unwrap();
+            params.check().unwrap();
+            for row in 0..5 {
+                for is_press in [true, false] {
+                    let samples = hit(&params, row, is_press, SAMPLE_RATE, 7);
+                    let peak = peak(&samples);
+                    assert!(peak > 0.01 && peak <= 1.0, "{} row {} press {}: peak {}", name, row, is_press, peak);
+                    assert!(samples.len() <= ms_to_samples(MAX_LENGTH_MS + 20.0, SAMPLE_RATE as f32));
+                    assert!(samples.last().unwrap().abs() < 1e-3, "{} doesn't fade out", name);
+                }
+            }
+        }
+        assert!(preset("gateron").is_err());
+    }
+
+    #[test]
+    fn test_hits_peak_at_their_level() {
+        let params = preset("mxblue").unwrap();
+        let press = peak(&hit(&params, 1, true, SAMPLE_RATE, 9));
+        let release = peak(&hit(&params, 1, false, SAMPLE_RATE, 9));
+        assert!((press / params.level - 1.0).abs() <= LEVEL_SPREAD, "press peaks at {}", press);
+        assert!(release < press * 0.7, "release peaks at {}", release);
+    }
+
+    #[test]
+    fn test_hits_vary_with_seed_only() {
+        let params = preset("holypanda").unwrap();
+        assert_eq!(hit(&params, 2, true, SAMPLE_RATE, 1), hit(&params, 2, true, SAMPLE_RATE, 1));
+        assert_ne!(hit(&params, 2, true, SAMPLE_RATE, 1), hit(&params, 2, true, SAMPLE_RATE, 2));
+    }
+
+    #[test]
+    fn test_space_bar_rings_longer() {
+        let params = preset("alpaca").unwrap();
+        let letter = hit(&params, 2, true, SAMPLE_RATE, 3);
+        let space = hit(&params, 4, true, SAMPLE_RATE, 3);
+        assert!(space.len() > letter.len());
+    }
+
+    #[test]
+    fn test_click_leads_bottom_out() {
+        let lead = ms_to_samples(6.0, SAMPLE_RATE as f32);
+        let clicky = hit(&preset("boxnavy").unwrap(), 2, true, SAMPLE_RATE, 5);
+        // Only the click sounds before bottom-out
+        assert!(peak(&clicky[..lead]) > 0.05);
+        let linear = SynthParams { click: None, ..preset("boxnavy").unwrap() };
+        assert_eq!(clicky.len(), hit(&linear, 2, true, SAMPLE_RATE, 5).len() + lead);
+        // A bar doesn't click on the way up, so the release isn't delayed
+        assert_eq!(hit(&preset("boxnavy").unwrap(), 2, false, SAMPLE_RATE, 5).len(), hit(&linear, 2, false, SAMPLE_RATE, 5).len());
+    }
+
+    #[test]
+    fn test_params_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("clickclack-synth-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(load(&dir).unwrap(), None);
+
+        let params = preset("mxblue").unwrap();
+        save(&params, &dir).unwrap();
+        assert_eq!(load(&dir).unwrap(), Some(params));
+
+        fs::write(dir.join(PARAMS_FILE), toml::to_string(&SynthParams {
+            case: mode(300.0, 0.0, 0.3),
+            ..preset("topre").unwrap()
+        }).unwrap()).unwrap();
+        assert!(load(&dir).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,251 @@
+use anyhow::{bail, Context, Result};
+use rodio::{Decoder, Source};
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::time::Duration;
+
+use super::synth;
+use crate::packs;
+
+/// Frames quieter than this on every channel count as silence, about -60 dBFS
+pub const SILENCE_THRESHOLD: f32 = 0.001;
+/// Most silence trimmed off the front of a sample. Past this the quiet start is likely
+/// meant to be there, like the slow rise of a tactile bump.
+pub const MAX_TRIM: Duration = Duration::from_millis(50);
+
+/// Delay a decoder adds on top of the encoder's, by the convention LAME writes its tag for
+const MP3_DECODER_DELAY: u32 = 529;
+
+/// Encoder padding an MP3 declares in its LAME tag, in samples per channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gapless {
+    /// Skipped at the start, encoder and decoder delay together
+    pub delay: u32,
+    /// Dropped at the end
+    pub padding: u32,
+}
+
+/// Reads the LAME tag of an MP3 file. rodio's MP3 decoder already skips what it
+/// declares, so this is for reporting.
+pub fn gapless(bytes: &[u8]) -> Option<Gapless> {
+    let frame = skip_id3(bytes)?;
+    let header = frame.get(..4)?;
+    if header[0] != 0xff || header[1] & 0xe0 != 0xe0 {
+        return None;
+    }
+    let mpeg1 = header[1] & 0x18 == 0x18;
+    let mono = header[3] & 0xc0 == 0xc0;
+    let side_info = match (mpeg1, mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+
+    let tag = frame.get(4 + side_info..)?;
+    if !tag.starts_with(b"Xing") && !tag.starts_with(b"Info") {
+        return None;
+    }
+    let flags = u32::from_be_bytes(tag.get(4..8)?.try_into().ok()?);
+    let optional = [(1, 4), (2, 4), (4, 100), (8, 4)];
+    let lame = 8 + optional.iter().filter(|(flag, _)| flags & flag != 0).map(|(_, len)| len).sum::<usize>();
+
+    let encoder = tag.get(lame..lame + 4)?;
+    if encoder != b"LAME" && encoder != b"Lavf" && encoder != b"Lavc" {
+        return None;
+    }
+    // Version string, revision, lowpass, ReplayGain, flags and bitrate come first
+    let trim = tag.get(lame + 21..lame + 24)?;
+    let trim = u32::from_be_bytes([0, trim[0], trim[1], trim[2]]);
+    Some(Gapless {
+        delay: (trim >> 12) + MP3_DECODER_DELAY,
+        padding: (trim & 0xfff).saturating_sub(MP3_DECODER_DELAY),
+    })
+}
+
+fn skip_id3(bytes: &[u8]) -> Option<&[u8]> {
+    if !bytes.starts_with(b"ID3") {
+        return Some(bytes);
+    }
+    let header = bytes.get(..10)?;
+    // Sizes are sync-safe, seven bits per byte
+    let size = header[6..10].iter().fold(0usize, |size, byte| (size << 7) | (*byte & 0x7f) as usize);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    bytes.get(10 + size + footer..)
+}
+
+/// Skips the silent frames at the start of a source, up to `MAX_TRIM`, so the sound
+/// starts the moment it is played
+pub struct TrimStart<S> {
+    source: S,
+    /// The first frame that wasn't silent, played before the rest of `source`
+    pending: Vec<f32>,
+    trimmed_frames: usize,
+}
+
+impl<S: Source<Item = f32>> TrimStart<S> {
+    pub fn new(mut source: S) -> Self {
+        let channels = source.channels().max(1) as usize;
+        let max_frames = (MAX_TRIM.as_secs_f64() * source.sample_rate() as f64) as usize;
+        let mut pending = Vec::with_capacity(channels);
+        let mut trimmed_frames = 0;
+        while trimmed_frames < max_frames {
+            pending.clear();
+            pending.extend(source.by_ref().take(channels));
+            if pending.iter().any(|sample| sample.abs() >= SILENCE_THRESHOLD) || pending.len() < channels {
+                break;
+            }
+            trimmed_frames += 1;
+            pending.clear();
+        }
+        pending.reverse();
+        Self { source, pending, trimmed_frames }
+    }
+
+    pub fn trimmed(&self) -> Duration {
+        Duration::from_secs_f64(self.trimmed_frames as f64 / self.source.sample_rate() as f64)
+    }
+}
+
+impl<S: Source<Item = f32>> Iterator for TrimStart<S> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        self.pending.pop().or_else(|| self.source.next())
+    }
+}
+
+impl<S: Source<Item = f32>> Source for TrimStart<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len().map(|len| len + self.pending.len())
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration().map(|duration| duration.saturating_sub(self.trimmed()))
+    }
+}
+
+/// When a sample file starts to sound
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Onset {
+    /// What the decoder skipped for an MP3 with a LAME tag
+    pub gapless: Option<Gapless>,
+    pub sample_rate: u32,
+    /// Silence at the start of the decoded sample
+    pub silence: Duration,
+    /// Silence left over after `TrimStart`, heard as delay
+    pub delay: Duration,
+}
+
+pub fn onset(path: &Path) -> Result<Onset> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let gapless = if path.extension().is_some_and(|extension| extension == "mp3") { gapless(&bytes) } else { None };
+
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let source = Decoder::new(BufReader::new(file)).with_context(|| format!("Failed to decode {:?}", path))?;
+    let (channels, sample_rate) = (source.channels().max(1) as usize, source.sample_rate());
+    let samples: Vec<f32> = source.convert_samples().collect();
+    let silent_frames = samples
+        .chunks(channels)
+        .take_while(|frame| frame.iter().all(|sample| sample.abs() < SILENCE_THRESHOLD))
+        .count();
+
+    let silence = Duration::from_secs_f64(silent_frames as f64 / sample_rate as f64);
+    Ok(Onset { gapless, sample_rate, silence, delay: silence.saturating_sub(MAX_TRIM) })
+}
+
+/// A table of every sample of the pack in `pack_dir` and how late it starts
+pub fn report(pack_dir: &Path) -> Result<String> {
+    if synth::load(pack_dir)?.is_some() {
+        bail!("{:?} is synthesized, its hits are made in memory and start without delay", pack_dir);
+    }
+    let samples = packs::list_samples(pack_dir)?;
+    if samples.is_empty() {
+        bail!("No samples in {:?}", pack_dir);
+    }
+
+    let ms = |duration: Duration| format!("{:.1} ms", duration.as_secs_f64() * 1000.0);
+    let mut out = format!("{:<24} {:>16} {:>10} {:>10}\n", "Sample", "Gapless skip", "Silence", "Delay");
+    for path in samples.values() {
+        let name = path.strip_prefix(pack_dir).unwrap_or(path).display().to_string();
+        let onset = onset(path)?;
+        let skipped = match onset.gapless {
+            Some(gapless) => ms(Duration::from_secs_f64(gapless.delay as f64 / onset.sample_rate as f64)),
+            None => "-".to_string(),
+        };
+        writeln!(out, "{:<24} {:>16} {:>10} {:>10}", name, skipped, ms(onset.silence), ms(onset.delay))?;
+    }
+    writeln!(out, "\nUp to {} of silence is trimmed when a sample loads; the rest is heard as delay.", ms(MAX_TRIM))?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn test_trims_silent_frames() {
+        // Stereo: two silent frames, one under the threshold, then the hit
+        let samples = vec![0.0, 0.0, 0.0005, -0.0005, 0.0, 0.0, 0.5, 0.0, 0.2, 0.3];
+        let trimmed = TrimStart::new(SamplesBuffer::new(2, 1000, samples));
+        assert_eq!(trimmed.trimmed(), Duration::from_millis(3));
+        assert_eq!(trimmed.collect::<Vec<f32>>(), vec![0.5, 0.0, 0.2, 0.3]);
+    }
+
+    #[test]
+    fn test_trims_at_most_max_trim() {
+        let mut samples = vec![0.0; 100];
+        samples.push(1.0);
+        let trimmed = TrimStart::new(SamplesBuffer::new(1, 1000, samples));
+        assert_eq!(trimmed.trimmed(), MAX_TRIM);
+        assert_eq!(trimmed.count(), 51);
+
+        let silent = TrimStart::new(SamplesBuffer::new(1, 1000, vec![0.0; 10]));
+        assert_eq!(silent.count(), 0);
+    }
+
+    #[test]
+    fn test_onset() {
+        let dir = std::env::temp_dir().join(format!("clickclack-onset-{}", std::process::id()));
+        fs::create_dir_all(dir.join("press")).unwrap();
+        fs::write(dir.join("press/GENERIC_R0.mp3"), include_bytes!("../../assets/switchtypes/mxblue/press/GENERIC_R0.mp3")).unwrap();
+        let spec = hound::WavSpec { channels: 1, sample_rate: 1000, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(dir.join("press/GENERIC_R1.wav"), spec).unwrap();
+        for i in 0..200 {
+            writer.write_sample(if i < 80 { 0i16 } else { i16::MAX / 2 }).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let wav = onset(&dir.join("press/GENERIC_R1.wav")).unwrap();
+        assert_eq!(wav, Onset { gapless: None, sample_rate: 1000, silence: Duration::from_millis(80), delay: Duration::from_millis(30) });
+        let mp3 = onset(&dir.join("press/GENERIC_R0.mp3")).unwrap();
+        assert_eq!(mp3.gapless.map(|gapless| gapless.delay), Some(1105));
+
+        let report = report(&dir).unwrap();
+        assert!(report.lines().nth(2).unwrap().starts_with("press/GENERIC_R1.wav"));
+        assert!(report.lines().nth(2).unwrap().ends_with("30.0 ms"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_gapless_from_lame_tag() {
+        let bundled = include_bytes!("../../assets/switchtypes/mxblue/press/GENERIC_R0.mp3");
+        assert_eq!(gapless(bundled), Some(Gapless { delay: 576 + 529, padding: 830 - 529 }));
+
+        // Behind an empty ID3v2 tag
+        let mut tagged = b"ID3\x04\x00\x00\x00\x00\x00\x00".to_vec();
+        tagged.extend_from_slice(bundled);
+        assert_eq!(gapless(&tagged), gapless(bundled));
+        assert_eq!(gapless(b"RIFF\x00\x00\x00\x00WAVE"), None);
+    }
+}
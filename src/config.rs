@@ -4,11 +4,55 @@ use std::fs;
 use std::path::PathBuf;
 use log;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub enabled: bool,
+    /// Slider position, 0.0 - 1.5. 1.0 plays the samples as recorded.
     pub volume: f32,
     pub switch_type: String,
+    /// Extra gain in dB applied on top of the volume
+    #[serde(default)]
+    pub master_gain_db: f32,
+    /// cpal name of the output device, `None` for the system default
+    #[serde(default)]
+    pub output_device: Option<String>,
+    /// Frames per output buffer, `None` for the device's default. Smaller buffers play
+    /// sooner but underrun (crackle) when the machine is busy.
+    #[serde(default)]
+    pub buffer_frames: Option<u32>,
+    /// What to play while a password is being typed
+    #[serde(default)]
+    pub secure_input: SecureInputMode,
+    /// Count keystrokes for `clickclack stats`. Only counts are stored, never text.
+    #[serde(default)]
+    pub stats: bool,
+    /// Which press sample each key plays
+    #[serde(default)]
+    pub keymap: Keymap,
+    /// How much each key's loudness and timing vary, from 0.0 (every hit the same) to 1.0
+    #[serde(default)]
+    pub humanize: f32,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecureInputMode {
+    /// No sounds at all
+    #[default]
+    Silent,
+    /// One sample for every key, with jittered timing
+    Masked,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Keymap {
+    /// Each keyboard row plays its own `GENERIC_R<row>` sample, and space and enter
+    /// their own where the pack has them
+    #[default]
+    Rows,
+    /// Every key plays the home row's sample
+    Flat,
 }
 
 impl Default for Config {
@@ -17,6 +61,13 @@ impl Default for Config {
             enabled: true,
             volume: 1.0,
             switch_type: String::from("mxblue"),
+            master_gain_db: 0.0,
+            output_device: None,
+            buffer_frames: None,
+            secure_input: SecureInputMode::default(),
+            stats: false,
+            keymap: Keymap::default(),
+            humanize: 0.0,
         }
     }
 }
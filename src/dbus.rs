@@ -0,0 +1,275 @@
+use anyhow::Result;
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::{dbus_interface, fdo, SignalContext};
+
+use crate::audio::gain::MAX_VOLUME;
+use crate::packs;
+use crate::Settings;
+
+pub const BUS_NAME: &str = "org.clickclack.Control";
+pub const OBJECT_PATH: &str = "/org/clickclack/Control";
+
+static CONNECTION: OnceCell<Connection> = OnceCell::new();
+
+/// The `org.clickclack.Control` object. Properties and methods mirror the tray menu.
+/// Methods emit PropertiesChanged for their caller before returning; the app's saving
+/// thread emits it once more, which clients take as a harmless repeat.
+pub struct Control {
+    settings: Settings,
+    /// Where the packs `SetSwitch` accepts are installed
+    switchtypes_dir: PathBuf,
+}
+
+#[dbus_interface(name = "org.clickclack.Control")]
+impl Control {
+    #[dbus_interface(property)]
+    fn enabled(&self) -> bool {
+        self.settings.current().enabled
+    }
+
+    #[dbus_interface(property)]
+    fn set_enabled(&mut self, enabled: bool) {
+        self.settings.update(|state| state.enabled = enabled);
+    }
+
+    #[dbus_interface(property)]
+    fn volume(&self) -> f64 {
+        self.settings.current().volume as f64
+    }
+
+    #[dbus_interface(property)]
+    fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        check_volume(volume)?;
+        self.settings.update(|state| state.volume = volume as f32);
+        Ok(())
+    }
+
+    #[dbus_interface(property)]
+    fn switch_type(&self) -> String {
+        self.settings.current().switch_type.clone()
+    }
+
+    #[dbus_interface(property)]
+    fn set_switch_type(&mut self, switch_type: String) -> fdo::Result<()> {
+        check_switch_type(&self.switchtypes_dir, &switch_type)?;
+        self.settings.update(|state| state.switch_type = switch_type);
+        Ok(())
+    }
+
+    /// Turns sound on or off and returns the new state
+    async fn toggle(&mut self, #[zbus(signal_context)] ctxt: SignalContext<'_>) -> fdo::Result<bool> {
+        self.settings.update(|state| state.enabled = !state.enabled);
+        self.enabled_changed(&ctxt).await?;
+        Ok(self.enabled())
+    }
+
+    #[dbus_interface(name = "SetVolume")]
+    async fn set_volume_action(
+        &mut self,
+        volume: f64,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        check_volume(volume)?;
+        self.settings.update(|state| state.volume = volume as f32);
+        self.volume_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    #[dbus_interface(name = "SetSwitch")]
+    async fn set_switch_action(
+        &mut self,
+        switch_type: String,
+        #[zbus(signal_context)] ctxt: SignalContext<'_>,
+    ) -> fdo::Result<()> {
+        check_switch_type(&self.switchtypes_dir, &switch_type)?;
+        self.settings.update(|state| state.switch_type = switch_type);
+        self.switch_type_changed(&ctxt).await?;
+        Ok(())
+    }
+
+    /// Installed switch packs as display name -> folder name
+    fn list_switches(&self) -> fdo::Result<HashMap<String, String>> {
+        let packs = packs::list_packs_in(&self.switchtypes_dir).map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        Ok(packs.into_iter().map(|pack| (pack.name, pack.folder)).collect())
+    }
+
+    fn quit(&self) {
+        // Give the reply a moment to go out before the process disappears
+        std::thread::spawn(|| {
+            std::thread::sleep(Duration::from_millis(100));
+            std::process::exit(0);
+        });
+    }
+}
+
+fn check_volume(volume: f64) -> fdo::Result<()> {
+    if !(0.0..=MAX_VOLUME as f64).contains(&volume) {
+        return Err(fdo::Error::InvalidArgs(format!("Volume must be between 0.0 and {}, got {}", MAX_VOLUME, volume)));
+    }
+    Ok(())
+}
+
+fn check_switch_type(switchtypes_dir: &Path, switch_type: &str) -> fdo::Result<()> {
+    let packs = packs::list_packs_in(switchtypes_dir).map_err(|e| fdo::Error::Failed(e.to_string()))?;
+    if !packs.iter().any(|pack| pack.folder == switch_type) {
+        return Err(fdo::Error::InvalidArgs(format!("Unknown switch type: {}", switch_type)));
+    }
+    Ok(())
+}
+
+
+/// Exports the control object for `settings` on the session bus
+pub fn start(settings: Settings) -> Result<()> {
+    let control = Control { settings, switchtypes_dir: packs::switchtypes_dir() };
+    let connection = serve(ConnectionBuilder::session()?, control)?;
+    info!("D-Bus interface exported as {} at {}", BUS_NAME, OBJECT_PATH);
+    let _ = CONNECTION.set(connection);
+    Ok(())
+}
+
+fn serve(builder: ConnectionBuilder, control: Control) -> zbus::Result<Connection> {
+    builder.name(BUS_NAME)?.serve_at(OBJECT_PATH, control)?.build()
+}
+
+/// Emits PropertiesChanged for all properties, after any change to the settings
+pub fn emit_state_changed() {
+    let Some(connection) = CONNECTION.get() else {
+        return;
+    };
+
+    if let Err(e) = emit_all_changed(connection) {
+        error!("Failed to emit D-Bus property changes: {}", e);
+    }
+}
+
+fn emit_all_changed(connection: &Connection) -> zbus::Result<()> {
+    let iface_ref = connection.object_server().interface::<_, Control>(OBJECT_PATH)?;
+    let control = iface_ref.get();
+    let ctxt = iface_ref.signal_context();
+    zbus::block_on(async {
+        control.enabled_changed(ctxt).await?;
+        control.volume_changed(ctxt).await?;
+        control.switch_type_changed(ctxt).await
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc;
+    use zbus::blocking::fdo::PropertiesProxy;
+    use zbus::dbus_proxy;
+
+    #[dbus_proxy(
+        interface = "org.clickclack.Control",
+        default_service = "org.clickclack.Control",
+        default_path = "/org/clickclack/Control"
+    )]
+    trait Remote {
+        fn toggle(&self) -> zbus::Result<bool>;
+
+        #[dbus_proxy(name = "SetSwitch")]
+        fn set_switch_action(&self, switch_type: &str) -> zbus::Result<()>;
+
+        #[dbus_proxy(property)]
+        fn enabled(&self) -> zbus::Result<bool>;
+
+        #[dbus_proxy(property)]
+        fn volume(&self) -> zbus::Result<f64>;
+
+        #[dbus_proxy(property)]
+        fn set_volume(&self, volume: f64) -> zbus::Result<()>;
+    }
+
+    /// A throwaway `dbus-daemon --session` so the tests never touch the user's bus
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        fn start() -> Option<Self> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take()?).read_line(&mut address).ok()?;
+            Some(Self { daemon, address: address.trim().to_string() })
+        }
+
+        fn connect(&self) -> ConnectionBuilder<'static> {
+            ConnectionBuilder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[test]
+    #[ignore = "needs dbus-daemon, run with --ignored"]
+    fn test_control_on_private_bus() {
+        let bus = PrivateBus::start().expect("Failed to start dbus-daemon");
+
+        // One installed pack, and settings that are never saved
+        let switchtypes_dir = std::env::temp_dir().join(format!("clickclack-dbus-{}", std::process::id()));
+        std::fs::create_dir_all(&switchtypes_dir).unwrap();
+        std::fs::write(switchtypes_dir.join("desc.json"), r#"{"switches": {"Topre": "topre"}}"#).unwrap();
+        let control = Control { settings: Settings::default(), switchtypes_dir: switchtypes_dir.clone() };
+
+        let _service = serve(bus.connect(), control).unwrap();
+        let client = bus.connect().build().unwrap();
+        let proxy = RemoteProxyBlocking::builder(&client)
+            .cache_properties(zbus::CacheProperties::No)
+            .build()
+            .unwrap();
+
+        // Collect PropertiesChanged signals on a separate connection
+        let (sender, receiver) = mpsc::channel();
+        let watcher = bus.connect().build().unwrap();
+        std::thread::spawn(move || {
+            let properties = PropertiesProxy::builder(&watcher)
+                .destination(BUS_NAME).unwrap()
+                .path(OBJECT_PATH).unwrap()
+                .build()
+                .unwrap();
+            for signal in properties.receive_properties_changed().unwrap() {
+                let args = signal.args().unwrap();
+                for name in args.changed_properties().keys() {
+                    let _ = sender.send(name.to_string());
+                }
+            }
+        });
+        // Give the watcher time to subscribe
+        std::thread::sleep(Duration::from_millis(200));
+
+        let before = proxy.enabled().unwrap();
+        assert_eq!(proxy.toggle().unwrap(), !before);
+        assert_eq!(proxy.enabled().unwrap(), !before);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), "Enabled");
+
+        proxy.set_volume(0.5).unwrap();
+        assert_eq!(proxy.volume().unwrap(), 0.5);
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), "Volume");
+
+        assert!(proxy.set_volume(2.0).is_err());
+        assert!(proxy.set_switch_action("not-a-pack").is_err());
+        proxy.set_switch_action("topre").unwrap();
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(5)).unwrap(), "SwitchType");
+
+        std::fs::remove_dir_all(switchtypes_dir).unwrap();
+    }
+}
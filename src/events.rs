@@ -0,0 +1,191 @@
+use parking_lot::Mutex;
+use rdev::Key;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// How many events a subscriber can fall behind before new ones are dropped for it
+pub const QUEUE_LEN: usize = 256;
+
+/// Something that happened in a player, as seen by its subscribers
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A key went down or up. Held key repeats are filtered out, and nothing is sent
+    /// while secure input is on.
+    Key { key: Key, is_press: bool },
+    /// The settings changed; these are the new ones. Sent after the more specific
+    /// events below.
+    ConfigChanged(Arc<Config>),
+    PackChanged(String),
+    VolumeChanged(f32),
+    EnabledChanged(bool),
+    OutputDeviceChanged(Option<String>),
+}
+
+impl Event {
+    /// The events for going from `old` to `new` settings
+    pub fn config_changes(old: &Config, new: &Arc<Config>) -> Vec<Event> {
+        let mut events = Vec::new();
+        if old.switch_type != new.switch_type {
+            events.push(Event::PackChanged(new.switch_type.clone()));
+        }
+        if old.volume != new.volume {
+            events.push(Event::VolumeChanged(new.volume));
+        }
+        if old.enabled != new.enabled {
+            events.push(Event::EnabledChanged(new.enabled));
+        }
+        if old.output_device != new.output_device {
+            events.push(Event::OutputDeviceChanged(new.output_device.clone()));
+        }
+        events.push(Event::ConfigChanged(new.clone()));
+        events
+    }
+}
+
+/// Hands every published event to every subscriber. Each subscriber has its own queue of
+/// `QUEUE_LEN` events; a subscriber that falls behind misses events instead of holding up
+/// the publisher, which is often the key thread. Clones share the subscribers.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+}
+
+struct Subscriber {
+    sender: SyncSender<Event>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> Subscription {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.subscribers.lock().push(Subscriber { sender, dropped: dropped.clone() });
+        Subscription { receiver, dropped, seen: 0 }
+    }
+
+    /// Queues `event` for every subscriber without waiting on any of them
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().retain(|subscriber| match subscriber.sender.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            // The subscription was dropped
+            Err(TrySendError::Disconnected(_)) => false,
+        })
+    }
+
+    /// Subscriptions not yet found dropped by a `publish`
+    #[cfg(test)]
+    pub fn subscribers(&self) -> usize {
+        self.subscribers.lock().len()
+    }
+}
+
+/// The events published since subscribing. Dropping it unsubscribes.
+pub struct Subscription {
+    receiver: Receiver<Event>,
+    dropped: Arc<AtomicU64>,
+    /// `dropped` when `missed` was last asked
+    seen: u64,
+}
+
+impl Subscription {
+    /// Waits for the next event. `None` once the bus is gone.
+    pub fn recv(&self) -> Option<Event> {
+        self.receiver.recv().ok()
+    }
+
+    /// Waits up to `timeout` for the next event, telling a timeout from the bus being gone
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Event, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+
+    pub fn try_recv(&self) -> Option<Event> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// How many events were dropped because this subscriber's queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether events were dropped since this was last asked, e.g. to reread the settings
+    /// in case a change was among them
+    pub fn missed(&mut self) -> bool {
+        let dropped = self.dropped();
+        std::mem::replace(&mut self.seen, dropped) != dropped
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.recv()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(key: Key) -> Event {
+        Event::Key { key, is_press: true }
+    }
+
+    #[test]
+    fn test_every_subscriber_gets_every_event() {
+        let bus = EventBus::default();
+        let (first, second) = (bus.subscribe(), bus.clone().subscribe());
+        bus.publish(key(Key::KeyA));
+        bus.publish(Event::VolumeChanged(0.5));
+        for subscription in [first, second] {
+            assert_eq!(subscription.try_recv(), Some(key(Key::KeyA)));
+            assert_eq!(subscription.try_recv(), Some(Event::VolumeChanged(0.5)));
+            assert_eq!(subscription.try_recv(), None);
+        }
+    }
+
+    #[test]
+    fn test_slow_subscribers_miss_events() {
+        let bus = EventBus::default();
+        let (mut slow, fast) = (bus.subscribe(), bus.subscribe());
+        for _ in 0..QUEUE_LEN + 10 {
+            bus.publish(key(Key::KeyA));
+            assert_eq!(fast.try_recv(), Some(key(Key::KeyA)));
+        }
+        assert_eq!((slow.dropped(), fast.dropped()), (10, 0));
+        assert!(slow.missed() && !slow.missed());
+        assert_eq!(std::iter::from_fn(|| slow.try_recv()).count(), QUEUE_LEN);
+    }
+
+    #[test]
+    fn test_dropped_subscriptions_are_forgotten() {
+        let bus = EventBus::default();
+        drop(bus.subscribe());
+        let subscription = bus.subscribe();
+        bus.publish(key(Key::KeyA));
+        assert_eq!(bus.subscribers.lock().len(), 1);
+        assert_eq!(subscription.try_recv(), Some(key(Key::KeyA)));
+
+        drop(bus);
+        assert_eq!(subscription.recv(), None);
+    }
+
+    #[test]
+    fn test_config_changes() {
+        let old = Config::default();
+        let new = Arc::new(Config { volume: 0.5, switch_type: "topre".into(), ..Default::default() });
+        assert_eq!(
+            Event::config_changes(&old, &new),
+            vec![Event::PackChanged("topre".into()), Event::VolumeChanged(0.5), Event::ConfigChanged(new.clone())]
+        );
+        assert_eq!(Event::config_changes(&new, &new), vec![Event::ConfigChanged(new.clone())]);
+    }
+}
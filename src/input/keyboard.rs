@@ -1,33 +1,57 @@
 use anyhow::Result;
 use rdev::{listen, Event, EventType, Key};
 use std::sync::Arc;
-use log::{error, info};
-use std::collections::HashSet;
+use std::time::{Instant, SystemTime};
+use log::{debug, error, info};
 use parking_lot::Mutex;
 
-use crate::audio::SoundEngine;
+use super::pressed::PressedKeys;
+use super::secure_input::{self, SecureInputDetector};
+use super::session::{SessionEvent, SessionRecorder};
+use crate::audio::{SoundEngine, SoundEvent};
+use crate::config::{Config, SecureInputMode};
+use crate::events::Event as BusEvent;
+use crate::logging::Redact;
 
 pub struct KeyboardHandler {
     sound_engine: Arc<SoundEngine>,
-    pressed_keys: Arc<Mutex<HashSet<Key>>>,
+    pressed_keys: Arc<Mutex<PressedKeys>>,
+    recorder: Option<Arc<Mutex<SessionRecorder>>>,
+    secure_input: Arc<dyn SecureInputDetector>,
 }
 
 impl KeyboardHandler {
     pub fn new(sound_engine: Arc<SoundEngine>) -> Result<Self> {
         Ok(Self {
             sound_engine,
-            pressed_keys: Arc::new(Mutex::new(HashSet::new())),
+            pressed_keys: Arc::new(Mutex::new(PressedKeys::default())),
+            recorder: None,
+            secure_input: secure_input::system_detector(),
         })
     }
 
+    /// Also writes every key event the listener sees to `recorder`, before held keys
+    /// are filtered, so a replay goes through the same filtering
+    pub fn record_to(mut self, recorder: SessionRecorder) -> Self {
+        self.recorder = Some(Arc::new(Mutex::new(recorder)));
+        self
+    }
+
     pub fn start(&self) -> Result<()> {
         let sound_engine = self.sound_engine.clone();
         let pressed_keys = self.pressed_keys.clone();
+        let recorder = self.recorder.clone();
+        let secure_input = self.secure_input.clone();
         info!("Starting keyboard listener...");
         
         std::thread::spawn(move || {
             if let Err(error) = listen(move |event| {
-                Self::callback(event, &sound_engine, &pressed_keys);
+                let secure = secure_input.is_active();
+                // Passwords don't end up in session files either
+                if let Some(recorder) = recorder.as_ref().filter(|_| !secure) {
+                    Self::record(&event, recorder);
+                }
+                Self::callback(event, &sound_engine, &pressed_keys, secure, true);
             }) {
                 error!("Failed to listen for keyboard events: {:?}", error);
             }
@@ -36,46 +60,111 @@ impl KeyboardHandler {
         Ok(())
     }
 
-    fn callback(event: Event, sound_engine: &SoundEngine, pressed_keys: &Arc<Mutex<HashSet<Key>>>) {
+    pub fn sound_engine(&self) -> &Arc<SoundEngine> {
+        &self.sound_engine
+    }
+
+    /// Keys held down right now, for the TUI's key visualizer
+    #[cfg(feature = "tui")]
+    pub fn pressed_keys(&self) -> Vec<Key> {
+        let mut keys: Vec<Key> = self.pressed_keys.lock().iter().copied().collect();
+        keys.sort_by_key(|key| format!("{:?}", key));
+        keys
+    }
+
+    #[cfg(feature = "tui")]
+    pub fn secure_input_active(&self) -> bool {
+        self.secure_input.is_active()
+    }
+
+    /// Feeds a recorded session through the same callback as live events, blocking until
+    /// the last one. `speed` 2.0 plays it twice as fast. Replays aren't published as key
+    /// events, so they don't count towards typing stats.
+    pub fn replay(&self, events: &[SessionEvent], speed: f64) {
+        let started = Instant::now();
+        for event in events {
+            if let Some(wait) = event.at().div_f64(speed).checked_sub(started.elapsed()) {
+                std::thread::sleep(wait);
+            }
+            let secure = self.secure_input.is_active();
+            Self::callback(key_event(event.key, event.press), &self.sound_engine, &self.pressed_keys, secure, false);
+        }
+    }
+
+    /// Plays a key event the app picked up itself, e.g. in its own window, with the same
+    /// filtering as the system wide listener. These are published like live ones.
+    pub fn handle(&self, key: Key, is_press: bool) {
+        Self::callback(key_event(key, is_press), &self.sound_engine, &self.pressed_keys, self.secure_input.is_active(), true);
+    }
+
+    fn record(event: &Event, recorder: &Mutex<SessionRecorder>) {
+        let result = match event.event_type {
+            EventType::KeyPress(key) => recorder.lock().record(key, true),
+            EventType::KeyRelease(key) => recorder.lock().record(key, false),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            error!("Failed to record key event: {:#}", e);
+        }
+    }
+
+    /// What to play for a key, if anything. While secure input is on, the key is either
+    /// kept quiet or masked, depending on the config.
+    fn sound_event(key: Key, is_press: bool, secure_input: bool, config: &Arc<Config>) -> Option<SoundEvent> {
+        if !config.enabled {
+            return None;
+        }
+        if !secure_input {
+            return Some(SoundEvent::new(Some(key), is_press, config));
+        }
+        match config.secure_input {
+            SecureInputMode::Silent => None,
+            SecureInputMode::Masked => Some(SoundEvent::masked(is_press, config)),
+        }
+    }
+
+    /// Tells subscribers about a key, unless a password is being typed
+    fn publish(sound_engine: &SoundEngine, key: Key, is_press: bool, secure_input: bool) {
+        if !secure_input {
+            sound_engine.settings().events().publish(BusEvent::Key { key, is_press });
+        }
+    }
+
+    fn callback(
+        event: Event,
+        sound_engine: &SoundEngine,
+        pressed_keys: &Mutex<PressedKeys>,
+        // Read once per event by the caller, so the sound and the bus agree
+        secure_input: bool,
+        publish: bool,
+    ) {
+        let arrived = Instant::now();
         match event.event_type {
             EventType::KeyPress(key) => {
-                // Only play sound if the key wasn't already pressed
-                let should_play = {
-                    let mut keys = pressed_keys.lock();
-                    if !keys.contains(&key) {
-                        keys.insert(key);
-                        true
-                    } else {
-                        false
-                    }
-                };
-
+                // Held keys repeat their press
+                let should_play = pressed_keys.lock().update(key, true);
                 if should_play {
-                    info!("Key pressed: {:?}", key);
-                    let enabled = {
-                        let app_state = crate::APP_STATE.lock();
-                        app_state.enabled
-                    };
-                    if enabled {
-                        sound_engine.play_sound(Some(key), true);
+                    debug!("Key pressed: {}", Redact(key));
+                    let sound = Self::sound_event(key, true, secure_input, &sound_engine.settings().current());
+                    if let Some(sound) = sound {
+                        sound_engine.play(sound.arrived_at(arrived));
+                    }
+                    if publish {
+                        Self::publish(sound_engine, key, true, secure_input);
                     }
                 }
             }
             EventType::KeyRelease(key) => {
                 // Only play sound if we had registered this key as pressed
-                let should_play = {
-                    let mut keys = pressed_keys.lock();
-                    keys.remove(&key)
-                };
-
+                let should_play = pressed_keys.lock().update(key, false);
                 if should_play {
-                    info!("Key released: {:?}", key);
-                    let enabled = {
-                        let app_state = crate::APP_STATE.lock();
-                        app_state.enabled
-                    };
-                    if enabled {
-                        sound_engine.play_sound(Some(key), false);
+                    debug!("Key released: {}", Redact(key));
+                    let sound = Self::sound_event(key, false, secure_input, &sound_engine.settings().current());
+                    if let Some(sound) = sound {
+                        sound_engine.play(sound.arrived_at(arrived));
+                    }
+                    if publish {
+                        Self::publish(sound_engine, key, false, secure_input);
                     }
                 }
             }
@@ -84,10 +173,40 @@ impl KeyboardHandler {
     }
 }
 
+fn key_event(key: Key, is_press: bool) -> Event {
+    let event_type = if is_press { EventType::KeyPress(key) } else { EventType::KeyRelease(key) };
+    Event { event_type, name: None, time: SystemTime::now() }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::audio::buffer::{BufferBackend, NullBackend};
+    use crate::audio::fixtures::test_pack;
+    use crate::settings::Settings;
     use std::thread;
+    use std::time::Duration;
+
+    fn sound_engine() -> Arc<SoundEngine> {
+        Arc::new(SoundEngine::with_backend(Settings::default(), Box::new(NullBackend), test_pack("keyboard")).unwrap())
+    }
+
+    /// An engine playing the `test` pack into a recording
+    fn recording_engine(name: &str) -> (Arc<SoundEngine>, BufferBackend) {
+        let backend = BufferBackend::new();
+        let settings = Settings::new(Config { switch_type: "test".into(), ..Default::default() });
+        let engine = SoundEngine::with_backend(settings, Box::new(backend.clone()), test_pack(name)).unwrap();
+        (Arc::new(engine), backend)
+    }
+
+    /// The samples played once `count` have come in, or after a few seconds
+    fn played(backend: &BufferBackend, count: usize) -> Vec<String> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while backend.triggers().len() < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        backend.triggers().into_iter().map(|trigger| trigger.sample).collect()
+    }
 
     fn create_test_event(key: rdev::Key, is_press: bool) -> Event {
         Event {
@@ -103,63 +222,136 @@ mod tests {
 
     #[test]
     fn test_keyboard_handler_creation() {
-        let sound_engine = Arc::new(SoundEngine::new().unwrap());
+        let sound_engine = sound_engine();
         assert!(KeyboardHandler::new(sound_engine).is_ok());
     }
 
     #[test]
     fn test_keyboard_handler_start() {
-        let sound_engine = Arc::new(SoundEngine::new().unwrap());
+        let sound_engine = sound_engine();
         let handler = KeyboardHandler::new(sound_engine).unwrap();
         assert!(handler.start().is_ok());
     }
 
     #[test]
     fn test_callback_enabled() {
-        let sound_engine = Arc::new(SoundEngine::new().unwrap());
-        let pressed_keys = Arc::new(Mutex::new(HashSet::new()));
-        
-        // Ensure app is enabled
-        {
-            let mut app_state = crate::APP_STATE.lock();
-            app_state.enabled = true;
-        }
+        let (sound_engine, backend) = recording_engine("callback-enabled");
+        let pressed_keys = Mutex::new(PressedKeys::default());
+        let callback = |key, is_press| {
+            KeyboardHandler::callback(create_test_event(key, is_press), &sound_engine, &pressed_keys, false, false);
+        };
 
-        // Test normal key press and release sequence
-        KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, true), &sound_engine, &pressed_keys);
-        KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, false), &sound_engine, &pressed_keys);
+        // Normal key press and release sequence
+        callback(Key::KeyA, true);
+        callback(Key::KeyA, false);
 
-        // Test holding a key (second press should not trigger sound)
-        KeyboardHandler::callback(create_test_event(rdev::Key::Space, true), &sound_engine, &pressed_keys);
-        KeyboardHandler::callback(create_test_event(rdev::Key::Space, true), &sound_engine, &pressed_keys); // Should not play
-        KeyboardHandler::callback(create_test_event(rdev::Key::Space, false), &sound_engine, &pressed_keys);
+        // Holding a key: the repeated press plays nothing
+        callback(Key::Space, true);
+        callback(Key::Space, true);
+        callback(Key::Space, false);
 
-        // Test multiple keys
-        KeyboardHandler::callback(create_test_event(rdev::Key::Return, true), &sound_engine, &pressed_keys);
-        KeyboardHandler::callback(create_test_event(rdev::Key::KeyB, true), &sound_engine, &pressed_keys);
-        KeyboardHandler::callback(create_test_event(rdev::Key::Return, false), &sound_engine, &pressed_keys);
-        KeyboardHandler::callback(create_test_event(rdev::Key::KeyB, false), &sound_engine, &pressed_keys);
+        // Overlapping keys
+        callback(Key::Return, true);
+        callback(Key::KeyB, true);
+        callback(Key::Return, false);
+        callback(Key::KeyB, false);
+
+        assert_eq!(played(&backend, 8), vec![
+            "test/press/GENERIC_R2.wav",
+            "test/release/GENERIC.wav",
+            "test/press/GENERIC_R4.wav",
+            "test/release/GENERIC.wav",
+            "test/press/GENERIC_R2.wav",
+            "test/press/GENERIC_R3.wav",
+            "test/release/GENERIC.wav",
+            "test/release/GENERIC.wav",
+        ]);
     }
 
     #[test]
     fn test_callback_disabled() {
-        let sound_engine = Arc::new(SoundEngine::new().unwrap());
-        let pressed_keys = Arc::new(Mutex::new(HashSet::new()));
-        
-        // Disable app
-        {
-            let mut app_state = crate::APP_STATE.lock();
-            app_state.enabled = false;
+        let (sound_engine, backend) = recording_engine("callback-disabled");
+        let pressed_keys = Mutex::new(PressedKeys::default());
+        let callback = |key, is_press| {
+            KeyboardHandler::callback(create_test_event(key, is_press), &sound_engine, &pressed_keys, false, false);
+        };
+
+        sound_engine.settings().update(|state| state.enabled = false);
+        callback(Key::KeyA, true);
+        callback(Key::KeyA, false);
+
+        // Sounds play in order, so once this one is in, nothing came before it
+        sound_engine.settings().update(|state| state.enabled = true);
+        callback(Key::KeyQ, true);
+        assert_eq!(played(&backend, 1), vec!["test/press/GENERIC_R1.wav"]);
+    }
+
+    #[test]
+    fn test_keys_are_published() {
+        let sound_engine = sound_engine();
+        let events = sound_engine.settings().events().subscribe();
+        let pressed_keys = Mutex::new(PressedKeys::default());
+        let callback = |key, is_press, secure_input, publish| {
+            let event = create_test_event(key, is_press);
+            KeyboardHandler::callback(event, &sound_engine, &pressed_keys, secure_input, publish);
+        };
+
+        callback(Key::KeyA, true, false, true);
+        callback(Key::KeyA, true, false, true);
+        callback(Key::KeyA, false, false, true);
+        // Passwords and replays stay off the bus
+        callback(Key::KeyB, true, true, true);
+        callback(Key::KeyC, true, false, false);
+
+        assert_eq!(events.try_recv(), Some(BusEvent::Key { key: Key::KeyA, is_press: true }));
+        assert_eq!(events.try_recv(), Some(BusEvent::Key { key: Key::KeyA, is_press: false }));
+        assert_eq!(events.try_recv(), None);
+    }
+
+    fn sample(key: Key, is_press: bool, secure_input: bool, config: &Config) -> Option<String> {
+        KeyboardHandler::sound_event(key, is_press, secure_input, &Arc::new(config.clone())).map(|event| event.sample())
+    }
+
+    #[test]
+    fn test_secure_input_silences_keys() {
+        let config = Config { switch_type: "topre".into(), ..Default::default() };
+        assert_eq!(sample(Key::KeyQ, true, false, &config), Some("topre/press/GENERIC_R1".to_string()));
+        assert_eq!(sample(Key::KeyQ, true, true, &config), None);
+        assert_eq!(sample(Key::KeyQ, false, true, &config), None);
+    }
+
+    #[test]
+    fn test_secure_input_masks_keys() {
+        let config = Config { switch_type: "topre".into(), secure_input: SecureInputMode::Masked, ..Default::default() };
+        for (key, is_press) in [(Key::KeyQ, true), (Key::Space, true), (Key::KeyQ, false), (Key::Num1, false)] {
+            assert_eq!(sample(key, is_press, true, &config), Some("topre/press/GENERIC_R2".to_string()));
         }
 
-        // Test callback while disabled
-        KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, true), &sound_engine, &pressed_keys);
-        KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, false), &sound_engine, &pressed_keys);
+        let disabled = Config { enabled: false, ..config };
+        assert_eq!(sample(Key::KeyQ, true, true, &disabled), None);
+    }
+
+    #[test]
+    fn test_replay_goes_through_callback() {
+        let handler = KeyboardHandler::new(sound_engine()).unwrap();
+        let events = [
+            SessionEvent { t_us: 0, key: rdev::Key::KeyA, press: true },
+            SessionEvent { t_us: 50_000, key: rdev::Key::KeyA, press: true },
+            SessionEvent { t_us: 100_000, key: rdev::Key::KeyB, press: true },
+            SessionEvent { t_us: 200_000, key: rdev::Key::KeyB, press: false },
+        ];
+
+        // At four times the speed, with the held key repeat filtered like a live one
+        let started = Instant::now();
+        handler.replay(&events, 4.0);
+        assert!(started.elapsed() >= std::time::Duration::from_millis(50));
+        assert!(started.elapsed() < std::time::Duration::from_millis(200));
+        assert_eq!(handler.pressed_keys.lock().iter().collect::<Vec<_>>(), vec![&rdev::Key::KeyA]);
     }
 
     #[test]
     fn test_thread_safety() {
-        let sound_engine = Arc::new(SoundEngine::new().unwrap());
+        let sound_engine = sound_engine();
         let handler = KeyboardHandler::new(sound_engine.clone()).unwrap();
         
         // Start the handler
@@ -170,8 +362,8 @@ mod tests {
             let engine = sound_engine.clone();
             let pressed_keys = handler.pressed_keys.clone();
             thread::spawn(move || {
-                KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, true), &engine, &pressed_keys);
-                KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, false), &engine, &pressed_keys);
+                KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, true), &engine, &pressed_keys, false, false);
+                KeyboardHandler::callback(create_test_event(rdev::Key::KeyA, false), &engine, &pressed_keys, false, false);
             })
         }).collect();
 
@@ -1,2 +1,6 @@
 mod keyboard;
-pub use keyboard::KeyboardHandler; 
\ No newline at end of file
+mod pressed;
+pub mod secure_input;
+pub mod session;
+pub use keyboard::KeyboardHandler;
+pub use pressed::PressedKeys; 
\ No newline at end of file
@@ -0,0 +1,38 @@
+use rdev::Key;
+use std::collections::HashSet;
+
+/// The keys held down. Keyboards repeat the press of a held key, and a release can come
+/// in for a key that went down before listening started; neither plays a sound.
+#[derive(Debug, Default)]
+pub struct PressedKeys(HashSet<Key>);
+
+impl PressedKeys {
+    /// Notes a key going down or up. False when it changes nothing, so it plays nothing.
+    pub fn update(&mut self, key: Key, is_press: bool) -> bool {
+        if is_press {
+            self.0.insert(key)
+        } else {
+            self.0.remove(&key)
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Key> {
+        self.0.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_and_stray_releases_change_nothing() {
+        let mut pressed = PressedKeys::default();
+        assert!(pressed.update(Key::KeyA, true));
+        assert!(!pressed.update(Key::KeyA, true));
+        assert!(!pressed.update(Key::KeyB, false));
+        assert_eq!(pressed.iter().collect::<Vec<_>>(), vec![&Key::KeyA]);
+        assert!(pressed.update(Key::KeyA, false));
+        assert!(pressed.update(Key::KeyA, true));
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+/// Tells whether the system is taking a password right now. Support for another
+/// platform is one more implementation, picked in `system_detector`.
+pub trait SecureInputDetector: Send + Sync {
+    fn is_active(&self) -> bool;
+}
+
+/// Secure event input, which macOS turns on for password fields and some terminals
+#[cfg(target_os = "macos")]
+pub struct MacSecureInput;
+
+#[cfg(target_os = "macos")]
+#[link(name = "Carbon", kind = "framework")]
+extern "C" {
+    fn IsSecureEventInputEnabled() -> u8;
+}
+
+#[cfg(target_os = "macos")]
+impl SecureInputDetector for MacSecureInput {
+    fn is_active(&self) -> bool {
+        unsafe { IsSecureEventInputEnabled() != 0 }
+    }
+}
+
+/// For platforms that can't tell
+#[cfg(not(target_os = "macos"))]
+pub struct NoSecureInput;
+
+#[cfg(not(target_os = "macos"))]
+impl SecureInputDetector for NoSecureInput {
+    fn is_active(&self) -> bool {
+        false
+    }
+}
+
+pub fn system_detector() -> Arc<dyn SecureInputDetector> {
+    #[cfg(target_os = "macos")]
+    return Arc::new(MacSecureInput);
+    #[cfg(not(target_os = "macos"))]
+    return Arc::new(NoSecureInput);
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use rdev::Key;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// One key event of a typing session, stored as a line of JSON
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SessionEvent {
+    /// Microseconds since the session started
+    pub t_us: u64,
+    pub key: Key,
+    pub press: bool,
+}
+
+impl SessionEvent {
+    pub fn at(&self) -> Duration {
+        Duration::from_micros(self.t_us)
+    }
+}
+
+/// Reads a session file, one event per line. Blank lines are skipped.
+pub fn load(path: &Path) -> Result<Vec<SessionEvent>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {:?}", path))?;
+    parse(&contents).with_context(|| format!("Invalid session file {:?}", path))
+}
+
+/// Appends key events to a session file as they happen, timed from when it was created.
+/// The keys in order are everything that was typed, so the file is only readable by its
+/// owner.
+pub struct SessionRecorder {
+    file: File,
+    started: Instant,
+}
+
+impl SessionRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to create {:?}", path))?;
+        // The mode only applies to new files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        Ok(Self { file, started: Instant::now() })
+    }
+
+    /// Writes the event straight to disk, so a session stopped with Ctrl-C is complete
+    pub fn record(&mut self, key: Key, press: bool) -> Result<()> {
+        let event = SessionEvent { t_us: self.started.elapsed().as_micros() as u64, key, press };
+        let mut line = serde_json::to_string(&event)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+fn parse(contents: &str) -> Result<Vec<SessionEvent>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| serde_json::from_str(line).with_context(|| format!("line {}", index + 1)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let events = parse("{\"t_us\":0,\"key\":\"KeyA\",\"press\":true}\n\n{\"t_us\":81000,\"key\":{\"Unknown\":179},\"press\":false}\n").unwrap();
+        assert_eq!(events, vec![
+            SessionEvent { t_us: 0, key: Key::KeyA, press: true },
+            SessionEvent { t_us: 81000, key: Key::Unknown(179), press: false },
+        ]);
+        assert_eq!(events[1].at(), Duration::from_millis(81));
+    }
+
+    #[test]
+    fn test_recorded_session_loads() {
+        let path = std::env::temp_dir().join(format!("clickclack-session-{}.jsonl", std::process::id()));
+        let mut recorder = SessionRecorder::create(&path).unwrap();
+        recorder.record(Key::KeyA, true).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        recorder.record(Key::KeyA, false).unwrap();
+
+        let events = load(&path).unwrap();
+        let keys: Vec<(Key, bool)> = events.iter().map(|event| (event.key, event.press)).collect();
+        assert_eq!(keys, vec![(Key::KeyA, true), (Key::KeyA, false)]);
+        assert!(events[1].at() - events[0].at() >= Duration::from_millis(5));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_session_file_is_private() {
+        let path = std::env::temp_dir().join(format!("clickclack-session-private-{}.jsonl", std::process::id()));
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        SessionRecorder::create(&path).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_error_names_line() {
+        let error = parse("{\"t_us\":0,\"key\":\"KeyA\",\"press\":true}\n{\"t_us\":1}\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2");
+    }
+}
@@ -0,0 +1,35 @@
+use anyhow::{Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+
+use super::protocol::{Request, Response};
+
+/// Sends a request to the running instance and prints each JSON reply line to stdout.
+/// For `subscribe-events` this keeps printing events until the server goes away.
+pub fn run(request: Request) -> Result<()> {
+    let path = super::socket_path();
+    let mut stream = UnixStream::connect(&path)
+        .with_context(|| format!("Failed to connect to {:?}. Is ClickClack running?", path))?;
+
+    let subscribe = request == Request::SubscribeEvents;
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+
+    let reader = BufReader::new(stream);
+    for line in reader.lines() {
+        let line = line?;
+        match serde_json::from_str::<Response>(&line)? {
+            Response::Error { message } => anyhow::bail!(message),
+            // The subscription acknowledgement carries nothing worth printing
+            Response::Ok if subscribe => continue,
+            _ => println!("{}", line),
+        }
+
+        if !subscribe {
+            break;
+        }
+    }
+
+    Ok(())
+}
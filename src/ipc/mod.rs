@@ -0,0 +1,16 @@
+pub mod client;
+pub mod protocol;
+pub mod server;
+
+pub use protocol::Request;
+
+use std::path::PathBuf;
+
+/// Location of the control socket. Uses the XDG runtime dir where there is one
+/// and the per-user temp dir otherwise (macOS).
+pub fn socket_path() -> PathBuf {
+    dirs::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("clickclack")
+        .join("clickclack.sock")
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+
+use crate::audio::latency::StageSummary;
+use crate::config::Config;
+use crate::packs::Pack;
+
+/// A single line of JSON sent by a client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+pub enum Request {
+    GetState,
+    SetVolume { volume: f32 },
+    SetSwitch { switch_type: String },
+    Toggle,
+    ListPacks,
+    PlayTest,
+    SubscribeEvents,
+    GetLatency,
+}
+
+/// A single line of JSON sent back by the server, either as a reply or as a
+/// pushed event on a subscribed connection
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Response {
+    Ok,
+    State(State),
+    Packs { packs: Vec<Pack> },
+    StateChanged(State),
+    /// `underruns` counts the times the sound card ran out of audio since start
+    Latency {
+        stages: Vec<StageSummary>,
+        #[serde(default)]
+        underruns: u64,
+    },
+    Error { message: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub enabled: bool,
+    pub volume: f32,
+    pub switch_type: String,
+}
+
+impl From<&Config> for State {
+    fn from(config: &Config) -> Self {
+        Self {
+            enabled: config.enabled,
+            volume: config.volume,
+            switch_type: config.switch_type.clone(),
+        }
+    }
+}
+
+impl Response {
+    pub fn error(message: impl Into<String>) -> Self {
+        Response::Error { message: message.into() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::latency::Stage;
+
+    #[test]
+    fn test_request_wire_format() {
+        let request: Request = serde_json::from_str(r#"{"cmd":"set-volume","volume":0.5}"#).unwrap();
+        assert_eq!(request, Request::SetVolume { volume: 0.5 });
+
+        let request: Request = serde_json::from_str(r#"{"cmd":"subscribe-events"}"#).unwrap();
+        assert_eq!(request, Request::SubscribeEvents);
+
+        let line = serde_json::to_string(&Request::SetSwitch { switch_type: "topre".into() }).unwrap();
+        assert_eq!(line, r#"{"cmd":"set-switch","switch_type":"topre"}"#);
+    }
+
+    #[test]
+    fn test_unknown_request_is_rejected() {
+        assert!(serde_json::from_str::<Request>(r#"{"cmd":"format-disk"}"#).is_err());
+    }
+
+    #[test]
+    fn test_response_wire_format() {
+        let state = State { enabled: true, volume: 0.25, switch_type: "mxblue".into() };
+        let line = serde_json::to_string(&Response::State(state.clone())).unwrap();
+        assert_eq!(line, r#"{"type":"state","enabled":true,"volume":0.25,"switch_type":"mxblue"}"#);
+        assert_eq!(serde_json::from_str::<Response>(&line).unwrap(), Response::State(state));
+
+        let stages = vec![StageSummary { stage: Stage::Total, count: 1, p50_us: Some(1024), p99_us: Some(1024), buckets: vec![] }];
+        let line = serde_json::to_string(&Response::Latency { stages, underruns: 2 }).unwrap();
+        assert_eq!(
+            line,
+            r#"{"type":"latency","stages":[{"stage":"total","count":1,"p50_us":1024,"p99_us":1024,"buckets":[]}],"underruns":2}"#
+        );
+
+        let line = serde_json::to_string(&Response::error("nope")).unwrap();
+        assert_eq!(line, r#"{"type":"error","message":"nope"}"#);
+    }
+}
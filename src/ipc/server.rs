@@ -0,0 +1,263 @@
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::protocol::{Request, Response, State};
+use crate::audio::gain::MAX_VOLUME;
+use crate::audio::SoundEngine;
+use crate::config::Config;
+use crate::events::{Event, Subscription};
+use crate::packs;
+use crate::settings::Settings;
+
+/// How long a write to a client can block before the client is dropped, and how often a
+/// subscription checks that its client is still there
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub fn start(sound_engine: Arc<SoundEngine>) -> Result<()> {
+    let path = super::socket_path();
+    let listener = bind(&path)?;
+    info!("Control socket listening at {:?}", path);
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let sound_engine = sound_engine.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = handle_connection(stream, &sound_engine) {
+                            debug!("Control connection closed: {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Failed to accept control connection: {}", e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn bind(path: &Path) -> Result<UnixListener> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
+    }
+
+    if path.exists() {
+        // A socket that still accepts belongs to another instance; anything else is left over from a crash
+        if UnixStream::connect(path).is_ok() {
+            anyhow::bail!("Another ClickClack instance is already listening on {:?}", path);
+        }
+        fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path).with_context(|| format!("Failed to bind control socket {:?}", path))
+}
+
+fn handle_connection(stream: UnixStream, sound_engine: &SoundEngine) -> Result<()> {
+    // Ends the connection's subscription along with it
+    let closed = Arc::new(AtomicBool::new(false));
+    let result = serve_requests(stream, sound_engine, &closed);
+    closed.store(true, Ordering::Relaxed);
+    result
+}
+
+fn serve_requests(stream: UnixStream, sound_engine: &SoundEngine, closed: &Arc<AtomicBool>) -> Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let mut subscribed = false;
+
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<Request>(&line) {
+            // A second subscription would push every change twice
+            Ok(Request::SubscribeEvents) if subscribed => {
+                write_response(&mut writer, &Response::error("Already subscribed to events"))?;
+            }
+            Ok(Request::SubscribeEvents) => {
+                subscribed = true;
+                write_response(&mut writer, &Response::Ok)?;
+                let settings = sound_engine.settings().clone();
+                let changes = settings.events().subscribe();
+                let mut stream = writer.try_clone()?;
+                let closed = closed.clone();
+                std::thread::spawn(move || {
+                    if let Err(e) = push_changes(&mut stream, changes, &settings, &closed) {
+                        debug!("Dropping subscribed control client: {}", e);
+                        let _ = stream.shutdown(Shutdown::Both);
+                    }
+                });
+            }
+            Ok(request) => write_response(&mut writer, &handle_request(request, sound_engine))?,
+            Err(e) => write_response(&mut writer, &Response::error(format!("Invalid request: {}", e)))?,
+        }
+    }
+
+    Ok(())
+}
+
+pub fn handle_request(request: Request, sound_engine: &SoundEngine) -> Response {
+    match request {
+        Request::GetState => Response::State(State::from(&*sound_engine.settings().current())),
+        Request::SetVolume { volume } => {
+            if !(0.0..=MAX_VOLUME).contains(&volume) {
+                return Response::error(format!("Volume must be between 0.0 and {}, got {}", MAX_VOLUME, volume));
+            }
+            update_state(sound_engine, |state| state.volume = volume)
+        }
+        Request::SetSwitch { switch_type } => {
+            match packs::list_packs() {
+                Ok(packs) if packs.iter().any(|pack| pack.folder == switch_type) => {}
+                Ok(_) => return Response::error(format!("Unknown switch type: {}", switch_type)),
+                Err(e) => return Response::error(e.to_string()),
+            }
+            update_state(sound_engine, |state| state.switch_type = switch_type)
+        }
+        Request::Toggle => update_state(sound_engine, |state| state.enabled = !state.enabled),
+        Request::ListPacks => match packs::list_packs() {
+            Ok(packs) => Response::Packs { packs },
+            Err(e) => Response::error(e.to_string()),
+        },
+        Request::PlayTest => {
+            sound_engine.play_sound(None, true);
+            Response::Ok
+        }
+        // Subscriptions need the connection and are set up in `handle_connection`
+        Request::SubscribeEvents => Response::Ok,
+        Request::GetLatency => Response::Latency {
+            stages: sound_engine.latency().summary(),
+            underruns: sound_engine.counters().underruns(),
+        },
+    }
+}
+
+fn update_state(sound_engine: &SoundEngine, apply: impl FnOnce(&mut Config)) -> Response {
+    Response::State(State::from(&*sound_engine.settings().update(apply)))
+}
+
+/// Pushes every settings change to a subscribed client until it goes away. The client
+/// reads from its own bounded queue, so one that stops reading only misses changes and
+/// holds up nobody else; after `WRITE_TIMEOUT` it is dropped.
+fn push_changes(stream: &mut UnixStream, mut changes: Subscription, settings: &Settings, closed: &AtomicBool) -> Result<()> {
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    while !closed.load(Ordering::Relaxed) {
+        let event = match changes.recv_timeout(WRITE_TIMEOUT) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        // Resend the settings in case a change was among the missed events
+        if matches!(event, Event::ConfigChanged(_)) || changes.missed() {
+            let state = State::from(&*settings.current());
+            write_response(stream, &Response::StateChanged(state))?;
+        }
+    }
+    Ok(())
+}
+
+fn write_response(stream: &mut UnixStream, response: &Response) -> Result<()> {
+    let mut line = serde_json::to_string(response)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::buffer::NullBackend;
+    use std::time::Instant;
+
+    fn sound_engine() -> SoundEngine {
+        SoundEngine::with_backend(Settings::default(), Box::new(NullBackend), packs::switchtypes_dir()).unwrap()
+    }
+
+    fn volume(sound_engine: &SoundEngine) -> f32 {
+        sound_engine.settings().current().volume
+    }
+
+    #[test]
+    fn test_volume_must_be_in_range() {
+        let sound_engine = sound_engine();
+        for volume in [-0.1, MAX_VOLUME + 0.1] {
+            let response = handle_request(Request::SetVolume { volume }, &sound_engine);
+            assert!(matches!(response, Response::Error { .. }));
+        }
+        assert_eq!(volume(&sound_engine), 1.0);
+
+        let response = handle_request(Request::SetVolume { volume: 0.5 }, &sound_engine);
+        assert!(matches!(response, Response::State(State { volume, .. }) if volume == 0.5));
+        assert_eq!(volume(&sound_engine), 0.5);
+    }
+
+    #[test]
+    fn test_unknown_switch_is_rejected() {
+        let sound_engine = sound_engine();
+        let response = handle_request(Request::SetSwitch { switch_type: "not-a-pack".into() }, &sound_engine);
+        assert!(matches!(response, Response::Error { .. }));
+        assert_eq!(sound_engine.settings().current().switch_type, "mxblue");
+    }
+
+    #[test]
+    fn test_toggle() {
+        let sound_engine = sound_engine();
+        for enabled in [false, true] {
+            let response = handle_request(Request::Toggle, &sound_engine);
+            assert!(matches!(response, Response::State(state) if state.enabled == enabled));
+        }
+    }
+
+    #[test]
+    fn test_second_subscribe_is_refused() {
+        let sound_engine = sound_engine();
+        let settings = sound_engine.settings().clone();
+        let (mut client, stream) = UnixStream::pair().unwrap();
+        std::thread::spawn(move || serve_requests(stream, &sound_engine, &Arc::new(AtomicBool::new(false))));
+        let mut replies = BufReader::new(client.try_clone().unwrap()).lines();
+        let mut reply = || serde_json::from_str::<Response>(&replies.next().unwrap().unwrap()).unwrap();
+
+        for _ in 0..2 {
+            client.write_all(b"{\"cmd\":\"subscribe-events\"}\n").unwrap();
+        }
+        assert!(matches!(reply(), Response::Ok));
+        assert!(matches!(reply(), Response::Error { .. }));
+
+        // Every change comes once, so the reply after the second change is about that one
+        settings.update(|config| config.volume = 0.5);
+        assert!(matches!(reply(), Response::StateChanged(State { volume, .. }) if volume == 0.5));
+        settings.update(|config| config.volume = 0.25);
+        assert!(matches!(reply(), Response::StateChanged(State { volume, .. }) if volume == 0.25));
+    }
+
+    #[test]
+    fn test_stalled_subscriber_is_dropped() {
+        let settings = Settings::default();
+        let (_client, mut stream) = UnixStream::pair().unwrap();
+        let changes = settings.events().subscribe();
+        let pusher = {
+            let settings = settings.clone();
+            std::thread::spawn(move || push_changes(&mut stream, changes, &settings, &AtomicBool::new(false)))
+        };
+
+        // The client never reads, so its socket buffer fills up and the writes time out
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !pusher.is_finished() && Instant::now() < deadline {
+            settings.update(|config| config.volume = 1.0 - config.volume);
+        }
+        assert!(pusher.join().unwrap().is_err());
+    }
+}
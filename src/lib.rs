@@ -0,0 +1,35 @@
+//! Mechanical keyboard sounds for any Rust app.
+//!
+//! [`ClickClack`] owns the settings and the engine playing them. Feed it the key events
+//! your app already sees, or let it listen system wide like the ClickClack app does:
+//!
+//! ```no_run
+//! use clickclack::{ClickClack, Config, Key};
+//!
+//! let clickclack = ClickClack::new(Config { switch_type: "topre".into(), ..Default::default() })?;
+//! clickclack.key(Key::KeyA, true);
+//! clickclack.key(Key::KeyA, false);
+//! clickclack.update(|config| config.volume = 0.5);
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Packs are folders below [`packs::switchtypes_dir`]; [`packs::ensure_assets_exist`]
+//! installs the bundled ones and [`packs::list_packs`] lists what is there. Key events
+//! are [`rdev`] keys, and recorded typing is a list of [`input::session::SessionEvent`]s
+//! that [`audio::render::render`] can mix offline.
+
+mod app;
+pub mod audio;
+pub mod config;
+pub mod events;
+pub mod input;
+pub mod logging;
+pub mod packs;
+pub mod settings;
+pub mod stats;
+
+pub use app::ClickClack;
+pub use audio::{SoundEngine, SoundEvent};
+pub use config::Config;
+pub use rdev::Key;
+pub use settings::Settings;
@@ -0,0 +1,151 @@
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Size at which the log file is rotated
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+/// Rotated files kept next to the log, `clickclack.log.1` being the newest
+const KEPT_LOGS: usize = 3;
+
+static LOG_KEYS: AtomicBool = AtomicBool::new(false);
+
+/// Where commands that draw on the terminal log to
+pub fn default_log_file() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("clickclack")
+        .join("clickclack.log")
+}
+
+/// Sets up logging at info level, or debug with `log_keys`. `RUST_LOG` still overrides
+/// the level, but key identities only show up with `log_keys`.
+pub fn init(log_keys: bool, log_file: Option<&Path>) -> Result<()> {
+    LOG_KEYS.store(log_keys, Ordering::Relaxed);
+
+    let default_level = if log_keys { "debug" } else { "info" };
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default()
+        .filter_or("RUST_LOG", default_level));
+    builder.format(|buf, record| {
+        writeln!(buf, "[{}] {}", record.level(), record.args())
+    });
+    if let Some(path) = log_file {
+        let file = RotatingFile::open(path, MAX_LOG_BYTES)
+            .with_context(|| format!("Failed to open log file {:?}", path))?;
+        builder.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    builder.init();
+    Ok(())
+}
+
+/// Logs a key, or anything that gives one away such as its sample, only when key logging
+/// was opted into with `--log-keys`
+pub struct Redact<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Display for Redact<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_redacted(f, &self.0, LOG_KEYS.load(Ordering::Relaxed))
+    }
+}
+
+fn write_redacted(f: &mut impl fmt::Write, value: &impl fmt::Debug, show: bool) -> fmt::Result {
+    if show {
+        write!(f, "{:?}", value)
+    } else {
+        f.write_str("<redacted>")
+    }
+}
+
+/// Appends to a log file, moving it to `.1`, `.1` to `.2` and so on once it passes
+/// `max_bytes`, and dropping the oldest
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_bytes: u64) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, size, max_bytes })
+    }
+
+    fn rotated(&self, index: usize) -> PathBuf {
+        let mut path = OsString::from(&self.path);
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for index in (1..KEPT_LOGS).rev() {
+            match fs::rename(self.rotated(index), self.rotated(index + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, self.rotated(1))?;
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_are_redacted_unless_opted_in() {
+        let mut hidden = String::new();
+        write_redacted(&mut hidden, &rdev::Key::KeyP, false).unwrap();
+        assert_eq!(hidden, "<redacted>");
+
+        let mut shown = String::new();
+        write_redacted(&mut shown, &rdev::Key::KeyP, true).unwrap();
+        assert_eq!(shown, "KeyP");
+    }
+
+    #[test]
+    fn test_log_file_is_rotated_and_capped() {
+        let dir = std::env::temp_dir().join(format!("clickclack-logs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("clickclack.log");
+        let mut file = RotatingFile::open(&path, 100).unwrap();
+        for line in 0..20 {
+            file.write_all(format!("{:040}\n", line).as_bytes()).unwrap();
+        }
+
+        // 41 byte lines, two per file, and only the newest files are kept
+        let mut names: Vec<String> = fs::read_dir(&dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["clickclack.log", "clickclack.log.1", "clickclack.log.2", "clickclack.log.3"]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{:040}\n{:040}\n", 18, 19));
+        assert!(fs::read_to_string(file.rotated(3)).unwrap().starts_with(&format!("{:040}", 12)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,27 +1,58 @@
-#[cfg(target_os = "macos")]
-use cocoa::base::nil;
-#[cfg(target_os = "macos")]
-use cocoa::appkit::{NSApplication, NSApplicationActivationPolicyAccessory};
-#[cfg(target_os = "macos")]
-use cocoa::foundation::NSAutoreleasePool;
-
-mod audio;
-mod input;
 mod ui;
-mod config;
+#[cfg(all(feature = "dbus", target_os = "linux"))]
+mod dbus;
+mod ipc;
+mod pack_build;
+mod pack_convert;
+mod platform;
 mod service;
 
 use anyhow::Result;
+use clickclack::{audio, config, events, input, logging, packs, settings, stats, ClickClack, Settings};
 use log::{info, error};
-use once_cell::sync::Lazy;
-use parking_lot::Mutex;
 use std::sync::Arc;
-use std::io::Write;
-use clap::Parser;
+use std::path::{Path, PathBuf};
+use clap::{Parser, Subcommand};
+use tao::event::Event;
+use tao::event_loop::{ControlFlow, EventLoop};
 
-static APP_STATE: Lazy<Arc<Mutex<config::Config>>> = Lazy::new(|| {
-    Arc::new(Mutex::new(config::Config::load().unwrap_or_default()))
-});
+/// Saves every change to `settings` and tells the tray and D-Bus clients about it. Control
+/// socket clients subscribe to the settings themselves. Saving on this one thread keeps the saves in the order the changes were made.
+fn watch_changes(settings: &Settings) {
+    let mut changes = settings.events().subscribe();
+    let settings = settings.clone();
+    std::thread::spawn(move || {
+        while let Some(event) = changes.recv() {
+            // A change could have been among the events dropped while this thread was behind
+            if !matches!(event, events::Event::ConfigChanged(_)) && !changes.missed() {
+                continue;
+            }
+            if let Err(e) = settings.current().save() {
+                error!("Failed to save configuration: {}", e);
+            }
+            ui::system_tray::request_refresh();
+            #[cfg(all(feature = "dbus", target_os = "linux"))]
+            dbus::emit_state_changed();
+        }
+    });
+}
+
+/// Counts typing stats from the keys played with `settings`. The returned hook saves
+/// the counts in progress; Ctrl-C and SIGTERM call it before exiting.
+fn count_stats(settings: &Settings) -> Result<ui::OnQuit> {
+    let stats = stats::count_keys(settings.events().subscribe(), settings.current().stats, stats::stats_path());
+    let on_quit: ui::OnQuit = Arc::new(move || {
+        if let Err(e) = stats.lock().save() {
+            error!("Failed to save typing stats: {:#}", e);
+        }
+    });
+    let on_signal = on_quit.clone();
+    ctrlc::set_handler(move || {
+        on_signal();
+        std::process::exit(0);
+    })?;
+    Ok(on_quit)
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -37,21 +68,485 @@ struct Cli {
     /// Restart the ClickClack service
     #[arg(long)]
     restart_service: bool,
+
+    /// Run without a tray icon, e.g. on a headless Linux box
+    #[arg(long)]
+    no_tray: bool,
+
+    /// Play into `null` or a .wav file instead of the sound card
+    #[arg(long, value_name = "null|FILE.wav")]
+    audio_output: Option<PathBuf>,
+
+    /// Debug mode: log at debug level, including which keys are pressed
+    #[arg(long)]
+    log_keys: bool,
+
+    /// Log to a file that is rotated at 1 MB instead of stderr
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<()> {
-    // Initialize logging with debug level
-    env_logger::Builder::from_env(env_logger::Env::default()
-        .filter_or("RUST_LOG", "debug"))
-        .format(|buf, record| {
-            writeln!(buf, "[{}] {}", record.level(), record.args())
-        })
-        .init();
-    info!("Starting ClickClack...");
+#[derive(Subcommand)]
+enum Command {
+    /// Control the running ClickClack instance
+    Ctl {
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// List the output devices `output_device` can be set to
+    ListDevices,
+    /// Mix a session of timestamped key events into a .wav or .flac file
+    Render {
+        /// JSON lines of `{"t_us": 81000, "key": "KeyA", "press": true}`
+        #[arg(long)]
+        events: PathBuf,
+        /// Pack folder to render with, defaults to the configured one
+        #[arg(long)]
+        pack: Option<String>,
+        /// Output file, .wav or .flac
+        #[arg(long)]
+        out: PathBuf,
+        #[arg(long, default_value_t = audio::buffer::SAMPLE_RATE)]
+        sample_rate: u32,
+    },
+    /// Play a demo phrase with a pack, or with two packs back to back
+    Preview {
+        /// Pack folder, e.g. `holypanda`
+        #[arg(required_unless_present = "compare")]
+        pack: Option<String>,
+        /// Two pack folders to compare, e.g. `mxblue,topre`
+        #[arg(long, value_delimiter = ',', conflicts_with = "pack")]
+        compare: Vec<String>,
+    },
+    /// Install a synthesized pack, starting from a preset, whose sound is tuned in its synth.toml
+    NewSynthPack {
+        /// One of alpaca, blackink, bluealps, boxnavy, buckling, cream, holypanda,
+        /// mxblack, mxblue, mxbrown, redink, topre, turquoise
+        preset: String,
+        /// Folder to create under switchtypes/, also the switch type to select
+        folder: String,
+        /// Name in the menus, defaults to the folder
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Slice a WAV recording of a switch into a new pack
+    #[command(after_help = pack_build::guide())]
+    PackBuild {
+        recording: PathBuf,
+        /// Folder to create under switchtypes/, also the switch type to select
+        #[arg(long)]
+        folder: String,
+        /// Name in the menus, defaults to the folder
+        #[arg(long)]
+        name: Option<String>,
+        /// File with one slot per press (R0-R4, SPACE, ENTER) when not following the guide
+        #[arg(long)]
+        labels: Option<PathBuf>,
+    },
+    /// Copy a pack with its samples as WAV or FLAC, at one loudness and the output's rate
+    PackConvert {
+        /// Pack folder to convert, e.g. `holypanda`
+        pack: String,
+        /// Folder to create under switchtypes/, also the switch type to select
+        #[arg(long)]
+        folder: String,
+        /// Name in the menus, defaults to the folder
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long, value_enum, default_value_t = pack_convert::Format::Flac)]
+        format: pack_convert::Format,
+        /// Loudness of the whole pack
+        #[arg(long, default_value_t = pack_convert::DEFAULT_LUFS, allow_hyphen_values = true)]
+        lufs: f64,
+        /// Defaults to the rate of the configured output device
+        #[arg(long)]
+        sample_rate: Option<u32>,
+    },
+    /// Show how late each sample of a pack starts sounding
+    LatencyReport {
+        /// Pack folder, defaults to the configured one
+        pack: Option<String>,
+    },
+    /// Type synthetic keys into a buffer instead of the sound card and report the latency
+    /// of each stage
+    BenchLatency {
+        /// Keys to type, each pressed and released
+        #[arg(long, default_value_t = 500)]
+        keys: usize,
+        /// Milliseconds between key presses
+        #[arg(long, default_value_t = 20)]
+        interval_ms: u64,
+        /// Pack folder, defaults to the configured one
+        #[arg(long)]
+        pack: Option<String>,
+    },
+    /// Time settings reads on the key path while another thread keeps changing and
+    /// saving them, with a shared mutex and with snapshots
+    BenchContention {
+        /// Reads to time
+        #[arg(long, default_value_t = 2000)]
+        reads: usize,
+        /// Microseconds between reads
+        #[arg(long, default_value_t = 500)]
+        interval_us: u64,
+    },
+    /// Play along and write every key press and release to a session file until Ctrl-C.
+    /// The file holds everything typed meanwhile.
+    Record { file: PathBuf },
+    /// Summarize the typing stats collected with `stats = true`
+    Stats {
+        #[arg(long, value_enum, default_value_t = StatsFormat::Text)]
+        format: StatsFormat,
+        /// Export per-key hit counts instead of daily summaries (CSV)
+        #[arg(long)]
+        keys: bool,
+    },
+    /// Run with a terminal interface instead of the tray
+    #[cfg(feature = "tui")]
+    Tui,
+    /// Play a recorded session back through the keyboard handler
+    Replay {
+        file: PathBuf,
+        /// 2.0 plays twice as fast
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+    },
+}
+
+impl Command {
+    /// Whether the command draws on the terminal, so logs have to go elsewhere
+    fn owns_terminal(&self) -> bool {
+        #[cfg(feature = "tui")]
+        if let Command::Tui = self {
+            return true;
+        }
+        false
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum StatsFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum CtlCommand {
+    /// Print the current state as JSON
+    GetState,
+    /// Set the volume (0.0 - 1.5, above 1.0 boosts)
+    SetVolume { volume: f32 },
+    /// Switch to an installed pack by its folder name
+    SetSwitch { switch_type: String },
+    /// Turn sound on or off
+    Toggle,
+    /// List the installed switch packs
+    ListPacks,
+    /// Play a test sound
+    PlayTest,
+    /// Print state changes as they happen
+    SubscribeEvents,
+    /// Print how long key sounds took through each stage, as JSON
+    Latency,
+}
+
+impl From<CtlCommand> for ipc::Request {
+    fn from(command: CtlCommand) -> Self {
+        match command {
+            CtlCommand::GetState => ipc::Request::GetState,
+            CtlCommand::SetVolume { volume } => ipc::Request::SetVolume { volume },
+            CtlCommand::SetSwitch { switch_type } => ipc::Request::SetSwitch { switch_type },
+            CtlCommand::Toggle => ipc::Request::Toggle,
+            CtlCommand::ListPacks => ipc::Request::ListPacks,
+            CtlCommand::PlayTest => ipc::Request::PlayTest,
+            CtlCommand::SubscribeEvents => ipc::Request::SubscribeEvents,
+            CtlCommand::Latency => ipc::Request::GetLatency,
+        }
+    }
+}
+
+fn list_devices() -> Result<()> {
+    let default = audio::device::default_device_name();
+    for device in audio::device::list_devices()? {
+        if Some(&device) == default.as_ref() {
+            println!("{} (default)", device);
+        } else {
+            println!("{}", device);
+        }
+    }
+    Ok(())
+}
+
+fn render(events: &Path, pack: Option<String>, out: &Path, sample_rate: u32) -> Result<()> {
+    let mut config = config::Config::load().unwrap_or_default();
+    if let Some(pack) = pack {
+        config.switch_type = pack;
+    }
+    let switchtypes_dir = packs::switchtypes_dir();
+    ensure_installed(&config.switch_type)?;
+
+    let events = input::session::load(events)?;
+    let recording = audio::render::render(&events, &config, &switchtypes_dir, sample_rate);
+    audio::render::save(&recording, out)?;
+    println!("Rendered {} sounds into {:?}", recording.triggers.len(), out);
+    Ok(())
+}
+
+fn ensure_installed(pack: &str) -> Result<()> {
+    let switchtypes_dir = packs::switchtypes_dir();
+    if !switchtypes_dir.join(pack).is_dir() {
+        anyhow::bail!("Pack {:?} is not installed in {:?}", pack, switchtypes_dir);
+    }
+    Ok(())
+}
+
+/// Installs the packs and starts playing into the sound card, or into `audio_output`
+fn start(audio_output: Option<&Path>) -> Result<ClickClack> {
+    if let Err(e) = packs::ensure_assets_exist() {
+        error!("Failed to initialize assets: {}", e);
+        return Err(anyhow::anyhow!("Failed to initialize assets: {}", e));
+    }
+    info!("Assets initialized successfully");
+
+    let settings = Settings::new(config::Config::load().unwrap_or_default());
+    watch_changes(&settings);
+    let clickclack = match audio_output {
+        None => ClickClack::with_settings(settings)?,
+        Some(path) if path.as_os_str() == "null" => {
+            ClickClack::with_backend(settings, Box::new(audio::buffer::NullBackend), packs::switchtypes_dir())?
+        }
+        Some(path) => {
+            let backend = audio::buffer::WavFileBackend::create(path)?;
+            ClickClack::with_backend(settings, Box::new(backend), packs::switchtypes_dir())?
+        }
+    };
+    info!("Sound engine initialized");
+    Ok(clickclack)
+}
+
+fn record(file: &Path, audio_output: Option<&Path>) -> Result<()> {
+    let recorder = input::session::SessionRecorder::create(file)?;
+    let clickclack = start(audio_output)?;
+    count_stats(clickclack.settings())?;
+    let keyboard_handler = input::KeyboardHandler::new(clickclack.sound_engine().clone())?.record_to(recorder);
+    keyboard_handler.start()?;
+    println!("Recording to {:?}, press Ctrl-C to stop", file);
+    loop {
+        std::thread::park();
+    }
+}
+
+fn replay(file: &Path, speed: f64, audio_output: Option<&Path>) -> Result<()> {
+    anyhow::ensure!(speed > 0.0, "--speed must be above 0");
+    let events = input::session::load(file)?;
+    start(audio_output)?.keyboard().replay(&events, speed);
+    // Let the last sound finish before the audio thread goes away
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    Ok(())
+}
+
+fn preview(pack: Option<String>, compare: Vec<String>, audio_output: Option<&Path>) -> Result<()> {
+    anyhow::ensure!(compare.is_empty() || compare.len() == 2, "--compare takes two packs, e.g. --compare mxblue,topre");
+    let clickclack = start(audio_output)?;
+    let sound_engine = clickclack.sound_engine();
+    let packs: Vec<String> = pack.into_iter().chain(compare).collect();
+    for pack in &packs {
+        ensure_installed(pack)?;
+    }
+
+    let names = packs::list_packs().unwrap_or_default();
+    let config = config::Config::load().unwrap_or_default();
+    for (index, pack) in packs.into_iter().enumerate() {
+        if index > 0 {
+            std::thread::sleep(std::time::Duration::from_secs(1));
+        }
+        let name = names.iter().find(|name| name.folder == pack).map_or(pack.as_str(), |name| name.name.as_str());
+        println!("Playing {}", name);
+        let config = Arc::new(config::Config { switch_type: pack.clone(), ..config.clone() });
+        audio::preview::play(sound_engine, &config);
+    }
+    // Let the last sound finish before the audio thread goes away
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    Ok(())
+}
+
+/// Where a new pack goes, after checking `folder` is free and a plain name
+fn new_pack_dir(folder: &str) -> Result<PathBuf> {
+    anyhow::ensure!(
+        !folder.is_empty() && folder.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "Folder names can only have letters, digits, - and _"
+    );
+    packs::ensure_assets_exist()?;
+    let pack_dir = packs::switchtypes_dir().join(folder);
+    anyhow::ensure!(!pack_dir.exists(), "{:?} already exists", pack_dir);
+    Ok(pack_dir)
+}
 
+fn new_synth_pack(preset: &str, folder: &str, name: Option<String>) -> Result<()> {
+    let params = audio::synth::preset(preset)?;
+    let pack_dir = new_pack_dir(folder)?;
+    packs::add_pack(name.as_deref().unwrap_or(folder), folder)?;
+    audio::synth::save(&params, &pack_dir)?;
+    println!("Created {:?}, select it with `clickclack ctl set-switch {}`", pack_dir.join(audio::synth::PARAMS_FILE), folder);
+    Ok(())
+}
+
+fn pack_build(recording: &Path, folder: &str, name: Option<String>, labels: Option<&Path>) -> Result<()> {
+    let labels = match labels {
+        Some(path) => Some(pack_build::parse_labels(&std::fs::read_to_string(path)?)?),
+        None => None,
+    };
+    let (samples, sample_rate) = pack_build::read_recording(recording)?;
+    let pack = pack_build::build(&samples, sample_rate, labels)?;
+
+    let pack_dir = new_pack_dir(folder)?;
+    let name = name.as_deref().unwrap_or(folder);
+    pack.write(&pack_dir, name, &recording.display().to_string())?;
+    packs::add_pack(name, folder)?;
+    println!("Wrote {} samples to {:?}, preview it with `clickclack preview {}`", pack.samples.len(), pack_dir, folder);
+    Ok(())
+}
+
+fn pack_convert(
+    pack: &str,
+    folder: &str,
+    name: Option<String>,
+    format: pack_convert::Format,
+    lufs: f64,
+    sample_rate: Option<u32>,
+) -> Result<()> {
+    ensure_installed(pack)?;
+    let config = config::Config::load().unwrap_or_default();
+    let sample_rate = sample_rate
+        .or_else(|| audio::device::sample_rate(config.output_device.as_deref()))
+        .unwrap_or(audio::buffer::SAMPLE_RATE);
+    let source_dir = packs::switchtypes_dir().join(pack);
+    let mut converted = pack_convert::read(&source_dir, sample_rate)?;
+    let normalized = converted.normalize(lufs)?;
+    if normalized.limited {
+        println!("{} LUFS would clip, stopped at {:.1} LUFS", lufs, converted.lufs().unwrap_or(lufs));
+    }
+
+    let pack_dir = new_pack_dir(folder)?;
+    converted.write(&source_dir, &pack_dir, format)?;
+    packs::add_pack(name.as_deref().unwrap_or(folder), folder)?;
+    println!(
+        "Wrote {} samples at {} Hz to {:?}, {:+.1} dB from {:.1} LUFS",
+        converted.clips.len(), sample_rate, pack_dir, normalized.gain_db, normalized.from_lufs
+    );
+    Ok(())
+}
+
+fn latency_report(pack: Option<String>) -> Result<()> {
+    let pack = pack.unwrap_or_else(|| config::Config::load().unwrap_or_default().switch_type);
+    ensure_installed(&pack)?;
+    print!("{}", audio::trim::report(&packs::switchtypes_dir().join(&pack))?);
+    Ok(())
+}
+
+fn bench_latency(keys: usize, interval_ms: u64, pack: Option<String>) -> Result<()> {
+    // Only for this process, the config file is left alone
+    let mut config = config::Config { enabled: true, ..config::Config::load().unwrap_or_default() };
+    if let Some(pack) = pack {
+        config.switch_type = pack;
+    }
+    ensure_installed(&config.switch_type)?;
+
+    let backend = audio::buffer::BufferBackend::new();
+    let clickclack = ClickClack::with_backend(Settings::new(config), Box::new(backend), packs::switchtypes_dir())?;
+    let (keyboard_handler, sound_engine) = (clickclack.keyboard(), clickclack.sound_engine());
+    let events = audio::latency::benchmark_events(keys, std::time::Duration::from_millis(interval_ms));
+    keyboard_handler.replay(&events, 1.0);
+
+    // Give the audio thread a moment to finish the last sounds
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while sound_engine.latency().count(audio::latency::Stage::Input) < events.len() as u64 && std::time::Instant::now() < deadline {
+        std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    print!("{}", audio::latency::to_text(&sound_engine.latency().summary()));
+    println!("Mixing into a buffer pulls right away; on a sound card `mix` also waits for the next buffer.");
+    Ok(())
+}
+
+fn bench_contention(reads: usize, interval_us: u64) -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("clickclack-bench-{}", std::process::id()));
+    let result = settings::contention_benchmark(reads, std::time::Duration::from_micros(interval_us), &dir);
+    let _ = std::fs::remove_dir_all(&dir);
+    let (mutex, snapshot) = result?;
+    print!("{}", settings::to_text(&mutex, &snapshot));
+    println!("The writer saves the config in a loop. With a mutex, reads wait out each save.");
+    Ok(())
+}
+
+fn print_stats(format: StatsFormat, keys: bool) -> Result<()> {
+    let stats = stats::load(&stats::stats_path())?;
+    match format {
+        StatsFormat::Text if stats.days.is_empty() => {
+            println!("No typing stats yet. Set `stats = true` in config.toml to start counting.");
+        }
+        StatsFormat::Text => print!("{}", stats::to_text(&stats)),
+        StatsFormat::Csv => print!("{}", stats::to_csv(&stats, keys)),
+        StatsFormat::Json => println!("{}", serde_json::to_string_pretty(&stats)?),
+    }
+    Ok(())
+}
+
+#[cfg(feature = "tui")]
+fn run_tui(audio_output: Option<&Path>) -> Result<()> {
+    let clickclack = start(audio_output)?;
+    clickclack.listen()?;
+    let sound_engine = clickclack.sound_engine().clone();
+
+    if let Err(e) = ipc::server::start(sound_engine.clone()) {
+        error!("Failed to start control socket: {}", e);
+    }
+    platform::init(clickclack.settings(), None);
+
+    let on_quit = count_stats(clickclack.settings())?;
+    let result = ui::tui::run(clickclack.keyboard(), sound_engine);
+    on_quit();
+    result
+}
+
+fn main() -> Result<()> {
     // Parse command line arguments
     let cli = Cli::parse();
 
+    let log_file = cli.log_file.clone().or_else(|| {
+        cli.command.as_ref().filter(|command| command.owns_terminal()).map(|_| logging::default_log_file())
+    });
+    logging::init(cli.log_keys, log_file.as_deref())?;
+    info!("Starting ClickClack...");
+
+    match cli.command {
+        Some(Command::Ctl { command }) => return ipc::client::run(command.into()),
+        Some(Command::ListDevices) => return list_devices(),
+        Some(Command::Render { events, pack, out, sample_rate }) => {
+            return render(&events, pack, &out, sample_rate);
+        }
+        Some(Command::Stats { format, keys }) => return print_stats(format, keys),
+        #[cfg(feature = "tui")]
+        Some(Command::Tui) => return run_tui(cli.audio_output.as_deref()),
+        Some(Command::Preview { pack, compare }) => return preview(pack, compare, cli.audio_output.as_deref()),
+        Some(Command::NewSynthPack { preset, folder, name }) => return new_synth_pack(&preset, &folder, name),
+        Some(Command::PackBuild { recording, folder, name, labels }) => {
+            return pack_build(&recording, &folder, name, labels.as_deref());
+        }
+        Some(Command::PackConvert { pack, folder, name, format, lufs, sample_rate }) => {
+            return pack_convert(&pack, &folder, name, format, lufs, sample_rate);
+        }
+        Some(Command::LatencyReport { pack }) => return latency_report(pack),
+        Some(Command::BenchLatency { keys, interval_ms, pack }) => return bench_latency(keys, interval_ms, pack),
+        Some(Command::BenchContention { reads, interval_us }) => return bench_contention(reads, interval_us),
+        Some(Command::Record { file }) => return record(&file, cli.audio_output.as_deref()),
+        Some(Command::Replay { file, speed }) => return replay(&file, speed, cli.audio_output.as_deref()),
+        None => {}
+    }
+
     // Handle service commands if present
     if cli.start_service || cli.stop_service || cli.restart_service {
         let service_manager = service::ServiceManager::new()?;
@@ -73,45 +568,58 @@ fn main() -> Result<()> {
         }
     }
 
-    // Regular application startup
-    #[cfg(target_os = "macos")]
-    unsafe {
-        let _pool = NSAutoreleasePool::new(nil);
-        let app = NSApplication::sharedApplication(nil);
-        app.setActivationPolicy_(NSApplicationActivationPolicyAccessory);
-    }
-
-    // Initialize assets
-    if let Err(e) = ui::tray::ensure_assets_exist() {
-        error!("Failed to initialize assets: {}", e);
-        return Err(anyhow::anyhow!("Failed to initialize assets: {}", e));
-    }
-    info!("Assets initialized successfully");
-
-    // Initialize the sound system
-    let sound_engine = Arc::new(audio::SoundEngine::new()?);
-    info!("Sound engine initialized");
+    let clickclack = start(cli.audio_output.as_deref())?;
+    let sound_engine = clickclack.sound_engine().clone();
 
     // Start keyboard listener in a separate thread
-    let keyboard_handler = input::KeyboardHandler::new(sound_engine.clone())?;
-    keyboard_handler.start()?;
+    let on_quit = count_stats(clickclack.settings())?;
+    clickclack.listen()?;
     info!("Keyboard handler started");
 
-    // Create the tray icon
-    let _tray = ui::TrayIcon::new()?;
-    info!("Tray icon created");
-    
-    // Run the main event loop
-    #[cfg(target_os = "macos")]
-    unsafe {
-        let app = NSApplication::sharedApplication(nil);
-        app.run();
+    // Listen for `clickclack ctl` clients
+    if let Err(e) = ipc::server::start(sound_engine.clone()) {
+        error!("Failed to start control socket: {}", e);
     }
 
-    #[cfg(not(target_os = "macos"))]
-    loop {
-        std::thread::sleep(std::time::Duration::from_millis(100));
+    if cli.no_tray {
+        platform::init(clickclack.settings(), None);
+        loop {
+            std::thread::park();
+        }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    // The tray menu needs a platform event loop on every platform
+    let mut event_loop = EventLoop::<ui::TrayEvent>::with_user_event();
+    platform::init(clickclack.settings(), Some(&mut event_loop));
+
+    // Create the tray icon, falling back to the Cocoa status item on macOS
+    let tray = match ui::SystemTray::new(event_loop.create_proxy(), sound_engine.clone(), on_quit.clone()) {
+        Ok(tray) => Some(tray),
+        Err(e) => {
+            error!("Failed to create tray icon: {}", e);
+            None
+        }
+    };
+    #[cfg(target_os = "macos")]
+    let cocoa_tray = if tray.is_none() {
+        Some(ui::TrayIcon::new(event_loop.create_proxy(), sound_engine, on_quit)?)
+    } else {
+        None
+    };
+    info!("Tray icon created");
+
+    // Run the main event loop
+    event_loop.run(move |event, _, control_flow| {
+        *control_flow = ControlFlow::Wait;
+
+        if let Event::UserEvent(event) = event {
+            #[cfg(target_os = "macos")]
+            if let (Some(cocoa_tray), ui::TrayEvent::StateChanged) = (&cocoa_tray, &event) {
+                cocoa_tray.refresh();
+            }
+            if let Some(tray) = &tray {
+                tray.handle_event(event);
+            }
+        }
+    })
+}
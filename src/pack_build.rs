@@ -0,0 +1,368 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use crate::audio::buffer::to_i16;
+
+/// Slots of a pack, in the order the guide asks for them
+pub const SLOTS: [&str; 7] = ["R0", "R1", "R2", "R3", "R4", "SPACE", "ENTER"];
+/// Presses per slot in a guided recording
+pub const HITS_PER_SLOT: usize = 4;
+
+/// Envelope resolution
+const WINDOW_MS: f32 = 1.0;
+/// A hit starts this far above the noise floor, and at least this share of the loudest hit
+const ONSET_OVER_FLOOR: f32 = 4.0;
+const ONSET_OF_LOUDEST: f32 = 0.1;
+/// Once started, it lasts until it falls below this share of the loudest hit
+const SUSTAIN_OF_LOUDEST: f32 = 0.03;
+/// A hit ends after this much quiet, or at this length
+const SILENCE_MS: usize = 15;
+const MAX_HIT_MS: usize = 250;
+/// A jump this steep starts a new hit even before the last one died down, once that one
+/// is long enough that the jump can't be its own bottom-out after a click
+const RETRIGGER_JUMP: f32 = 4.0;
+const RETRIGGER_OVER_MS: usize = 3;
+const MIN_RETRIGGER_MS: usize = 50;
+/// A quieter hit this soon after a press is its release
+const MAX_HOLD_MS: usize = 350;
+const RELEASE_LOUDER: f32 = 1.5;
+/// Kept before the onset and after the end of each hit
+const PRE_ROLL_MS: usize = 2;
+const TAIL_MS: usize = 20;
+const FADE_OUT_MS: usize = 5;
+/// Presses are normalized to this peak, releases by the same gain as their press
+const TARGET_PEAK: f32 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Hit {
+    start: usize,
+    end: usize,
+    peak: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Keystroke {
+    press: Hit,
+    release: Option<Hit>,
+}
+
+/// A pack cut from a recording, ready to be written out
+pub struct BuiltPack {
+    pub sample_rate: u32,
+    /// Sample path below the pack folder, e.g. `press/GENERIC_R0.wav`, and its audio
+    pub samples: BTreeMap<String, Vec<f32>>,
+    manifest: BTreeMap<String, SampleInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct Manifest<'a> {
+    name: &'a str,
+    source: &'a str,
+    sample_rate: u32,
+    samples: &'a BTreeMap<String, SampleInfo>,
+}
+
+/// Where a sample was cut from, so a bad cut can be found in the recording
+#[derive(Debug, Clone, Serialize)]
+struct SampleInfo {
+    at_ms: u64,
+    gain_db: f32,
+}
+
+/// The recording order `pack-build` expects without labels
+pub fn guide() -> String {
+    let slots: Vec<String> = SLOTS.iter().map(|slot| format!("{} x{}", slot, HITS_PER_SLOT)).collect();
+    format!(
+        "Press and release one key at a time, about half a second apart: {}. \
+         R0 is the number row, R1 the top letter row, down to R4 for the modifiers.",
+        slots.join(", ")
+    )
+}
+
+/// Reads a WAV recording mixed down to mono
+pub fn read_recording(path: &Path) -> Result<(Vec<f32>, u32)> {
+    let mut reader = hound::WavReader::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let spec = reader.spec();
+    let interleaved: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>()?,
+        hound::SampleFormat::Int => {
+            let scale = (1u64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 / scale)).collect::<Result<_, _>>()?
+        }
+    };
+    let channels = spec.channels as usize;
+    let mono = interleaved
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect();
+    Ok((mono, spec.sample_rate))
+}
+
+/// Reads slot names, one per press in the recording, separated by whitespace
+pub fn parse_labels(contents: &str) -> Result<Vec<String>> {
+    contents
+        .split_whitespace()
+        .map(|label| {
+            let slot = label.to_ascii_uppercase();
+            if !SLOTS.contains(&slot.as_str()) {
+                bail!("Unknown slot {:?}, use one of {}", label, SLOTS.join(", "));
+            }
+            Ok(slot)
+        })
+        .collect()
+}
+
+/// Cuts the recording into one press per slot plus releases. Without `labels` the presses
+/// have to follow the `guide`.
+pub fn build(samples: &[f32], sample_rate: u32, labels: Option<Vec<String>>) -> Result<BuiltPack> {
+    let ms = |ms: usize| ms * sample_rate as usize / 1000;
+    let strokes = pair(&detect_hits(samples, sample_rate), ms(MAX_HOLD_MS));
+    let labels = match labels {
+        Some(labels) => labels,
+        None => SLOTS.iter().flat_map(|slot| std::iter::repeat_n(slot.to_string(), HITS_PER_SLOT)).collect(),
+    };
+    if labels.len() != strokes.len() {
+        bail!(
+            "Found {} key presses but expected {}. Either record again following the guide, or \
+             pass --labels with one slot per press.\n{}",
+            strokes.len(), labels.len(), guide()
+        );
+    }
+
+    let mut by_slot: BTreeMap<&str, Vec<Keystroke>> = BTreeMap::new();
+    for (label, stroke) in labels.iter().zip(strokes) {
+        by_slot.entry(label.as_str()).or_default().push(stroke);
+    }
+    for slot in &SLOTS[..5] {
+        if !by_slot.contains_key(slot) {
+            bail!("No presses labelled {}, every row needs one", slot);
+        }
+    }
+
+    let mut pack = BuiltPack { sample_rate, samples: BTreeMap::new(), manifest: BTreeMap::new() };
+    for (slot, strokes) in &by_slot {
+        let path = match *slot {
+            "SPACE" | "ENTER" => format!("press/{}.wav", slot),
+            row => format!("press/GENERIC_{}.wav", row),
+        };
+        let stroke = typical(strokes, |stroke| Some(stroke.press)).context("No presses")?;
+        pack.add(path, samples, stroke.press, stroke.press, sample_rate);
+    }
+
+    // Every row shares one release, the big keys have their own
+    let generic: Vec<Keystroke> = SLOTS[..5].iter().flat_map(|slot| by_slot[slot].iter().copied()).collect();
+    let stroke = typical(&generic, |stroke| stroke.release)
+        .context("Found no key releases. Hold each key for a moment and let it go cleanly.")?;
+    pack.add("release/GENERIC.wav".into(), samples, stroke.release.unwrap(), stroke.press, sample_rate);
+    for slot in ["SPACE", "ENTER"] {
+        if let Some(stroke) = by_slot.get(slot).and_then(|strokes| typical(strokes, |stroke| stroke.release)) {
+            pack.add(format!("release/{}.wav", slot), samples, stroke.release.unwrap(), stroke.press, sample_rate);
+        }
+    }
+    Ok(pack)
+}
+
+impl BuiltPack {
+    fn add(&mut self, path: String, samples: &[f32], hit: Hit, press: Hit, sample_rate: u32) {
+        let ms = |ms: usize| ms * sample_rate as usize / 1000;
+        let gain = TARGET_PEAK / press.peak;
+        let start = hit.start.saturating_sub(ms(PRE_ROLL_MS));
+        let end = (hit.end + ms(TAIL_MS)).min(samples.len());
+        let mut cut: Vec<f32> = samples[start..end].iter().map(|sample| sample * gain).collect();
+        let fade = ms(FADE_OUT_MS).min(cut.len());
+        let len = cut.len();
+        for (i, sample) in cut[len - fade..].iter_mut().enumerate() {
+            *sample *= 1.0 - i as f32 / fade as f32;
+        }
+
+        let info = SampleInfo {
+            at_ms: (hit.start as u64 * 1000) / sample_rate as u64,
+            gain_db: 20.0 * gain.log10(),
+        };
+        self.samples.insert(path.clone(), cut);
+        self.manifest.insert(path, info);
+    }
+
+    /// Writes the samples and `manifest.json` into `pack_dir`
+    pub fn write(&self, pack_dir: &Path, name: &str, source: &str) -> Result<()> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: self.sample_rate,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        for (path, samples) in &self.samples {
+            let path = pack_dir.join(path);
+            fs::create_dir_all(path.parent().unwrap_or(pack_dir))?;
+            let mut writer = hound::WavWriter::create(&path, spec).with_context(|| format!("Failed to create {:?}", path))?;
+            for sample in samples {
+                writer.write_sample(to_i16(*sample))?;
+            }
+            writer.finalize()?;
+        }
+
+        let manifest = Manifest { name, source, sample_rate: self.sample_rate, samples: &self.manifest };
+        fs::write(pack_dir.join("manifest.json"), serde_json::to_string_pretty(&manifest)?)?;
+        Ok(())
+    }
+}
+
+/// The hit of median loudness, so neither a fumbled nor a slammed key ends up in the pack
+fn typical(strokes: &[Keystroke], hit: impl Fn(&Keystroke) -> Option<Hit>) -> Option<Keystroke> {
+    let mut candidates: Vec<(Hit, Keystroke)> = strokes.iter().filter_map(|stroke| Some((hit(stroke)?, *stroke))).collect();
+    candidates.sort_by(|a, b| a.0.peak.total_cmp(&b.0.peak));
+    candidates.get(candidates.len() / 2).map(|(_, stroke)| *stroke)
+}
+
+/// Finds hits in a peak envelope, which is robust to the slow rumble of a desk or a fan
+fn detect_hits(samples: &[f32], sample_rate: u32) -> Vec<Hit> {
+    let window = ((WINDOW_MS / 1000.0 * sample_rate as f32) as usize).max(1);
+    let envelope: Vec<f32> = samples
+        .chunks(window)
+        .map(|chunk| chunk.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs())))
+        .collect();
+    if envelope.is_empty() {
+        return Vec::new();
+    }
+
+    let mut sorted = envelope.clone();
+    sorted.sort_by(f32::total_cmp);
+    let floor = sorted[sorted.len() / 5];
+    let loudest = sorted[sorted.len() - 1];
+    let onset = (floor * ONSET_OVER_FLOOR).max(loudest * ONSET_OF_LOUDEST);
+    let sustain = (floor * ONSET_OVER_FLOOR).max(loudest * SUSTAIN_OF_LOUDEST);
+
+    let windows = |ms: usize| (ms as f32 / WINDOW_MS) as usize;
+    let (silence, max_hit) = (windows(SILENCE_MS), windows(MAX_HIT_MS));
+    let (jump_over, min_retrigger) = (windows(RETRIGGER_OVER_MS), windows(MIN_RETRIGGER_MS));
+    let mut hits = Vec::new();
+    let mut current: Option<(usize, usize, f32)> = None;
+    for (index, &level) in envelope.iter().enumerate() {
+        current = match current {
+            None if level > onset => Some((index, index, level)),
+            None => None,
+            Some((start, last_loud, peak)) => {
+                let retrigger = index - start >= min_retrigger
+                    && level > onset
+                    && level > envelope[index - jump_over] * RETRIGGER_JUMP;
+                if retrigger {
+                    hits.push(Hit { start: start * window, end: index * window, peak });
+                    current = Some((index, index, level));
+                    continue;
+                }
+                let last_loud = if level > sustain { index } else { last_loud };
+                if index - last_loud >= silence || index - start >= max_hit {
+                    hits.push(Hit { start: start * window, end: (last_loud + 1) * window, peak });
+                    None
+                } else {
+                    Some((start, last_loud, peak.max(level)))
+                }
+            }
+        };
+    }
+    if let Some((start, last_loud, peak)) = current {
+        hits.push(Hit { start: start * window, end: ((last_loud + 1) * window).min(samples.len()), peak });
+    }
+    hits
+}
+
+/// Pairs each press with the quieter hit that follows it within `max_hold` samples
+fn pair(hits: &[Hit], max_hold: usize) -> Vec<Keystroke> {
+    let mut strokes: Vec<Keystroke> = Vec::new();
+    for &hit in hits {
+        match strokes.last_mut() {
+            Some(stroke) if stroke.release.is_none()
+                && hit.start - stroke.press.start <= max_hold
+                && hit.peak <= stroke.press.peak * RELEASE_LOUDER => stroke.release = Some(hit),
+            _ => strokes.push(Keystroke { press: hit, release: None }),
+        }
+    }
+    strokes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::synth;
+
+    const RATE: u32 = 48000;
+
+    /// A guided recording made with a synthesized switch over some background noise
+    fn recording() -> Vec<f32> {
+        let params = synth::preset("holypanda").unwrap();
+        let mut samples: Vec<f32> = (0..RATE as usize * 16).map(|i| ((i * 7919) % 101) as f32 / 101.0 * 0.002).collect();
+        let mut at = RATE as usize / 2;
+        for (slot, _) in SLOTS.iter().enumerate() {
+            for press in 0..HITS_PER_SLOT {
+                let row = slot.min(4);
+                let seed = (slot * HITS_PER_SLOT + press) as u64;
+                for (offset, is_press) in [(0, true), (RATE as usize / 8, false)] {
+                    for (i, sample) in synth::hit(&params, row, is_press, RATE, seed).iter().enumerate() {
+                        samples[at + offset + i] += sample * 0.5;
+                    }
+                }
+                at += RATE as usize / 2;
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn test_detects_presses_and_releases() {
+        let hits = detect_hits(&recording(), RATE);
+        let strokes = pair(&hits, MAX_HOLD_MS * RATE as usize / 1000);
+        assert_eq!(strokes.len(), SLOTS.len() * HITS_PER_SLOT);
+        assert!(strokes.iter().all(|stroke| stroke.release.is_some()));
+        // Half a second apart, starting half a second in
+        for (index, stroke) in strokes.iter().enumerate() {
+            let expected = (index + 1) * RATE as usize / 2;
+            assert!(stroke.press.start.abs_diff(expected) < RATE as usize / 500, "press {} at {}", index, stroke.press.start);
+        }
+    }
+
+    #[test]
+    fn test_builds_every_slot() {
+        let pack = build(&recording(), RATE, None).unwrap();
+        let paths: Vec<&str> = pack.samples.keys().map(String::as_str).collect();
+        assert_eq!(paths, vec![
+            "press/ENTER.wav", "press/GENERIC_R0.wav", "press/GENERIC_R1.wav", "press/GENERIC_R2.wav",
+            "press/GENERIC_R3.wav", "press/GENERIC_R4.wav", "press/SPACE.wav",
+            "release/ENTER.wav", "release/GENERIC.wav", "release/SPACE.wav",
+        ]);
+        for (path, samples) in &pack.samples {
+            let peak = samples.iter().fold(0.0, |peak: f32, sample| peak.max(sample.abs()));
+            if path.starts_with("press") {
+                assert!((peak - TARGET_PEAK).abs() < 0.01, "{} peaks at {}", path, peak);
+            } else {
+                assert!(peak < TARGET_PEAK, "{} peaks at {}", path, peak);
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("clickclack-pack-build-{}", std::process::id()));
+        pack.write(&dir, "Test Panda", "panda.wav").unwrap();
+        let reader = hound::WavReader::open(dir.join("press/SPACE.wav")).unwrap();
+        assert_eq!(reader.spec().sample_rate, RATE);
+        let manifest: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(manifest["name"], "Test Panda");
+        assert_eq!(manifest["samples"].as_object().unwrap().len(), 10);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_labels_must_match_presses() {
+        let labels = parse_labels("r2 R2\nSPACE").unwrap();
+        assert_eq!(labels, vec!["R2", "R2", "SPACE"]);
+        assert!(parse_labels("R5").is_err());
+
+        let error = build(&recording(), RATE, Some(labels)).err().unwrap().to_string();
+        assert!(error.starts_with("Found 28 key presses but expected 3"), "{}", error);
+        let rows_only = SLOTS[..5].iter()
+            .flat_map(|slot| std::iter::repeat_n(slot.to_string(), 5))
+            .chain(std::iter::repeat_n("R0".to_string(), 3))
+            .collect();
+        assert!(build(&recording(), RATE, Some(rows_only)).unwrap().samples.keys().all(|path| path.contains("GENERIC")));
+    }
+}
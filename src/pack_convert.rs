@@ -0,0 +1,239 @@
+use anyhow::{bail, Context, Result};
+use rodio::source::UniformSourceIterator;
+use rodio::{Decoder, Source};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::audio::buffer::to_i16;
+use crate::audio::loudness::Meter;
+use crate::audio::{flac, synth};
+use crate::packs;
+
+/// Loudness packs are brought to unless asked otherwise
+pub const DEFAULT_LUFS: f64 = -20.0;
+/// The gain is held back so no sample peaks above this
+const PEAK_CEILING: f32 = 0.98;
+
+/// What `pack-convert` writes. There is no Vorbis or MP3 encoder to write the others with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Wav,
+    Flac,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Wav => "wav",
+            Format::Flac => "flac",
+        }
+    }
+}
+
+/// Interleaved audio of one sample
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clip {
+    pub channels: u16,
+    pub samples: Vec<f32>,
+}
+
+/// Every sample of a pack, decoded at one rate
+#[derive(Debug)]
+pub struct DecodedPack {
+    pub sample_rate: u32,
+    /// Keyed by the path below the pack folder without its extension, e.g. `press/GENERIC_R0`
+    pub clips: BTreeMap<String, Clip>,
+}
+
+/// How `normalize` changed a pack
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Normalized {
+    pub from_lufs: f64,
+    pub gain_db: f64,
+    /// Whether the target was too loud to reach without clipping
+    pub limited: bool,
+}
+
+/// Decodes the samples of the pack in `pack_dir`, resampled to `sample_rate`. Where a
+/// sample is there in several formats, the one playback would pick is read.
+pub fn read(pack_dir: &Path, sample_rate: u32) -> Result<DecodedPack> {
+    if synth::load(pack_dir)?.is_some() {
+        bail!("{:?} is synthesized, it has no samples to convert", pack_dir);
+    }
+
+    let mut clips = BTreeMap::new();
+    for (stem, path) in packs::list_samples(pack_dir)? {
+        clips.insert(stem, decode(&path, sample_rate)?);
+    }
+    if clips.is_empty() {
+        bail!("No {} samples in {:?}", packs::SAMPLE_EXTENSIONS.join("/"), pack_dir);
+    }
+    Ok(DecodedPack { sample_rate, clips })
+}
+
+fn decode(path: &Path, sample_rate: u32) -> Result<Clip> {
+    let file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+    let source = Decoder::new(BufReader::new(file)).with_context(|| format!("Failed to decode {:?}", path))?;
+    let channels = source.channels();
+    let samples = UniformSourceIterator::<_, f32>::new(source, channels, sample_rate).collect();
+    Ok(Clip { channels, samples })
+}
+
+impl DecodedPack {
+    /// Loudness of all samples together
+    pub fn lufs(&self) -> Option<f64> {
+        let mut meter = Meter::default();
+        for clip in self.clips.values() {
+            meter.add(&clip.samples, clip.channels, self.sample_rate);
+        }
+        meter.lufs()
+    }
+
+    /// Brings the pack as a whole to `target_lufs`. It is one gain for every sample, so
+    /// releases stay as much quieter than presses as the pack's author made them.
+    pub fn normalize(&mut self, target_lufs: f64) -> Result<Normalized> {
+        let from_lufs = self.lufs().context("The pack is silent")?;
+        let peak = self
+            .clips
+            .values()
+            .flat_map(|clip| &clip.samples)
+            .fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+
+        let mut gain = 10f32.powf(((target_lufs - from_lufs) / 20.0) as f32);
+        let limited = peak * gain > PEAK_CEILING;
+        if limited {
+            gain = PEAK_CEILING / peak;
+        }
+        for sample in self.clips.values_mut().flat_map(|clip| &mut clip.samples) {
+            *sample *= gain;
+        }
+        Ok(Normalized { from_lufs, gain_db: 20.0 * (gain as f64).log10(), limited })
+    }
+
+    /// Writes the samples into `pack_dir` as `format`, along with every other file of the
+    /// pack in `source_dir`, such as its manifest
+    pub fn write(&self, source_dir: &Path, pack_dir: &Path, format: Format) -> Result<()> {
+        for file in packs::pack_files(source_dir)?.into_iter().filter(|file| !packs::is_sample(file)) {
+            let to = pack_dir.join(&file);
+            fs::create_dir_all(to.parent().unwrap_or(pack_dir))?;
+            fs::copy(source_dir.join(&file), &to).with_context(|| format!("Failed to copy {:?}", file))?;
+        }
+
+        for (stem, clip) in &self.clips {
+            let path = pack_dir.join(format!("{}.{}", stem, format.extension()));
+            fs::create_dir_all(path.parent().unwrap_or(pack_dir))?;
+            let pcm: Vec<i16> = clip.samples.iter().map(|sample| to_i16(*sample)).collect();
+            match format {
+                Format::Wav => {
+                    let spec = hound::WavSpec {
+                        channels: clip.channels,
+                        sample_rate: self.sample_rate,
+                        bits_per_sample: 16,
+                        sample_format: hound::SampleFormat::Int,
+                    };
+                    let mut writer = hound::WavWriter::create(&path, spec)
+                        .with_context(|| format!("Failed to create {:?}", path))?;
+                    for sample in pcm {
+                        writer.write_sample(sample)?;
+                    }
+                    writer.finalize()?;
+                }
+                Format::Flac => flac::write(&path, clip.channels, self.sample_rate, &pcm)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Writes a clip of `len` frames at `level`, as WAV or FLAC depending on the extension
+    fn write_clip(path: &Path, channels: u16, len: usize, level: f32) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let pcm: Vec<i16> = (0..len * channels as usize)
+            .map(|i| to_i16(level * (i as f32 * 0.3).sin()))
+            .collect();
+        if path.extension().unwrap() == "flac" {
+            flac::write(path, channels, 44100, &pcm).unwrap();
+            return;
+        }
+        let spec = hound::WavSpec { channels, sample_rate: 44100, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for sample in pcm {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn source_pack(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clickclack-convert-{}-{}", std::process::id(), name));
+        write_clip(&dir.join("press/GENERIC_R0.wav"), 1, 4410, 0.5);
+        write_clip(&dir.join("press/SPACE.flac"), 2, 4410, 0.5);
+        write_clip(&dir.join("release/GENERIC.wav"), 1, 2205, 0.1);
+        // Shadowed by the WAV, like it is during playback
+        write_clip(&dir.join("release/GENERIC.flac"), 1, 100, 0.9);
+        fs::write(dir.join("manifest.json"), "{}").unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_reads_every_format_at_one_rate() {
+        let pack = read(&source_pack("read"), 48000).unwrap();
+        assert_eq!(pack.clips.keys().collect::<Vec<_>>(), vec!["press/GENERIC_R0", "press/SPACE", "release/GENERIC"]);
+        assert_eq!(pack.clips["press/GENERIC_R0"].samples.len(), 4800);
+        assert_eq!(pack.clips["press/SPACE"].channels, 2);
+        assert_eq!(pack.clips["press/SPACE"].samples.len(), 2 * 4800);
+        assert_eq!(pack.clips["release/GENERIC"].samples.len(), 2400);
+    }
+
+    #[test]
+    fn test_normalize_keeps_the_balance() {
+        let mut pack = read(&source_pack("normalize"), 48000).unwrap();
+        let ratio = |pack: &DecodedPack| pack.clips["press/GENERIC_R0"].samples[100] / pack.clips["release/GENERIC"].samples[100];
+        let before = ratio(&pack);
+
+        let normalized = pack.normalize(-30.0).unwrap();
+        assert!(!normalized.limited);
+        assert!((pack.lufs().unwrap() + 30.0).abs() < 0.01);
+        assert!((ratio(&pack) - before).abs() < 0.001);
+
+        // Too loud to reach, so it stops short of clipping
+        let normalized = pack.normalize(10.0).unwrap();
+        assert!(normalized.limited);
+        let peak = pack.clips.values().flat_map(|clip| &clip.samples).fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!((peak - PEAK_CEILING).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_write_converts_and_copies() {
+        let source = source_pack("write");
+        let mut pack = read(&source, 48000).unwrap();
+        pack.normalize(DEFAULT_LUFS).unwrap();
+
+        let out = std::env::temp_dir().join(format!("clickclack-convert-{}-out", std::process::id()));
+        pack.write(&source, &out, Format::Flac).unwrap();
+        assert_eq!(packs::pack_files(&out).unwrap(), vec![
+            PathBuf::from("manifest.json"),
+            PathBuf::from("press/GENERIC_R0.flac"),
+            PathBuf::from("press/SPACE.flac"),
+            PathBuf::from("release/GENERIC.flac"),
+        ]);
+
+        let converted = read(&out, 48000).unwrap();
+        assert_eq!(converted.clips["press/SPACE"].samples.len(), pack.clips["press/SPACE"].samples.len());
+        assert!((converted.lufs().unwrap() - DEFAULT_LUFS).abs() < 0.1);
+        fs::remove_dir_all(&out).unwrap();
+    }
+
+    #[test]
+    fn test_synth_packs_have_nothing_to_convert() {
+        let dir = std::env::temp_dir().join(format!("clickclack-convert-{}-synth", std::process::id()));
+        synth::save(&synth::preset("topre").unwrap(), &dir).unwrap();
+        assert!(read(&dir, 48000).is_err());
+    }
+}
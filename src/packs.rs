@@ -0,0 +1,277 @@
+use anyhow::{Context, Result};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pack {
+    pub name: String,
+    pub folder: String,
+}
+
+pub fn switchtypes_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("~/.config"))
+        .join("clickclack")
+        .join("switchtypes")
+}
+
+/// Formats a sample can be in, in the order they are looked for. The cheapest to
+/// decode comes first, in case a pack has the same sample twice.
+pub const SAMPLE_EXTENSIONS: [&str; 4] = ["wav", "flac", "ogg", "mp3"];
+
+/// The file for a sample path without its extension, e.g. `mxblue/press/GENERIC_R2`
+pub fn find_sample(dir: &Path, stem: &str) -> Option<PathBuf> {
+    SAMPLE_EXTENSIONS
+        .iter()
+        .map(|extension| dir.join(format!("{}.{}", stem, extension)))
+        .find(|path| path.is_file())
+}
+
+pub fn is_sample(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| SAMPLE_EXTENSIONS.contains(&extension))
+}
+
+/// The samples of the pack in `pack_dir` by their path without extension, e.g.
+/// `press/GENERIC_R0`, each with the file playback picks for it
+pub fn list_samples(pack_dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut samples = BTreeMap::new();
+    for file in pack_files(pack_dir)?.into_iter().filter(|file| is_sample(file)) {
+        let stem = file.with_extension("").to_string_lossy().replace('\\', "/");
+        if let Some(path) = find_sample(pack_dir, &stem) {
+            samples.insert(stem, path);
+        }
+    }
+    Ok(samples)
+}
+
+/// Every file below `pack_dir`, relative to it
+pub fn pack_files(pack_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let dir = pack_dir.join(&relative);
+        for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))? {
+            let entry = entry?;
+            let path = relative.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                pending.push(path);
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Reads the installed switch packs from `switchtypes/desc.json`
+pub fn list_packs() -> Result<Vec<Pack>> {
+    list_packs_in(&switchtypes_dir())
+}
+
+/// Reads the packs listed in `desc.json` in `switchtypes_dir`
+pub fn list_packs_in(switchtypes_dir: &Path) -> Result<Vec<Pack>> {
+    let desc_path = switchtypes_dir.join("desc.json");
+    let contents = fs::read_to_string(&desc_path)
+        .with_context(|| format!("Failed to read {:?}", desc_path))?;
+    parse_desc(&contents)
+}
+
+fn parse_desc(contents: &str) -> Result<Vec<Pack>> {
+    let json: serde_json::Value = serde_json::from_str(contents)?;
+    let switches = json
+        .get("switches")
+        .and_then(|switches| switches.as_object())
+        .context("desc.json has no \"switches\" object")?;
+
+    Ok(switches
+        .iter()
+        .filter_map(|(name, folder)| {
+            folder.as_str().map(|folder| Pack {
+                name: name.clone(),
+                folder: folder.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Lists a pack in `switchtypes/desc.json` so the menus and `ctl` pick it up
+pub fn add_pack(name: &str, folder: &str) -> Result<()> {
+    let desc_path = switchtypes_dir().join("desc.json");
+    let contents = fs::read_to_string(&desc_path)
+        .with_context(|| format!("Failed to read {:?}", desc_path))?;
+    fs::write(&desc_path, add_to_desc(&contents, name, folder)?)
+        .with_context(|| format!("Failed to write {:?}", desc_path))?;
+    Ok(())
+}
+
+fn add_to_desc(contents: &str, name: &str, folder: &str) -> Result<String> {
+    let mut json: serde_json::Value = serde_json::from_str(contents)?;
+    let switches = json
+        .get_mut("switches")
+        .and_then(|switches| switches.as_object_mut())
+        .context("desc.json has no \"switches\" object")?;
+    if switches.values().any(|existing| existing == folder) {
+        anyhow::bail!("A pack in {:?} is already installed", folder);
+    }
+    switches.insert(name.to_string(), folder.into());
+    Ok(serde_json::to_string_pretty(&json)?)
+}
+
+pub fn ensure_assets_exist() -> std::io::Result<()> {
+    info!("Starting asset initialization...");
+    
+    let config_dir = dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("~/.config"))
+        .join("clickclack");
+    debug!("Config directory path: {:?}", config_dir);
+    
+    // Copy icon file if it doesn't exist
+    let icon_path = config_dir.join("icon.png");
+    if !icon_path.exists() {
+        if let Some(exe_dir) = std::env::current_exe()?.parent() {
+            let source_icon = exe_dir.join("assets/icon.png");
+            if source_icon.exists() {
+                fs::copy(source_icon, &icon_path)?;
+                debug!("Copied icon file to: {:?}", icon_path);
+            }
+        }
+    }
+    
+    let switchtypes_dir = config_dir.join("switchtypes");
+    debug!("Checking for switchtypes directory at: {:?}", switchtypes_dir);
+    debug!("Switchtypes directory exists: {}", switchtypes_dir.exists());
+    
+    if !switchtypes_dir.exists() {
+        info!("Switchtypes directory not found, downloading assets...");
+        fs::create_dir_all(&config_dir)?;
+        debug!("Created config directory at: {:?}", config_dir);
+        
+        // Download the zip file
+        let url = "https://github.com/cesarferreira/clickclack/raw/refs/heads/main/assets/switchtypes.zip";
+        info!("Downloading assets from: {}", url);
+        
+        let response = match ureq::get(url).call() {
+            Ok(resp) => resp,
+            Err(e) => {
+                error!("Failed to download assets: {}", e);
+                return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+            }
+        };
+        debug!("Download response received");
+        
+        let mut bytes: Vec<u8> = Vec::new();
+        match response.into_reader().read_to_end(&mut bytes) {
+            Ok(_) => debug!("Downloaded {} bytes", bytes.len()),
+            Err(e) => {
+                error!("Failed to read response: {}", e);
+                return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+            }
+        }
+        
+        // Extract the zip file
+        let cursor = Cursor::new(bytes);
+        let mut archive = match zip::ZipArchive::new(cursor) {
+            Ok(archive) => {
+                debug!("Successfully created zip archive with {} files", archive.len());
+                archive
+            },
+            Err(e) => {
+                error!("Failed to create zip archive: {}", e);
+                return Err(io::Error::new(io::ErrorKind::Other, e.to_string()));
+            }
+        };
+        
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let outpath = config_dir.join(file.name());
+            debug!("Extracting file to: {:?}", outpath);
+            
+            if file.name().ends_with('/') {
+                fs::create_dir_all(&outpath)?;
+                debug!("Created directory: {:?}", outpath);
+            } else {
+                if let Some(p) = outpath.parent() {
+                    fs::create_dir_all(p)?;
+                    debug!("Created parent directory: {:?}", p);
+                }
+                let mut outfile = fs::File::create(&outpath)?;
+                std::io::copy(&mut file, &mut outfile)?;
+                debug!("Extracted file: {:?}", outpath);
+            }
+        }
+        
+        info!("Assets downloaded and extracted successfully");
+    } else {
+        debug!("Switchtypes directory already exists at: {:?}", switchtypes_dir);
+        
+        // Check if the directory has content
+        let entries = fs::read_dir(&switchtypes_dir)?;
+        let count = entries.count();
+        debug!("Found {} entries in switchtypes directory", count);
+        
+        if count == 0 {
+            info!("Switchtypes directory is empty, removing and recreating...");
+            fs::remove_dir(&switchtypes_dir)?;
+            return ensure_assets_exist();
+        }
+    }
+    
+    info!("Asset initialization complete");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_desc() {
+        let packs = parse_desc(r#"{ "switches": { "Cherry MX Blue": "mxblue", "Topre": "topre" } }"#).unwrap();
+        assert_eq!(packs, vec![
+            Pack { name: "Cherry MX Blue".into(), folder: "mxblue".into() },
+            Pack { name: "Topre".into(), folder: "topre".into() },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_desc_without_switches() {
+        assert!(parse_desc(r#"{ "packs": {} }"#).is_err());
+    }
+
+    #[test]
+    fn test_add_to_desc() {
+        let desc = r#"{ "switches": { "Topre": "topre" } }"#;
+        let added = add_to_desc(desc, "Synth Blue", "synthblue").unwrap();
+        assert_eq!(parse_desc(&added).unwrap(), vec![
+            Pack { name: "Synth Blue".into(), folder: "synthblue".into() },
+            Pack { name: "Topre".into(), folder: "topre".into() },
+        ]);
+        assert!(add_to_desc(&added, "Other", "topre").is_err());
+    }
+
+    #[test]
+    fn test_find_sample_prefers_cheap_formats() {
+        let dir = std::env::temp_dir().join(format!("clickclack-find-sample-{}", std::process::id()));
+        fs::create_dir_all(dir.join("pack/press")).unwrap();
+        assert_eq!(find_sample(&dir, "pack/press/GENERIC_R2"), None);
+
+        fs::write(dir.join("pack/press/GENERIC_R2.mp3"), b"").unwrap();
+        assert_eq!(find_sample(&dir, "pack/press/GENERIC_R2"), Some(dir.join("pack/press/GENERIC_R2.mp3")));
+        fs::write(dir.join("pack/press/GENERIC_R2.flac"), b"").unwrap();
+        assert_eq!(find_sample(&dir, "pack/press/GENERIC_R2"), Some(dir.join("pack/press/GENERIC_R2.flac")));
+
+        fs::write(dir.join("pack/manifest.json"), b"{}").unwrap();
+        let samples = list_samples(&dir.join("pack")).unwrap();
+        assert_eq!(samples.into_iter().collect::<Vec<_>>(), vec![
+            ("press/GENERIC_R2".to_string(), dir.join("pack/press/GENERIC_R2.flac")),
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
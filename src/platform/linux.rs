@@ -0,0 +1,10 @@
+use crate::Settings;
+
+/// Exports the D-Bus interface for `settings`, when built with it
+#[allow(unused_variables)]
+pub fn init(settings: &Settings) {
+    #[cfg(feature = "dbus")]
+    if let Err(e) = crate::dbus::start(settings.clone()) {
+        log::error!("Failed to export D-Bus interface: {}", e);
+    }
+}
@@ -1,9 +1,7 @@
-use cocoa::appkit::{NSApplication, NSApplicationActivationPolicy};
-use cocoa::base::nil;
+use tao::event_loop::EventLoop;
+use tao::platform::macos::{ActivationPolicy, EventLoopExtMacOS};
 
-pub fn init_platform() {
-    unsafe {
-        let app = NSApplication::sharedApplication(nil);
-        app.setActivationPolicy_(NSApplicationActivationPolicy::NSApplicationActivationPolicyAccessory);
-    }
+/// Keeps ClickClack out of the Dock and the app switcher; it lives in the menu bar
+pub fn init<T>(event_loop: &mut EventLoop<T>) {
+    event_loop.set_activation_policy(ActivationPolicy::Accessory);
 }
@@ -0,0 +1,24 @@
+//! Setup only one platform needs. Each module is compiled on its own platform only, and
+//! `init` is the one place the rest of the app calls into them.
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(target_os = "macos")]
+mod macos;
+
+use tao::event_loop::EventLoop;
+
+use crate::ui::TrayEvent;
+use crate::Settings;
+
+/// Platform setup once the sound engine plays with `settings`, before anything is shown.
+/// `event_loop` is the tray's, when there is a tray.
+#[allow(unused_variables)]
+pub fn init(settings: &Settings, event_loop: Option<&mut EventLoop<TrayEvent>>) {
+    #[cfg(target_os = "linux")]
+    linux::init(settings);
+    #[cfg(target_os = "macos")]
+    if let Some(event_loop) = event_loop {
+        macos::init(event_loop);
+    }
+}
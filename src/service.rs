@@ -93,12 +93,15 @@ impl ServiceManager {
         // Create the plist content
         let dict = vec![
             (String::from("Label"), Value::String(PLIST_LABEL.into())),
+            // The app rotates its own log, launchd would append to it forever
             (String::from("ProgramArguments"), Value::Array(vec![
-                Value::String(bundle_path.join("Contents/MacOS").join(APP_NAME).to_string_lossy().into_owned())
+                Value::String(bundle_path.join("Contents/MacOS").join(APP_NAME).to_string_lossy().into_owned()),
+                Value::String("--log-file".into()),
+                Value::String(format!("{}/Library/Logs/clickclack.log", home)),
             ])),
             (String::from("RunAtLoad"), Value::Boolean(true)),
             (String::from("KeepAlive"), Value::Boolean(true)),
-            (String::from("StandardOutPath"), Value::String(format!("{}/Library/Logs/clickclack.log", home))),
+            // Only panics end up here
             (String::from("StandardErrorPath"), Value::String(format!("{}/Library/Logs/clickclack.error.log", home))),
         ];
         
@@ -113,9 +116,8 @@ impl ServiceManager {
 
     pub fn start_service(&self) -> Result<()> {
         info!("Starting ClickClack service...");
-        if !self.plist_path.exists() {
-            self.install_service()?;
-        }
+        // Always rewritten, so a plist from an older version doesn't keep its settings
+        self.install_service()?;
         
         // Load the service using launchctl
         let output = Command::new("launchctl")
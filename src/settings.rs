@@ -0,0 +1,301 @@
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::events::{Event, EventBus};
+
+/// A value shared as immutable snapshots. Readers keep a `Cache` and pay one atomic load
+/// while nothing changed. Writers build the next snapshot on the side and only hold the
+/// lock readers could meet for the pointer swap, never for saving or UI work.
+pub struct Published<T> {
+    current: Mutex<Arc<T>>,
+    /// Bumped after every swap
+    generation: AtomicU64,
+    /// Serializes `update`s so none is lost to another
+    writer: Mutex<()>,
+}
+
+/// A reader's last snapshot and the generation it was taken at
+pub struct Cache<T>(Option<(u64, Arc<T>)>);
+
+impl<T> Default for Cache<T> {
+    fn default() -> Self {
+        Self(None)
+    }
+}
+
+impl<T: Clone> Published<T> {
+    pub fn new(value: T) -> Self {
+        Self { current: Mutex::new(Arc::new(value)), generation: AtomicU64::new(0), writer: Mutex::new(()) }
+    }
+
+    /// The latest snapshot, from `cache` unless a newer one was published since
+    pub fn load(&self, cache: &mut Cache<T>) -> Arc<T> {
+        let generation = self.generation.load(Ordering::Acquire);
+        match &cache.0 {
+            Some((seen, value)) if *seen == generation => value.clone(),
+            _ => {
+                let value = self.current.lock().clone();
+                cache.0 = Some((generation, value.clone()));
+                value
+            }
+        }
+    }
+
+    /// Publishes a copy of the latest snapshot with `apply` made to it, and returns it
+    pub fn update(&self, apply: impl FnOnce(&mut T)) -> Arc<T> {
+        self.update_then(apply, |_, _| {})
+    }
+
+    /// Like `update`, then calls `then` with the previous and the new snapshot before the
+    /// next update can start, so whatever it passes on goes out in update order
+    pub fn update_then(&self, apply: impl FnOnce(&mut T), then: impl FnOnce(&T, &Arc<T>)) -> Arc<T> {
+        let _writer = self.writer.lock();
+        let mut value = T::clone(&self.current.lock());
+        apply(&mut value);
+        let value = Arc::new(value);
+        let previous = std::mem::replace(&mut *self.current.lock(), value.clone());
+        self.generation.fetch_add(1, Ordering::Release);
+        then(&previous, &value);
+        // Freed outside the lock, if this was the last reference
+        drop(previous);
+        value
+    }
+}
+
+/// The settings of one player, shared by everything that reads or changes them, and the
+/// bus its events go out on. Clones share the same settings and bus.
+#[derive(Clone)]
+pub struct Settings {
+    id: usize,
+    published: Arc<Published<Config>>,
+    events: EventBus,
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// The snapshot this thread last read, and which `Settings` it came from
+    static CACHE: RefCell<(usize, Cache<Config>)> = RefCell::new((usize::MAX, Cache::default()));
+}
+
+impl Settings {
+    pub fn new(config: Config) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            published: Arc::new(Published::new(config)),
+            events: EventBus::default(),
+        }
+    }
+
+    /// The latest snapshot. One atomic load, unless the settings changed since this
+    /// thread last looked.
+    pub fn current(&self) -> Arc<Config> {
+        CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            if cache.0 != self.id {
+                *cache = (self.id, Cache::default());
+            }
+            self.published.load(&mut cache.1)
+        })
+    }
+
+    /// Publishes the settings with `apply` made to them and tells subscribers what changed.
+    /// Nothing is saved.
+    pub fn update(&self, apply: impl FnOnce(&mut Config)) -> Arc<Config> {
+        // Publishing never waits on a subscriber, so it can happen under the writer lock
+        self.published.update_then(apply, |old, new| {
+            if old != &**new {
+                for event in Event::config_changes(old, new) {
+                    self.events.publish(event);
+                }
+            }
+        })
+    }
+
+    /// Where this player's key and settings events go out
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+/// How long reading the settings took, from a key thread's point of view
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadTimes {
+    pub p50: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl ReadTimes {
+    fn new(mut times: Vec<Duration>) -> Self {
+        times.sort();
+        let at = |quantile: f64| times[((times.len() as f64 * quantile).ceil() as usize).clamp(1, times.len()) - 1];
+        Self { p50: at(0.5), p99: at(0.99), max: at(1.0) }
+    }
+}
+
+/// Times `reads` settings reads, one every `interval`, while another thread keeps
+/// changing the settings and saving them to `dir`. Once the old way, with the save done
+/// under the same mutex the reads take, then through a `Published` snapshot.
+pub fn contention_benchmark(reads: usize, interval: Duration, dir: &Path) -> Result<(ReadTimes, ReadTimes)> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join("config.toml");
+    let save = |config: &Config| -> Result<()> { Ok(fs::write(&path, toml::to_string_pretty(config)?)?) };
+
+    let locked = Mutex::new(Config::default());
+    let published = Published::new(Config::default());
+    let mut cache = Cache::default();
+    let done = AtomicBool::new(false);
+
+    let times = |read: &mut dyn FnMut() -> usize| {
+        (0..reads.max(1))
+            .map(|_| {
+                std::thread::sleep(interval);
+                let started = Instant::now();
+                std::hint::black_box(read());
+                started.elapsed()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mutex = std::thread::scope(|scope| {
+        let writer = scope.spawn(|| -> Result<()> {
+            while !done.load(Ordering::Relaxed) {
+                let mut state = locked.lock();
+                state.volume = 1.0 - state.volume;
+                save(&state)?;
+            }
+            Ok(())
+        });
+        let times = times(&mut || locked.lock().switch_type.clone().len());
+        done.store(true, Ordering::Relaxed);
+        writer.join().expect("writer panicked").map(|_| times)
+    })?;
+
+    done.store(false, Ordering::Relaxed);
+    let snapshot = std::thread::scope(|scope| {
+        let writer = scope.spawn(|| -> Result<()> {
+            while !done.load(Ordering::Relaxed) {
+                let state = published.update(|state| state.volume = 1.0 - state.volume);
+                save(&state)?;
+            }
+            Ok(())
+        });
+        let times = times(&mut || published.load(&mut cache).switch_type.len());
+        done.store(true, Ordering::Relaxed);
+        writer.join().expect("writer panicked").map(|_| times)
+    })?;
+
+    Ok((ReadTimes::new(mutex), ReadTimes::new(snapshot)))
+}
+
+pub fn to_text(mutex: &ReadTimes, snapshot: &ReadTimes) -> String {
+    let us = |duration: Duration| format!("{:.1} µs", duration.as_secs_f64() * 1e6);
+    let mut out = format!("{:<10} {:>12} {:>12} {:>12}\n", "Reads", "p50", "p99", "max");
+    for (name, times) in [("mutex", mutex), ("snapshot", snapshot)] {
+        let _ = writeln!(out, "{:<10} {:>12} {:>12} {:>12}", name, us(times.p50), us(times.p99), us(times.max));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readers_see_updates() {
+        let published = Published::new(Config::default());
+        let (mut cache, mut other) = (Cache::default(), Cache::default());
+        let first = published.load(&mut cache);
+        assert!(Arc::ptr_eq(&first, &published.load(&mut cache)));
+
+        let updated = published.update(|config| config.switch_type = "topre".into());
+        assert_eq!(published.load(&mut cache).switch_type, "topre");
+        assert!(Arc::ptr_eq(&updated, &published.load(&mut other)));
+        // Snapshots already handed out don't change
+        assert_eq!(first.switch_type, "mxblue");
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_not_lost() {
+        let published = Published::new(Config { volume: 0.0, ..Default::default() });
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..100 {
+                        published.update(|config| config.volume += 1.0);
+                    }
+                });
+            }
+        });
+        assert_eq!(published.load(&mut Cache::default()).volume, 400.0);
+    }
+
+    #[test]
+    fn test_settings_are_shared_by_clones() {
+        let (settings, other) = (Settings::default(), Settings::default());
+        let clone = settings.clone();
+        settings.update(|config| config.volume = 0.5);
+        assert_eq!(clone.current().volume, 0.5);
+        // The thread's cache doesn't mix up different settings
+        assert_eq!(other.current().volume, 1.0);
+        assert_eq!(settings.current().volume, 0.5);
+    }
+
+    #[test]
+    fn test_updates_are_published() {
+        let settings = Settings::default();
+        let events = settings.events().subscribe();
+        settings.update(|config| config.enabled = false);
+        // Changes that change nothing aren't news
+        settings.update(|config| config.enabled = false);
+        assert_eq!(events.try_recv(), Some(Event::EnabledChanged(false)));
+        assert_eq!(events.try_recv(), Some(Event::ConfigChanged(settings.current())));
+        assert_eq!(events.try_recv(), None);
+    }
+
+    #[test]
+    fn test_concurrent_updates_are_published_in_order() {
+        let settings = Settings::new(Config { volume: 0.0, ..Default::default() });
+        let events = settings.events().subscribe();
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for _ in 0..25 {
+                        settings.update(|config| config.volume += 1.0);
+                    }
+                });
+            }
+        });
+        let volumes: Vec<f32> = std::iter::from_fn(|| events.try_recv())
+            .filter_map(|event| match event {
+                Event::VolumeChanged(volume) => Some(volume),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(volumes, (1..=100).map(|volume| volume as f32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_contention_benchmark() {
+        let dir = std::env::temp_dir().join(format!("clickclack-contention-{}", std::process::id()));
+        let (mutex, snapshot) = contention_benchmark(20, Duration::from_micros(100), &dir).unwrap();
+        assert!(mutex.p50 <= mutex.max && snapshot.p50 <= snapshot.p99);
+        assert_eq!(to_text(&mutex, &snapshot).lines().count(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
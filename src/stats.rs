@@ -0,0 +1,421 @@
+use anyhow::{bail, Context, Result};
+use rdev::Key;
+use parking_lot::Mutex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::events::{Event, Subscription};
+
+/// Keystrokes per word, as typing tests count them
+const KEYSTROKES_PER_WORD: f64 = 5.0;
+/// Window of the sustained WPM figure
+const ROLLING_MINUTES: u64 = 5;
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+pub fn stats_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("~/.config"))
+        .join("clickclack")
+        .join("stats.log")
+}
+
+/// Counts key presses and appends the totals to the stats file. Each minute adds a line
+/// `m <unix minute> <keystrokes> <backspaces>`. Each hour adds a line
+/// `k <unix hour> <key>=<count>...` with per-key counts. The order keys were typed in
+/// is never written, so no text can be read back out.
+pub struct StatsCollector {
+    path: PathBuf,
+    minute: u64,
+    keystrokes: u32,
+    backspaces: u32,
+    hour: u64,
+    keys: BTreeMap<String, u32>,
+}
+
+impl StatsCollector {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, minute: 0, keystrokes: 0, backspaces: 0, hour: 0, keys: BTreeMap::new() }
+    }
+
+    /// Counts a key press. The previous minute and hour are written out once time
+    /// moves past them.
+    pub fn record(&mut self, key: Key, unix_secs: u64) -> Result<()> {
+        self.roll_over(unix_secs)?;
+        self.keystrokes += 1;
+        if key == Key::Backspace {
+            self.backspaces += 1;
+        }
+        *self.keys.entry(format!("{:?}", key)).or_default() += 1;
+        Ok(())
+    }
+
+    /// Writes out the minute and hour that are over at `unix_secs`
+    pub fn roll_over(&mut self, unix_secs: u64) -> Result<()> {
+        let minute = unix_secs / 60;
+        if minute != self.minute {
+            self.flush_minute()?;
+            self.minute = minute;
+        }
+        if minute / 60 != self.hour {
+            self.flush_keys()?;
+            self.hour = minute / 60;
+        }
+        Ok(())
+    }
+
+    /// Writes out the counts of the minute and hour in progress. Counting can go on;
+    /// the stats add up a minute or hour that is in the file twice.
+    pub fn save(&mut self) -> Result<()> {
+        self.flush_minute()?;
+        self.flush_keys()
+    }
+
+    fn flush_minute(&mut self) -> Result<()> {
+        if self.keystrokes > 0 {
+            self.append(&format!("m {} {} {}", self.minute, self.keystrokes, self.backspaces))?;
+        }
+        self.keystrokes = 0;
+        self.backspaces = 0;
+        Ok(())
+    }
+
+    fn flush_keys(&mut self) -> Result<()> {
+        if !self.keys.is_empty() {
+            let counts: Vec<String> = self.keys.iter().map(|(key, count)| format!("{}={}", key, count)).collect();
+            self.append(&format!("k {} {}", self.hour, counts.join(" ")))?;
+        }
+        self.keys.clear();
+        Ok(())
+    }
+
+    fn append(&self, line: &str) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)
+            .with_context(|| format!("Failed to open {:?}", self.path))?;
+        writeln!(file, "{}", line)?;
+        Ok(())
+    }
+}
+
+impl Drop for StatsCollector {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            log::error!("Failed to save typing stats: {:#}", e);
+        }
+    }
+}
+
+/// Counts the key presses on `events` into the stats file at `path` while stats are on,
+/// starting with `enabled`. Runs on a thread of its own, so writing the file never holds
+/// up a key. Each minute is written once it is over, and everything counted when stats
+/// are turned off; `save` the returned collector before the process exits.
+pub fn count_keys(events: Subscription, enabled: bool, path: PathBuf) -> Arc<Mutex<StatsCollector>> {
+    let collector = Arc::new(Mutex::new(StatsCollector::new(path)));
+    let counting = collector.clone();
+    std::thread::spawn(move || {
+        let mut enabled = enabled;
+        loop {
+            let until_next_minute = Duration::from_secs(60 - unix_secs() % 60);
+            let result = match events.recv_timeout(until_next_minute) {
+                Ok(Event::Key { key, is_press: true }) if enabled => counting.lock().record(key, unix_secs()),
+                Ok(Event::ConfigChanged(config)) => {
+                    let was_enabled = std::mem::replace(&mut enabled, config.stats);
+                    if was_enabled && !enabled {
+                        counting.lock().save()
+                    } else {
+                        Ok(())
+                    }
+                }
+                Ok(_) => Ok(()),
+                Err(RecvTimeoutError::Timeout) => counting.lock().roll_over(unix_secs()),
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(e) = result {
+                log::error!("Failed to save typing stats: {:#}", e);
+            }
+        }
+    });
+    collector
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Stats {
+    pub days: Vec<DayStats>,
+    /// Hits per key over the whole history, for a heatmap
+    pub keys: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DayStats {
+    /// UTC date, `YYYY-MM-DD`
+    pub date: String,
+    pub keystrokes: u64,
+    pub backspaces: u64,
+    pub backspace_ratio: f64,
+    /// Minutes with at least one keystroke
+    pub active_minutes: u64,
+    /// Over the active minutes
+    pub average_wpm: f64,
+    /// Best single minute
+    pub peak_wpm: f64,
+    /// Best `ROLLING_MINUTES` window
+    pub rolling_wpm: f64,
+}
+
+pub fn load(path: &Path) -> Result<Stats> {
+    if !path.exists() {
+        return Ok(Stats::default());
+    }
+    let contents = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    parse(&contents).with_context(|| format!("Invalid stats file {:?}", path))
+}
+
+fn parse(contents: &str) -> Result<Stats> {
+    // The same minute can show up twice if ClickClack restarted within it
+    let mut minutes: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    let mut keys: BTreeMap<String, u64> = BTreeMap::new();
+
+    for (index, line) in contents.lines().enumerate() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let number = |field: usize| -> Result<u64> {
+            let value = fields.get(field).with_context(|| format!("line {}: missing field", index + 1))?;
+            value.parse().with_context(|| format!("line {}: {:?} is not a number", index + 1, value))
+        };
+        match fields.first() {
+            None => {}
+            Some(&"m") => {
+                let counts = minutes.entry(number(1)?).or_default();
+                counts.0 += number(2)?;
+                counts.1 += number(3)?;
+            }
+            Some(&"k") => {
+                for field in fields.iter().skip(2) {
+                    let (key, count) = field.split_once('=')
+                        .with_context(|| format!("line {}: {:?} is not key=count", index + 1, field))?;
+                    *keys.entry(key.to_string()).or_default() += count.parse::<u64>()
+                        .with_context(|| format!("line {}: {:?} is not a number", index + 1, count))?;
+                }
+            }
+            Some(kind) => bail!("line {}: unknown record {:?}", index + 1, kind),
+        }
+    }
+
+    let mut days: BTreeMap<u64, Vec<(u64, u64, u64)>> = BTreeMap::new();
+    for (minute, (keystrokes, backspaces)) in minutes {
+        days.entry(minute / MINUTES_PER_DAY).or_default().push((minute, keystrokes, backspaces));
+    }
+    Ok(Stats {
+        days: days.into_iter().map(|(day, minutes)| day_stats(day, &minutes)).collect(),
+        keys,
+    })
+}
+
+/// `minutes` holds (unix minute, keystrokes, backspaces), sorted
+fn day_stats(day: u64, minutes: &[(u64, u64, u64)]) -> DayStats {
+    let keystrokes: u64 = minutes.iter().map(|minute| minute.1).sum();
+    let backspaces: u64 = minutes.iter().map(|minute| minute.2).sum();
+    let wpm = |keystrokes: u64, minutes: u64| keystrokes as f64 / KEYSTROKES_PER_WORD / minutes as f64;
+    let rolling = minutes
+        .iter()
+        .map(|(start, _, _)| {
+            minutes.iter()
+                .filter(|(minute, _, _)| (*start..start + ROLLING_MINUTES).contains(minute))
+                .map(|minute| minute.1)
+                .sum()
+        })
+        .max()
+        .unwrap_or(0);
+
+    DayStats {
+        date: date(day),
+        keystrokes,
+        backspaces,
+        backspace_ratio: if keystrokes == 0 { 0.0 } else { backspaces as f64 / keystrokes as f64 },
+        active_minutes: minutes.len() as u64,
+        average_wpm: wpm(keystrokes, minutes.len().max(1) as u64),
+        peak_wpm: wpm(minutes.iter().map(|minute| minute.1).max().unwrap_or(0), 1),
+        rolling_wpm: wpm(rolling, ROLLING_MINUTES),
+    }
+}
+
+/// Days since the Unix epoch as a calendar date
+fn date(day: u64) -> String {
+    // Howard Hinnant's civil_from_days
+    let z = day as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day_of_month = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day_of_month)
+}
+
+pub fn to_text(stats: &Stats) -> String {
+    let mut text = format!("{:<10}  {:>9}  {:>9}  {:>6}  {:>7}  {:>8}  {:>9}\n",
+        "Date", "Keys", "Backspace", "Active", "Avg WPM", "Peak WPM", "5-min WPM");
+    for day in &stats.days {
+        text += &format!("{:<10}  {:>9}  {:>8.1}%  {:>5}m  {:>7.1}  {:>8.1}  {:>9.1}\n",
+            day.date, day.keystrokes, day.backspace_ratio * 100.0, day.active_minutes,
+            day.average_wpm, day.peak_wpm, day.rolling_wpm);
+    }
+
+    let mut keys: Vec<(&String, &u64)> = stats.keys.iter().collect();
+    keys.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+    let top: Vec<String> = keys.iter().take(10).map(|(key, count)| format!("{} {}", key, count)).collect();
+    text += &format!("\nMost hit keys: {}\n", top.join(", "));
+    text
+}
+
+/// Daily summaries, or the per-key counts with `keys`
+pub fn to_csv(stats: &Stats, keys: bool) -> String {
+    if keys {
+        let mut csv = String::from("key,count\n");
+        for (key, count) in &stats.keys {
+            csv += &format!("{},{}\n", key, count);
+        }
+        return csv;
+    }
+
+    let mut csv = String::from("date,keystrokes,backspaces,backspace_ratio,active_minutes,average_wpm,peak_wpm,rolling_wpm\n");
+    for day in &stats.days {
+        csv += &format!("{},{},{},{:.4},{},{:.2},{:.2},{:.2}\n",
+            day.date, day.keystrokes, day.backspaces, day.backspace_ratio, day.active_minutes,
+            day.average_wpm, day.peak_wpm, day.rolling_wpm);
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::events::EventBus;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// 2024-03-01 00:00 UTC
+    const MARCH_1: u64 = 1_709_251_200;
+
+    #[test]
+    fn test_collector_writes_counts_only() {
+        let path = std::env::temp_dir().join(format!("clickclack-stats-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut collector = StatsCollector::new(path.clone());
+        for (offset, key) in [(0, Key::KeyH), (1, Key::KeyI), (2, Key::Backspace), (61, Key::KeyI)] {
+            collector.record(key, MARCH_1 + offset).unwrap();
+        }
+        // Only the first minute is over
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("m {} 3 1\n", MARCH_1 / 60));
+
+        drop(collector);
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!(
+            "m {} 3 1\nm {} 1 0\nk {} Backspace=1 KeyH=1 KeyI=2\n",
+            MARCH_1 / 60, MARCH_1 / 60 + 1, MARCH_1 / 3600,
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_finished_minutes_and_hours_are_written() {
+        let path = std::env::temp_dir().join(format!("clickclack-stats-roll-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut collector = StatsCollector::new(path.clone());
+        collector.record(Key::KeyA, MARCH_1).unwrap();
+        collector.roll_over(MARCH_1 + 59).unwrap();
+        assert!(!path.exists());
+        collector.roll_over(MARCH_1 + 60).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("m {} 1 0\n", MARCH_1 / 60));
+        collector.roll_over(MARCH_1 + 3600).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("m {} 1 0\nk {} KeyA=1\n", MARCH_1 / 60, MARCH_1 / 3600));
+        drop(collector);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_counts_published_keys() {
+        let path = std::env::temp_dir().join(format!("clickclack-stats-bus-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let bus = EventBus::default();
+        let collector = count_keys(bus.subscribe(), true, path.clone());
+        bus.publish(Event::Key { key: Key::KeyA, is_press: true });
+        bus.publish(Event::Key { key: Key::KeyA, is_press: false });
+        bus.publish(Event::ConfigChanged(Arc::new(Config { stats: false, ..Default::default() })));
+        bus.publish(Event::Key { key: Key::KeyB, is_press: true });
+
+        // Turning stats off saves what was counted, while the app keeps running
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while load(&path).unwrap().keys.is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(load(&path).unwrap().keys, BTreeMap::from([("KeyA".to_string(), 1)]));
+
+        bus.publish(Event::ConfigChanged(Arc::new(Config { stats: true, ..Default::default() })));
+        bus.publish(Event::Key { key: Key::KeyC, is_press: true });
+        while !collector.lock().keys.contains_key("KeyC") && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        // As on quitting
+        collector.lock().save().unwrap();
+        assert_eq!(load(&path).unwrap().keys, BTreeMap::from([("KeyA".to_string(), 1), ("KeyC".to_string(), 1)]));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_day_summaries() {
+        let minute = MARCH_1 / 60;
+        let contents = format!(
+            "m {} 300 30\nm {} 200 0\nm {} 100 10\nm {} 50 0\nk 1 KeyA=2 Space=5\nk 2 KeyA=1\n",
+            minute, minute + 1, minute + 1, minute + MINUTES_PER_DAY,
+        );
+        let stats = parse(&contents).unwrap();
+
+        assert_eq!(stats.days[0], DayStats {
+            date: "2024-03-01".into(),
+            keystrokes: 600,
+            backspaces: 40,
+            backspace_ratio: 40.0 / 600.0,
+            active_minutes: 2,
+            average_wpm: 60.0,
+            peak_wpm: 60.0,
+            rolling_wpm: 24.0,
+        });
+        assert_eq!(stats.days[1].date, "2024-03-02");
+        assert_eq!(stats.keys, BTreeMap::from([("KeyA".to_string(), 3), ("Space".to_string(), 5)]));
+    }
+
+    #[test]
+    fn test_exports() {
+        let stats = parse(&format!("m {} 10 1\nk 1 KeyA=10\n", MARCH_1 / 60)).unwrap();
+        assert_eq!(to_csv(&stats, false).lines().nth(1), Some("2024-03-01,10,1,0.1000,1,2.00,2.00,0.40"));
+        assert_eq!(to_csv(&stats, true), "key,count\nKeyA,10\n");
+        assert!(to_text(&stats).contains("Most hit keys: KeyA 10"));
+    }
+
+    #[test]
+    fn test_bad_record_names_line() {
+        let error = parse("m 1 2 3\nx 1\n").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: unknown record \"x\"");
+    }
+
+    #[test]
+    fn test_dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(MARCH_1 / 86400 - 1), "2024-02-29");
+        assert_eq!(date(MARCH_1 / 86400), "2024-03-01");
+    }
+}
@@ -0,0 +1,337 @@
+use crate::audio::gain::MAX_VOLUME;
+use crate::config::Config;
+use crate::packs::Pack;
+
+/// Spacing of the volume steps for backends without a native slider
+pub const VOLUME_STEP: f32 = 0.1;
+
+/// Index of an item in `MenuModel::items`. Backends use it as their native item tag or id.
+pub type ItemId = usize;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuAction {
+    ToggleSound,
+    SetVolume(f32),
+    SetSwitch(String),
+    /// `None` selects the system default
+    SetOutputDevice(Option<String>),
+    /// Plays the demo phrase with a pack without switching to it
+    Preview(String),
+    Quit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MenuItemKind {
+    /// Clickable item with a checkmark
+    Check { checked: bool, action: MenuAction },
+    /// Clickable item without a checkmark
+    Action(MenuAction),
+    /// Volume slider from 0.0 to `max`
+    Slider { value: f32, max: f32 },
+    /// Disabled section header
+    Label,
+    Separator,
+    /// Items up to the matching `EndSubmenu` go in a submenu titled `label`
+    Submenu,
+    EndSubmenu,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MenuItem {
+    pub label: String,
+    pub kind: MenuItemKind,
+}
+
+/// Platform-independent description of the tray menu. Backends render the items, send
+/// clicks back through `apply` and update whichever items it reports as changed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MenuModel {
+    items: Vec<MenuItem>,
+}
+
+impl MenuModel {
+    pub fn build(config: &Config, packs: &[Pack], devices: &[String]) -> Self {
+        let mut items = vec![
+            check("Enable Sound", MenuAction::ToggleSound),
+            separator(),
+            label("Volume"),
+        ];
+        items.push(MenuItem {
+            label: String::new(),
+            kind: MenuItemKind::Slider { value: 0.0, max: MAX_VOLUME },
+        });
+        items.push(separator());
+
+        items.push(label("Switch Type"));
+        for pack in packs {
+            items.push(check(&format!("  {}", pack.name), MenuAction::SetSwitch(pack.folder.clone())));
+        }
+        items.push(MenuItem { label: "Preview".into(), kind: MenuItemKind::Submenu });
+        for pack in packs {
+            items.push(MenuItem { label: pack.name.clone(), kind: MenuItemKind::Action(MenuAction::Preview(pack.folder.clone())) });
+        }
+        items.push(MenuItem { label: String::new(), kind: MenuItemKind::EndSubmenu });
+        items.push(separator());
+
+        items.push(MenuItem { label: "Output Device".into(), kind: MenuItemKind::Submenu });
+        items.push(check("System Default", MenuAction::SetOutputDevice(None)));
+        for device in devices {
+            items.push(check(device, MenuAction::SetOutputDevice(Some(device.clone()))));
+        }
+        items.push(MenuItem { label: String::new(), kind: MenuItemKind::EndSubmenu });
+        items.push(separator());
+
+        items.push(MenuItem { label: "Quit".into(), kind: MenuItemKind::Action(MenuAction::Quit) });
+
+        let mut model = Self { items };
+        model.sync(config);
+        model
+    }
+
+    pub fn items(&self) -> &[MenuItem] {
+        &self.items
+    }
+
+    pub fn action(&self, id: ItemId) -> Option<&MenuAction> {
+        match &self.items.get(id)?.kind {
+            MenuItemKind::Check { action, .. } | MenuItemKind::Action(action) => Some(action),
+            _ => None,
+        }
+    }
+
+    /// Action for moving the slider `id` to `value`, clamped to its range
+    pub fn slider_action(&self, id: ItemId, value: f32) -> Option<MenuAction> {
+        match self.items.get(id)?.kind {
+            MenuItemKind::Slider { max, .. } => Some(MenuAction::SetVolume(value.clamp(0.0, max))),
+            _ => None,
+        }
+    }
+
+    pub fn slider_value(&self, id: ItemId) -> Option<f32> {
+        match self.items.get(id)?.kind {
+            MenuItemKind::Slider { value, .. } => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn is_checked(&self, id: ItemId) -> bool {
+        matches!(self.items.get(id), Some(MenuItem { kind: MenuItemKind::Check { checked: true, .. }, .. }))
+    }
+
+    /// Applies a clicked action to `config` and returns the items whose checkmark or value changed.
+    /// `Preview` and `Quit` are left to the backend.
+    pub fn apply(&mut self, action: &MenuAction, config: &mut Config) -> Vec<ItemId> {
+        match action {
+            MenuAction::ToggleSound => config.enabled = !config.enabled,
+            MenuAction::SetVolume(volume) => config.volume = *volume,
+            MenuAction::SetSwitch(switch_type) => config.switch_type = switch_type.clone(),
+            MenuAction::SetOutputDevice(device) => config.output_device = device.clone(),
+            MenuAction::Preview(_) | MenuAction::Quit => {}
+        }
+        self.sync(config)
+    }
+
+    /// Re-derives every checkmark from `config`, e.g. after a change made over IPC, and
+    /// returns the items that changed
+    pub fn sync(&mut self, config: &Config) -> Vec<ItemId> {
+        let mut changed = Vec::new();
+        for (id, item) in self.items.iter_mut().enumerate() {
+            match &mut item.kind {
+                MenuItemKind::Check { checked, action } => {
+                    let now = is_active(action, config);
+                    if *checked != now {
+                        *checked = now;
+                        changed.push(id);
+                    }
+                }
+                MenuItemKind::Slider { value, max } => {
+                    let now = config.volume.clamp(0.0, *max);
+                    if *value != now {
+                        *value = now;
+                        changed.push(id);
+                    }
+                }
+                _ => {}
+            }
+        }
+        changed
+    }
+}
+
+/// Positions a stepped volume menu offers: 0.0, `VOLUME_STEP`, ... up to `max`
+pub fn volume_steps(max: f32) -> Vec<f32> {
+    let count = (max / VOLUME_STEP).round() as usize;
+    (0..=count).map(|step| step as f32 * VOLUME_STEP).collect()
+}
+
+/// Index of the step closest to `value`, so a hand-edited volume still gets a checkmark
+pub fn nearest_step(steps: &[f32], value: f32) -> usize {
+    (0..steps.len())
+        .min_by(|a, b| (steps[*a] - value).abs().total_cmp(&(steps[*b] - value).abs()))
+        .unwrap_or(0)
+}
+
+fn is_active(action: &MenuAction, config: &Config) -> bool {
+    match action {
+        MenuAction::ToggleSound => config.enabled,
+        MenuAction::SetSwitch(switch_type) => config.switch_type == *switch_type,
+        MenuAction::SetOutputDevice(device) => config.output_device == *device,
+        MenuAction::SetVolume(_) | MenuAction::Preview(_) | MenuAction::Quit => false,
+    }
+}
+
+fn check(label: &str, action: MenuAction) -> MenuItem {
+    MenuItem { label: label.into(), kind: MenuItemKind::Check { checked: false, action } }
+}
+
+fn label(label: &str) -> MenuItem {
+    MenuItem { label: label.into(), kind: MenuItemKind::Label }
+}
+
+fn separator() -> MenuItem {
+    MenuItem { label: String::new(), kind: MenuItemKind::Separator }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SLIDER: ItemId = 3;
+
+    fn config() -> Config {
+        Config {
+            enabled: true,
+            volume: 0.5,
+            switch_type: "topre".into(),
+            ..Default::default()
+        }
+    }
+
+    fn devices() -> Vec<String> {
+        vec!["Speakers".into(), "Headphones".into()]
+    }
+
+    fn packs() -> Vec<Pack> {
+        vec![
+            Pack { name: "Cherry MX Blue".into(), folder: "mxblue".into() },
+            Pack { name: "Topre".into(), folder: "topre".into() },
+        ]
+    }
+
+    fn find(model: &MenuModel, wanted: &MenuAction) -> ItemId {
+        (0..model.items().len()).find(|id| model.action(*id) == Some(wanted)).unwrap()
+    }
+
+    fn checked_labels(model: &MenuModel) -> Vec<&str> {
+        (0..model.items().len())
+            .filter(|id| model.is_checked(*id))
+            .map(|id| model.items()[id].label.trim())
+            .collect()
+    }
+
+    #[test]
+    fn test_build_layout() {
+        let model = MenuModel::build(&config(), &packs(), &devices());
+        let labels: Vec<&str> = model.items().iter().map(|item| item.label.trim()).collect();
+        assert_eq!(labels, vec![
+            "Enable Sound", "", "Volume", "", "", "Switch Type", "Cherry MX Blue", "Topre",
+            "Preview", "Cherry MX Blue", "Topre", "", "",
+            "Output Device", "System Default", "Speakers", "Headphones", "", "", "Quit",
+        ]);
+        assert_eq!(checked_labels(&model), vec!["Enable Sound", "Topre", "System Default"]);
+        assert_eq!(model.slider_value(SLIDER), Some(0.5));
+        assert_eq!(model.action(2), None);
+        assert_eq!(model.action(8), None);
+        assert_eq!(model.action(10), Some(&MenuAction::Preview("topre".into())));
+        assert_eq!(model.action(19), Some(&MenuAction::Quit));
+    }
+
+    #[test]
+    fn test_toggle_reports_only_toggle_item() {
+        let mut config = config();
+        let mut model = MenuModel::build(&config, &packs(), &devices());
+        let changed = model.apply(&MenuAction::ToggleSound, &mut config);
+        assert!(!config.enabled);
+        assert_eq!(changed, vec![find(&model, &MenuAction::ToggleSound)]);
+        assert!(!model.is_checked(changed[0]));
+    }
+
+    #[test]
+    fn test_slider_sets_volume() {
+        let mut config = config();
+        let mut model = MenuModel::build(&config, &packs(), &devices());
+        let action = model.slider_action(SLIDER, 1.3).unwrap();
+        assert_eq!(model.apply(&action, &mut config), vec![SLIDER]);
+        assert_eq!(config.volume, 1.3);
+        assert_eq!(model.slider_value(SLIDER), Some(1.3));
+
+        assert_eq!(model.slider_action(SLIDER, 4.0), Some(MenuAction::SetVolume(MAX_VOLUME)));
+        assert_eq!(model.slider_action(0, 0.5), None);
+    }
+
+    #[test]
+    fn test_volume_steps() {
+        let steps = volume_steps(MAX_VOLUME);
+        assert_eq!(steps.len(), 16);
+        assert_eq!(steps[10], 1.0);
+        // A hand-edited volume between steps still picks one
+        assert_eq!(nearest_step(&steps, 0.33), 3);
+        assert_eq!(nearest_step(&steps, 9.0), 15);
+    }
+
+    #[test]
+    fn test_reselecting_same_switch_changes_nothing() {
+        let mut config = config();
+        let mut model = MenuModel::build(&config, &packs(), &devices());
+        assert!(model.apply(&MenuAction::SetSwitch("topre".into()), &mut config).is_empty());
+
+        let changed = model.apply(&MenuAction::SetSwitch("mxblue".into()), &mut config);
+        assert_eq!(config.switch_type, "mxblue");
+        assert_eq!(changed.len(), 2);
+        assert_eq!(checked_labels(&model), vec!["Enable Sound", "Cherry MX Blue", "System Default"]);
+    }
+
+    #[test]
+    fn test_output_device_selection() {
+        let mut config = config();
+        let mut model = MenuModel::build(&config, &packs(), &devices());
+        let headphones = MenuAction::SetOutputDevice(Some("Headphones".into()));
+        let changed = model.apply(&headphones, &mut config);
+        assert_eq!(config.output_device.as_deref(), Some("Headphones"));
+        assert_eq!(changed, vec![find(&model, &MenuAction::SetOutputDevice(None)), find(&model, &headphones)]);
+
+        // A configured device that isn't plugged in leaves the submenu unchecked
+        config.output_device = Some("USB DAC".into());
+        model.sync(&config);
+        assert_eq!(checked_labels(&model), vec!["Enable Sound", "Topre"]);
+    }
+
+    #[test]
+    fn test_sync_picks_up_external_changes() {
+        let mut config = config();
+        let mut model = MenuModel::build(&config, &packs(), &devices());
+        config.enabled = false;
+        config.switch_type = "not-installed".into();
+        config.volume = 0.3;
+        assert_eq!(model.sync(&config).len(), 3);
+        assert_eq!(checked_labels(&model), vec!["System Default"]);
+        assert_eq!(model.slider_value(SLIDER), Some(0.3));
+        assert!(model.sync(&config).is_empty());
+    }
+
+    #[test]
+    fn test_preview_leaves_config_alone() {
+        let mut config = config();
+        let mut model = MenuModel::build(&config, &packs(), &devices());
+        assert!(model.apply(&MenuAction::Preview("mxblue".into()), &mut config).is_empty());
+        assert_eq!(config.switch_type, "topre");
+    }
+
+    #[test]
+    fn test_quit_leaves_config_alone() {
+        let mut config = config();
+        let mut model = MenuModel::build(&config, &packs(), &devices());
+        assert!(model.apply(&MenuAction::Quit, &mut config).is_empty());
+        assert!(config.enabled);
+    }
+}
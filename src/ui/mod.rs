@@ -1,2 +1,15 @@
+pub mod menu;
+pub mod system_tray;
+#[cfg(target_os = "macos")]
 pub mod tray;
-pub use tray::TrayIcon; 
\ No newline at end of file
+#[cfg(feature = "tui")]
+pub mod tui;
+
+use std::sync::Arc;
+
+pub use system_tray::{SystemTray, TrayEvent};
+#[cfg(target_os = "macos")]
+pub use tray::TrayIcon;
+
+/// Saves what only lives in memory. The menus call it before quitting the app.
+pub type OnQuit = Arc<dyn Fn() + Send + Sync>;
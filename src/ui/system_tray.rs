@@ -0,0 +1,235 @@
+use anyhow::Result;
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use std::cell::RefCell;
+use std::sync::Arc;
+use tao::event_loop::EventLoopProxy;
+use tray_icon::menu::{CheckMenuItem, IsMenuItem, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu};
+use tray_icon::{TrayIcon, TrayIconBuilder};
+
+use super::menu::{self, ItemId, MenuAction, MenuItemKind, MenuModel};
+use super::OnQuit;
+use crate::audio::{device, preview, SoundEngine};
+use crate::packs;
+
+/// Events delivered to the main event loop
+#[derive(Debug)]
+pub enum TrayEvent {
+    Menu(MenuEvent),
+    /// The state was changed elsewhere (control socket, D-Bus) and the checkmarks are stale
+    StateChanged,
+}
+
+static EVENT_PROXY: OnceCell<Mutex<EventLoopProxy<TrayEvent>>> = OnceCell::new();
+
+/// The native counterpart of a model item that needs updating later
+enum NativeItem {
+    Check(CheckMenuItem),
+    /// muda has no slider, so the volume becomes a submenu of steps with ids `<item>:<step>`
+    Slider { submenu: Submenu, steps: Vec<(f32, CheckMenuItem)> },
+    Static,
+}
+
+/// Tray menu built on tray-icon/muda, so it works as a macOS status item and as a
+/// StatusNotifierItem on Linux. It only renders the `MenuModel`.
+pub struct SystemTray {
+    _tray: TrayIcon,
+    model: RefCell<MenuModel>,
+    /// Indexed by `ItemId`
+    items: Vec<NativeItem>,
+    sound_engine: Arc<SoundEngine>,
+    on_quit: OnQuit,
+}
+
+impl SystemTray {
+    pub fn new(proxy: EventLoopProxy<TrayEvent>, sound_engine: Arc<SoundEngine>, on_quit: OnQuit) -> Result<Self> {
+        let packs = packs::list_packs().unwrap_or_else(|e| {
+            error!("Failed to list switch types: {}", e);
+            Vec::new()
+        });
+        let devices = device::list_devices().unwrap_or_else(|e| {
+            error!("Failed to list output devices: {}", e);
+            Vec::new()
+        });
+        let model = MenuModel::build(&sound_engine.settings().current(), &packs, &devices);
+
+        let menu = Menu::new();
+        let mut submenus: Vec<Submenu> = Vec::new();
+        let mut items = Vec::new();
+        for (id, item) in model.items().iter().enumerate() {
+            let parent = submenus.last();
+            let native = match &item.kind {
+                MenuItemKind::Check { checked, .. } => {
+                    let native = CheckMenuItem::with_id(id.to_string(), &item.label, true, *checked, None);
+                    append(&menu, parent, &native)?;
+                    NativeItem::Check(native)
+                }
+                MenuItemKind::Slider { value, max } => {
+                    let submenu = Submenu::new(slider_title(*value), true);
+                    let values = menu::volume_steps(*max);
+                    let selected = menu::nearest_step(&values, *value);
+                    let mut steps = Vec::new();
+                    for (step, step_value) in values.into_iter().enumerate() {
+                        let label = format!("{:.0}%", step_value * 100.0);
+                        let native = CheckMenuItem::with_id(format!("{}:{}", id, step), label, true, step == selected, None);
+                        submenu.append(&native)?;
+                        steps.push((step_value, native));
+                    }
+                    append(&menu, parent, &submenu)?;
+                    NativeItem::Slider { submenu, steps }
+                }
+                MenuItemKind::Action(_) => {
+                    append(&menu, parent, &MenuItem::with_id(id.to_string(), &item.label, true, None))?;
+                    NativeItem::Static
+                }
+                MenuItemKind::Label => {
+                    append(&menu, parent, &MenuItem::new(&item.label, false, None))?;
+                    NativeItem::Static
+                }
+                MenuItemKind::Separator => {
+                    append(&menu, parent, &PredefinedMenuItem::separator())?;
+                    NativeItem::Static
+                }
+                MenuItemKind::Submenu => {
+                    let submenu = Submenu::new(&item.label, true);
+                    append(&menu, parent, &submenu)?;
+                    submenus.push(submenu);
+                    NativeItem::Static
+                }
+                MenuItemKind::EndSubmenu => {
+                    submenus.pop();
+                    NativeItem::Static
+                }
+            };
+            items.push(native);
+        }
+
+        let builder = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip("ClickClack");
+
+        // macOS keeps the emoji title of the Cocoa status item; other platforms need an icon
+        #[cfg(target_os = "macos")]
+        let builder = builder.with_title("🎹");
+        #[cfg(not(target_os = "macos"))]
+        let builder = builder.with_icon(load_icon()?);
+
+        let tray = builder.build()?;
+
+        let _ = EVENT_PROXY.set(Mutex::new(proxy));
+        MenuEvent::set_event_handler(Some(|event| {
+            if let Some(proxy) = EVENT_PROXY.get() {
+                let _ = proxy.lock().send_event(TrayEvent::Menu(event));
+            }
+        }));
+
+        Ok(Self {
+            _tray: tray,
+            model: RefCell::new(model),
+            items,
+            sound_engine,
+            on_quit,
+        })
+    }
+
+    pub fn handle_event(&self, event: TrayEvent) {
+        match event {
+            TrayEvent::Menu(event) => self.handle_menu_event(event),
+            TrayEvent::StateChanged => self.refresh(),
+        }
+    }
+
+    fn handle_menu_event(&self, event: MenuEvent) {
+        let Some((id, action)) = self.parse_event(event.id.as_ref()) else {
+            error!("Unknown menu item: {}", event.id.as_ref());
+            return;
+        };
+        info!("Menu action: {:?}", action);
+
+        if action == MenuAction::Quit {
+            (self.on_quit)();
+            std::process::exit(0);
+        }
+        if let MenuAction::Preview(pack) = action {
+            preview::spawn(self.sound_engine.clone(), pack);
+            return;
+        }
+
+        let mut changed = Vec::new();
+        self.sound_engine.settings().update(|state| changed = self.model.borrow_mut().apply(&action, state));
+
+        // Check items toggle themselves when clicked, so the clicked one is always reset
+        changed.push(id);
+        self.update_items(&changed);
+    }
+
+    fn parse_event(&self, event_id: &str) -> Option<(ItemId, MenuAction)> {
+        let model = self.model.borrow();
+        match event_id.split_once(':') {
+            Some((id, step)) => {
+                let id = id.parse().ok()?;
+                let NativeItem::Slider { steps, .. } = self.items.get(id)? else {
+                    return None;
+                };
+                let (value, _) = steps.get(step.parse::<usize>().ok()?)?;
+                Some((id, model.slider_action(id, *value)?))
+            }
+            None => {
+                let id = event_id.parse().ok()?;
+                Some((id, model.action(id)?.clone()))
+            }
+        }
+    }
+
+    /// Brings every checkmark in line with the settings
+    pub fn refresh(&self) {
+        let changed = self.model.borrow_mut().sync(&self.sound_engine.settings().current());
+        self.update_items(&changed);
+    }
+
+    fn update_items(&self, ids: &[ItemId]) {
+        let model = self.model.borrow();
+        for &id in ids {
+            match self.items.get(id) {
+                Some(NativeItem::Check(item)) => item.set_checked(model.is_checked(id)),
+                Some(NativeItem::Slider { submenu, steps }) => {
+                    let value = model.slider_value(id).unwrap_or_default();
+                    let values: Vec<f32> = steps.iter().map(|(step_value, _)| *step_value).collect();
+                    let selected = menu::nearest_step(&values, value);
+                    submenu.set_text(slider_title(value));
+                    for (step, (_, item)) in steps.iter().enumerate() {
+                        item.set_checked(step == selected);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Appends to the innermost open submenu, or the top-level menu
+fn append(menu: &Menu, parent: Option<&Submenu>, item: &dyn IsMenuItem) -> tray_icon::menu::Result<()> {
+    match parent {
+        Some(submenu) => submenu.append(item),
+        None => menu.append(item),
+    }
+}
+
+fn slider_title(value: f32) -> String {
+    format!("  {:.0}%", value * 100.0)
+}
+
+/// Asks the tray to resync its checkmarks. Safe to call from any thread.
+pub fn request_refresh() {
+    if let Some(proxy) = EVENT_PROXY.get() {
+        let _ = proxy.lock().send_event(TrayEvent::StateChanged);
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+fn load_icon() -> Result<tray_icon::Icon> {
+    let image = image::load_from_memory(include_bytes!("../../assets/icon.png"))?.into_rgba8();
+    let (width, height) = image.dimensions();
+    Ok(tray_icon::Icon::from_rgba(image.into_raw(), width, height)?)
+}
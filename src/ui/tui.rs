@@ -0,0 +1,349 @@
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::crossterm::ExecutableCommand;
+use ratatui::prelude::*;
+use ratatui::widgets::{Block, Borders, Gauge, List, ListItem, ListState, Paragraph};
+use std::io::stdout;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::menu::VOLUME_STEP;
+use crate::audio::gain::MAX_VOLUME;
+use crate::audio::{preview, SoundEngine};
+use crate::config::{Keymap, SecureInputMode};
+use crate::input::KeyboardHandler;
+use crate::packs::{self, Pack};
+use crate::Settings;
+
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+/// Humanization goes up by this much per key press, then back to none
+const HUMANIZE_STEP: f32 = 0.25;
+
+/// Runs the terminal interface until the user quits. Sounds keep playing from
+/// `keyboard` meanwhile.
+pub fn run(keyboard: &KeyboardHandler, sound_engine: Arc<SoundEngine>) -> Result<()> {
+    let _screen = Screen::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(stdout()))?;
+    Tui::new(keyboard, sound_engine).run(&mut terminal)
+}
+
+/// Raw mode on the alternate screen, until dropped. Dropping it also puts the shell back
+/// when drawing fails or panics.
+struct Screen;
+
+impl Screen {
+    fn enter() -> Result<Self> {
+        enable_raw_mode()?;
+        // From here on, dropping undoes whatever got turned on
+        let screen = Self;
+        stdout().execute(EnterAlternateScreen)?;
+        Ok(screen)
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = stdout().execute(LeaveAlternateScreen);
+    }
+}
+
+struct Tui<'a> {
+    keyboard: &'a KeyboardHandler,
+    sound_engine: Arc<SoundEngine>,
+    packs: Vec<Pack>,
+    selected: ListState,
+    events_per_second: f64,
+    counted_events: u64,
+    counted_at: Instant,
+}
+
+impl<'a> Tui<'a> {
+    fn new(keyboard: &'a KeyboardHandler, sound_engine: Arc<SoundEngine>) -> Self {
+        let packs = packs::list_packs().unwrap_or_default();
+        let current = sound_engine.settings().current().switch_type.clone();
+        let mut selected = ListState::default();
+        selected.select(Some(packs.iter().position(|pack| pack.folder == current).unwrap_or(0)));
+        let counted_events = sound_engine.counters().events();
+        Self {
+            keyboard,
+            sound_engine,
+            packs,
+            selected,
+            events_per_second: 0.0,
+            counted_events,
+            counted_at: Instant::now(),
+        }
+    }
+
+    fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<std::io::Stdout>>) -> Result<()> {
+        loop {
+            self.count_events();
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(REDRAW_INTERVAL)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press && !self.handle_key(key.code) {
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+
+    fn count_events(&mut self) {
+        let elapsed = self.counted_at.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let events = self.sound_engine.counters().events();
+            self.events_per_second = (events - self.counted_events) as f64 / elapsed.as_secs_f64();
+            self.counted_events = events;
+            self.counted_at = Instant::now();
+        }
+    }
+
+    /// Returns false to quit. Settings changes go out as events, so they are saved and
+    /// the tray and control clients hear about them.
+    fn handle_key(&mut self, code: KeyCode) -> bool {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return false,
+            KeyCode::Up => self.move_selection(-1),
+            KeyCode::Down => self.move_selection(1),
+            KeyCode::Enter => {
+                if let Some(pack) = self.selected_pack() {
+                    let folder = pack.folder.clone();
+                    self.settings().update(|state| state.switch_type = folder);
+                }
+            }
+            KeyCode::Char('p') => {
+                if let Some(pack) = self.selected_pack() {
+                    preview::spawn(self.sound_engine.clone(), pack.folder.clone());
+                }
+            }
+            KeyCode::Left => change_volume(self.settings(), -VOLUME_STEP),
+            KeyCode::Right => change_volume(self.settings(), VOLUME_STEP),
+            KeyCode::Char('m') => {
+                self.settings().update(|state| state.enabled = !state.enabled);
+            }
+            KeyCode::Char('s') => {
+                self.settings().update(|state| {
+                    state.secure_input = match state.secure_input {
+                        SecureInputMode::Silent => SecureInputMode::Masked,
+                        SecureInputMode::Masked => SecureInputMode::Silent,
+                    }
+                });
+            }
+            KeyCode::Char('t') => {
+                self.settings().update(|state| state.stats = !state.stats);
+            }
+            KeyCode::Char('k') => {
+                self.settings().update(|state| {
+                    state.keymap = match state.keymap {
+                        Keymap::Rows => Keymap::Flat,
+                        Keymap::Flat => Keymap::Rows,
+                    }
+                });
+            }
+            KeyCode::Char('h') => change_humanize(self.settings()),
+            _ => {}
+        }
+        true
+    }
+
+    fn settings(&self) -> &Settings {
+        self.sound_engine.settings()
+    }
+
+    fn move_selection(&mut self, by: isize) {
+        if self.packs.is_empty() {
+            return;
+        }
+        let current = self.selected.selected().unwrap_or(0) as isize;
+        let next = (current + by).rem_euclid(self.packs.len() as isize);
+        self.selected.select(Some(next as usize));
+    }
+
+    fn selected_pack(&self) -> Option<&Pack> {
+        self.packs.get(self.selected.selected()?)
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let state = self.settings().current();
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(11), Constraint::Length(3), Constraint::Length(3), Constraint::Length(1)])
+            .split(frame.size());
+        let top = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+            .split(rows[0]);
+        let settings = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3), Constraint::Min(0)])
+            .split(top[1]);
+
+        let items: Vec<ListItem> = self.packs
+            .iter()
+            .map(|pack| {
+                let marker = if pack.folder == state.switch_type { "● " } else { "  " };
+                ListItem::new(format!("{}{}", marker, pack.name))
+            })
+            .collect();
+        let packs = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title(" Packs "))
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        frame.render_stateful_widget(packs, top[0], &mut self.selected);
+
+        let volume = Gauge::default()
+            .block(Block::default().borders(Borders::ALL).title(" Volume "))
+            .ratio((state.volume / MAX_VOLUME).clamp(0.0, 1.0) as f64)
+            .label(format!("{:.0}%", state.volume * 100.0));
+        frame.render_widget(volume, settings[0]);
+
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let lines = vec![
+            setting("Sound", on_off(state.enabled)),
+            setting("Secure input", &format!("{:?}", state.secure_input).to_lowercase()),
+            setting("Typing stats", on_off(state.stats)),
+            setting("Keymap", &format!("{:?}", state.keymap).to_lowercase()),
+            setting("Humanize", &format!("{:.0}%", state.humanize * 100.0)),
+            setting("Output", state.output_device.as_deref().unwrap_or("System default")),
+        ];
+        let settings_block = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" Settings "));
+        frame.render_widget(settings_block, settings[1]);
+
+        let secure = self.keyboard.secure_input_active();
+        let status = format!(
+            "Voices {}   Events/s {:.1}{}",
+            self.sound_engine.counters().voices(),
+            self.events_per_second,
+            if secure { "   Secure input on" } else { "" },
+        );
+        frame.render_widget(Paragraph::new(status).block(Block::default().borders(Borders::ALL).title(" Status ")), rows[1]);
+
+        // Keys stay hidden during password entry, the screen may be shared
+        let keys = if secure {
+            "hidden while secure input is on".to_string()
+        } else {
+            self.keyboard.pressed_keys().iter().map(|key| format!("{:?}", key)).collect::<Vec<_>>().join(" ")
+        };
+        frame.render_widget(Paragraph::new(keys).block(Block::default().borders(Borders::ALL).title(" Keys ")), rows[2]);
+
+        let help = "↑↓ pack  Enter use  p preview  ←→ volume  m mute  s secure input  t stats  k keymap  h humanize  q quit";
+        frame.render_widget(Paragraph::new(help).style(Style::default().add_modifier(Modifier::DIM)), rows[3]);
+    }
+}
+
+fn setting(name: &str, value: &str) -> Line<'static> {
+    Line::from(vec![
+        Span::raw(format!("{:<14}", name)),
+        Span::styled(value.to_string(), Style::default().add_modifier(Modifier::BOLD)),
+    ])
+}
+
+fn change_volume(settings: &Settings, by: f32) {
+    settings.update(|state| {
+        let steps = ((state.volume + by) / VOLUME_STEP).round();
+        state.volume = (steps * VOLUME_STEP).clamp(0.0, MAX_VOLUME);
+    });
+}
+
+/// Steps the humanization up, wrapping from the most back to none
+fn change_humanize(settings: &Settings) {
+    settings.update(|state| {
+        let steps = (state.humanize / HUMANIZE_STEP).round() + 1.0;
+        state.humanize = if steps * HUMANIZE_STEP > 1.0 { 0.0 } else { steps * HUMANIZE_STEP };
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::buffer::NullBackend;
+    use std::path::PathBuf;
+
+    fn sound_engine() -> Arc<SoundEngine> {
+        Arc::new(SoundEngine::with_backend(Settings::default(), Box::new(NullBackend), PathBuf::new()).unwrap())
+    }
+
+    fn packs() -> Vec<Pack> {
+        vec![
+            Pack { name: "Cherry MX Blue".into(), folder: "mxblue".into() },
+            Pack { name: "Topre".into(), folder: "topre".into() },
+        ]
+    }
+
+    #[test]
+    fn test_keys_change_settings() {
+        let sound_engine = sound_engine();
+        let keyboard = KeyboardHandler::new(sound_engine.clone()).unwrap();
+        let mut tui = Tui::new(&keyboard, sound_engine.clone());
+        let current = || sound_engine.settings().current();
+
+        for code in ['m', 's', 't', 'k', 'h'] {
+            assert!(tui.handle_key(KeyCode::Char(code)));
+        }
+        let state = current();
+        assert!(!state.enabled);
+        assert_eq!(state.secure_input, SecureInputMode::Masked);
+        assert!(state.stats);
+        assert_eq!(state.keymap, Keymap::Flat);
+        assert_eq!(state.humanize, HUMANIZE_STEP);
+
+        tui.handle_key(KeyCode::Right);
+        assert!((current().volume - 1.1).abs() < 1e-6);
+        tui.handle_key(KeyCode::Left);
+        tui.handle_key(KeyCode::Left);
+        assert!((current().volume - 0.9).abs() < 1e-6);
+
+        assert!(!tui.handle_key(KeyCode::Char('q')));
+        assert!(!tui.handle_key(KeyCode::Esc));
+    }
+
+    #[test]
+    fn test_selection_wraps_and_enter_switches() {
+        let sound_engine = sound_engine();
+        let keyboard = KeyboardHandler::new(sound_engine.clone()).unwrap();
+        let mut tui = Tui::new(&keyboard, sound_engine.clone());
+        tui.packs = packs();
+        tui.selected.select(Some(0));
+
+        tui.handle_key(KeyCode::Up);
+        assert_eq!(tui.selected_pack().unwrap().folder, "topre");
+        tui.handle_key(KeyCode::Enter);
+        assert_eq!(sound_engine.settings().current().switch_type, "topre");
+        tui.handle_key(KeyCode::Down);
+        assert_eq!(tui.selected_pack().unwrap().folder, "mxblue");
+    }
+
+    #[test]
+    fn test_change_volume_steps_and_clamps() {
+        let settings = Settings::default();
+        change_volume(&settings, VOLUME_STEP);
+        assert!((settings.current().volume - 1.1).abs() < 1e-6);
+        for _ in 0..10 {
+            change_volume(&settings, VOLUME_STEP);
+        }
+        assert_eq!(settings.current().volume, MAX_VOLUME);
+        for _ in 0..20 {
+            change_volume(&settings, -VOLUME_STEP);
+        }
+        assert_eq!(settings.current().volume, 0.0);
+
+        // Off-step values snap to the nearest step
+        settings.update(|state| state.volume = 0.53);
+        change_volume(&settings, VOLUME_STEP);
+        assert!((settings.current().volume - 0.6).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_humanize_cycles() {
+        let settings = Settings::default();
+        let mut seen = Vec::new();
+        for _ in 0..5 {
+            change_humanize(&settings);
+            seen.push(settings.current().humanize);
+        }
+        assert_eq!(seen, vec![0.25, 0.5, 0.75, 1.0, 0.0]);
+    }
+}